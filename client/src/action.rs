@@ -1,8 +1,9 @@
 use crate::nice;
-use hex_literal::hex;
+use crate::script::{self, Arg, ScriptCall};
+use crate::state::TokenInfo;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
-use std::str::FromStr;
 use web3::types::{H160, U256};
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -31,75 +32,30 @@ impl fmt::Display for VotingAction {
     }
 }
 
-pub struct TokenDescriptor {
-    pub name: String,
-    pub decimals: usize,
-    pub addr: Vec<u8>,
-}
-
-impl TokenDescriptor {
-    pub fn new(name: &str, decimals: usize, addr: H160) -> Self {
-        Self {
-            name: name.to_owned(),
-            decimals,
-            addr: addr.as_bytes().into(),
-        }
-    }
-}
-
 impl VotingAction {
-    pub fn from_script(script_data: &Vec<u8>) -> Option<Self> {
-        if script_data.len() == 0 {
-            return None;
-        }
-
-        let mut tokens: Vec<TokenDescriptor> = vec![];
-        tokens.push(TokenDescriptor::new(
-            "USDC",
-            6,
-            hex!("a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").into(),
-        ));
-        tokens.push(TokenDescriptor::new(
-            "API3",
-            6,
-            hex!("a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").into(),
-        ));
+    pub fn from_script(script_data: &[u8], tokens: &BTreeMap<H160, TokenInfo>) -> Option<Self> {
+        Self::from_calls(&script::parse(script_data).ok()?, tokens)
+    }
 
-        for t in tokens {
-            let addr: Vec<u8> = script_data
-                .iter()
-                .skip(0x20 + 12)
-                .take(20)
-                .map(|x| x.clone())
-                .collect();
-            if addr == t.addr.as_slice() {
-                // not very accurante, but most likely this is Transfer
-                // and it can work
-                let offset = 32 + 32 + 32 + 32 + 32 + 4 + 12;
-                let to: Vec<u8> = script_data
-                    .iter()
-                    .skip(offset)
-                    .take(20)
-                    .map(|x| x.clone())
-                    .collect();
-                let amt: Vec<u8> = script_data
-                    .iter()
-                    .skip(offset + 20 + 16)
-                    .take(16)
-                    .map(|x| x.clone())
-                    .collect();
-                let amt_hex = format!("0x{}", hex::encode(amt));
-                let amount: U256 = U256::from_str(&amt_hex).unwrap();
-                return Some(Self {
+    // the first transfer of the known token, made by the agent or directly
+    pub fn from_calls(calls: &[ScriptCall], tokens: &BTreeMap<H160, TokenInfo>) -> Option<Self> {
+        calls.iter().find_map(|c| {
+            let call = c.effective();
+            let f = call.function.as_ref()?;
+            if f.name() != "transfer" {
+                return None;
+            }
+            let t = tokens.get(&call.target)?;
+            match (f.arg("to"), f.arg("amount")) {
+                (Some(Arg::Address(to)), Some(Arg::Uint(amount))) => Some(Self {
                     action: "Transfer".to_owned(),
-                    amount,
-                    wallet: Some(H160::from_slice(&to)),
-                    token: t.name,
+                    amount: *amount,
+                    wallet: Some(*to),
+                    token: t.symbol.clone(),
                     decimals: t.decimals,
-                });
+                }),
+                _ => None,
             }
-        }
-
-        return None;
+        })
     }
 }
@@ -0,0 +1,27 @@
+// networks the DAO could be tracked on
+
+// name of the test network, None for the mainnet
+pub fn testnet(chain_id: u64) -> Option<&'static str> {
+    match chain_id {
+        1 => None,
+        3 => Some("ropsten"),
+        4 => Some("rinkeby"),
+        5 => Some("goerli"),
+        42 => Some("kovan"),
+        11155111 => Some("sepolia"),
+        _ => Some("testnet"),
+    }
+}
+
+// block explorer of the network
+pub fn explorer(chain_id: u64) -> Option<&'static str> {
+    match chain_id {
+        1 => Some("https://etherscan.io"),
+        3 => Some("https://ropsten.etherscan.io"),
+        4 => Some("https://rinkeby.etherscan.io"),
+        5 => Some("https://goerli.etherscan.io"),
+        42 => Some("https://kovan.etherscan.io"),
+        11155111 => Some("https://sepolia.etherscan.io"),
+        _ => None,
+    }
+}
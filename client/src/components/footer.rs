@@ -1,22 +1,17 @@
+use crate::chain;
 use crate::nice;
 use crate::state::AppState;
 use sauron::prelude::*;
 
 pub fn render<T>(state: &AppState) -> Node<T> {
-    let testnet: Node<T> = if state.chain_id == 4 {
-        span(
-            vec![class("mdiv badge badge-testnet")],
-            vec![text("rinkeby")],
-        )
-    } else if state.chain_id != 1 {
-        span(
-            vec![class("mdiv badge badge-testnet")],
-            vec![text("testnet")],
-        )
-    } else {
-        span(vec![], vec![])
+    let testnet: Node<T> = match chain::testnet(state.chain_id) {
+        Some(network) => span(vec![class("mdiv badge badge-testnet")], vec![text(network)]),
+        None => span(vec![], vec![]),
+    };
+    let footer_class = match chain::testnet(state.chain_id) {
+        Some(_) => "testnet",
+        None => "",
     };
-    let footer_class = if state.chain_id == 1 { "" } else { "testnet" };
     node! {
         <footer class={footer_class}>
             <div class="inner">
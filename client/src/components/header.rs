@@ -1,3 +1,4 @@
+use crate::chain;
 use crate::state::AppState;
 use sauron::prelude::*;
 
@@ -54,15 +55,15 @@ pub fn render<T>(active_menu: &'static str, state: &AppState) -> Node<T> {
         },
     ];
 
-    let testnet: Node<T> = if state.chain_id == 4 {
-        span(vec![class("badge badge-testnet")], vec![text("rinkeby")])
-    } else if state.chain_id != 1 {
-        span(vec![class("badge badge-testnet")], vec![text("testnet")])
-    } else {
-        span(vec![], vec![])
+    let testnet: Node<T> = match chain::testnet(state.chain_id) {
+        Some(network) => span(vec![class("badge badge-testnet")], vec![text(network)]),
+        None => span(vec![], vec![]),
     };
 
-    let header_class = if state.chain_id == 1 { "" } else { "testnet" };
+    let header_class = match chain::testnet(state.chain_id) {
+        Some(_) => "testnet",
+        None => "",
+    };
     node! {
       <header class={header_class}>
         <div class="inner">
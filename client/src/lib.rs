@@ -1,11 +1,14 @@
 pub mod action;
+pub mod chain;
 pub mod components;
 pub mod events;
 pub mod eventsnode;
 pub mod logreader;
+pub mod metadata;
 pub mod nice;
 pub mod router;
 pub mod screens;
+pub mod script;
 pub mod state;
 
 use sauron::prelude::*;
@@ -1,8 +1,7 @@
-use std::str::FromStr;
 use thiserror::Error;
 use web3::types::{H160, H256, U256};
 
-#[derive(Error, Debug, Clone)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum EventParseError {
     #[error("no topics")]
     NoTopics,
@@ -10,14 +9,54 @@ pub enum EventParseError {
     InvalidTopics(usize, usize),
     #[error("{0} data length, {1} bytes expected")]
     InvalidDataSize(usize, usize),
+    #[error("no topic {0}")]
+    MissingTopic(usize),
+    #[error("{0} bytes at {1} are out of {2} bytes of data")]
+    OutOfData(usize, usize, usize),
+    #[error("invalid offset or length {0}")]
+    InvalidOffset(U256),
+    #[error("invalid address {0:?}")]
+    InvalidAddress(H256),
+    #[error("invalid bool {0}")]
+    InvalidBool(U256),
+    #[error("invalid UTF-8 string")]
+    InvalidString,
+    #[error("{0} is not supported")]
+    Unsupported(String),
+    #[error("{0} doesn't match the event: {1}")]
+    InvalidFields(String, String),
 }
 
+// text of the event as it is shown, with the fields separated by '|'
+pub fn sanitize_text(bts: &[u8]) -> String {
+    let mut s = String::from("");
+    bts.iter().filter(|ch| **ch != 0).for_each(|ch| {
+        if *ch == 0x1F {
+            s.push('|');
+        } else if *ch == '\\' as u8
+            || *ch == '"' as u8
+            || *ch == '\'' as u8
+            || *ch == '<' as u8
+            || *ch == '>' as u8
+        {
+            // preventing HTML injection
+            s.push(' ');
+        } else if *ch > 0x1F && *ch < 0x80 {
+            s.push(*ch as char);
+        }
+    });
+    s
+}
+
+// Reader of the event parameters: indexed ones from the topics,
+// others from the data, which is ABI encoded. Values of the static types
+// are in the words of the head, dynamic ones are at the offsets given in the head
 pub struct LogReader {
     pub topics: Vec<H256>,
     pub data: Vec<u8>,
     // mutable iterator for topics
     pub current_topic: usize,
-    // mutable data offset
+    // mutable offset of the next word of the head of the data
     pub data_offset: usize,
 }
 
@@ -39,7 +78,7 @@ impl LogReader {
         let data: &Vec<u8> = &log.data.0;
         if let Some(sz) = expected_data_size {
             if data.len() != sz * 32 {
-                return Err(EventParseError::InvalidDataSize(data.len(), sz));
+                return Err(EventParseError::InvalidDataSize(data.len(), sz * 32));
             }
         }
         Ok(Self {
@@ -50,83 +89,136 @@ impl LogReader {
         })
     }
 
+    // reader of ABI encoded data without topics, i.e. arguments of the call
+    pub fn from_data(data: &[u8]) -> Self {
+        Self {
+            current_topic: 1,
+            data_offset: 0,
+            topics: vec![],
+            data: data.to_vec(),
+        }
+    }
+
     fn has_topics(&self) -> bool {
         self.topics.len() > self.current_topic
     }
 
-    fn has_data(&self) -> bool {
-        self.data.len() > self.data_offset
+    // bytes of the data at the offset
+    fn slice(&self, offset: usize, len: usize) -> Result<&[u8], EventParseError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(&self.data[offset..end]),
+            _ => Err(EventParseError::OutOfData(len, offset, self.data.len())),
+        }
+    }
+
+    // word of the data at the offset
+    fn word_at(&self, offset: usize) -> Result<[u8; 32], EventParseError> {
+        let mut res = [0u8; 32];
+        res.copy_from_slice(self.slice(offset, 32)?);
+        Ok(res)
+    }
+
+    // word of the data at the offset, as offset or length within the data
+    fn usize_at(&self, offset: usize) -> Result<usize, EventParseError> {
+        let value = U256::from_big_endian(&self.word_at(offset)?);
+        if value > U256::from(self.data.len()) {
+            return Err(EventParseError::InvalidOffset(value));
+        }
+        Ok(value.as_usize())
+    }
+
+    // pop the next topic
+    pub fn topic(&mut self) -> Result<H256, EventParseError> {
+        match self.topics.get(self.current_topic) {
+            Some(x) => {
+                self.current_topic += 1;
+                Ok(*x)
+            }
+            None => Err(EventParseError::MissingTopic(self.current_topic)),
+        }
     }
 
-    fn next32(&mut self) -> String {
+    // pop the next word of the head of the data
+    pub fn word(&mut self) -> Result<[u8; 32], EventParseError> {
+        let res = self.word_at(self.data_offset)?;
+        self.data_offset += 32;
+        Ok(res)
+    }
+
+    // pop the next topic, or the next word of the data when topics are over
+    fn next32(&mut self) -> Result<[u8; 32], EventParseError> {
         if self.has_topics() {
-            let hex_str = format!("{:?}", self.topics.get(self.current_topic).unwrap()); // string of 64
-                                                                                         // println!("next topic={}", hex_str);
-            self.current_topic += 1;
-            hex_str[2..].to_owned()
+            Ok(self.topic()?.to_fixed_bytes())
         } else {
-            let hex_str = hex::encode(&self.data);
-            let offs: usize = 2 * self.data_offset;
-            let res: String = hex_str.chars().skip(offs).take(64).collect();
-            self.data_offset += 32;
-            res
+            self.word()
         }
     }
 
-    // pop meta data as text
-    pub fn text(&mut self) -> String {
-        let _hex_size = self.next32();
-        let mut s = String::from("");
-        while self.has_data() {
-            let nextword = self.next32();
-            let bts: Vec<u8> = hex::decode(nextword).unwrap();
-            bts.iter().filter(|ch| **ch != 0).for_each(|ch| {
-                if *ch == 0x1F {
-                    s.push('|');
-                } else if *ch == '\\' as u8
-                    || *ch == '"' as u8
-                    || *ch == '\'' as u8
-                    || *ch == '<' as u8
-                    || *ch == '>' as u8
-                {
-                    // preventing HTML injection
-                    s.push(' ');
-                } else if *ch > 0x1F && *ch < 0x80 {
-                    s.push(*ch as char);
-                }
-            });
+    pub fn as_address(word: &[u8; 32]) -> Result<H160, EventParseError> {
+        if word[..12].iter().any(|x| *x != 0) {
+            return Err(EventParseError::InvalidAddress(H256::from(*word)));
         }
-        s
+        Ok(H160::from_slice(&word[12..]))
     }
 
-    // pop address from the latest topic
-    pub fn address(&mut self) -> H160 {
-        let hex_str = self.next32();
-        // println!("address={}", hex_str);
-        H160::from_str(&hex_str[24..]).unwrap()
+    pub fn as_bool(word: &[u8; 32]) -> Result<bool, EventParseError> {
+        match U256::from_big_endian(word) {
+            x if x.is_zero() => Ok(false),
+            x if x == U256::one() => Ok(true),
+            x => Err(EventParseError::InvalidBool(x)),
+        }
     }
 
-    // pop array of addresses
-    pub fn addresses(&mut self) -> Vec<H160> {
-        let mut res = vec![];
-        while self.has_data() {
-            res.push(self.address());
-        }
-        res
+    // pop address from the latest topic or data
+    pub fn address(&mut self) -> Result<H160, EventParseError> {
+        Self::as_address(&self.next32()?)
     }
 
     // pop value from the latest topic or data
-    pub fn value(&mut self) -> U256 {
-        // let offs = self.data_offset;
-        let hex_str = self.next32();
-        // println!("offset={} data={}", offs, hex_str);
-        U256::from_str(hex_str.as_str()).unwrap()
+    pub fn value(&mut self) -> Result<U256, EventParseError> {
+        Ok(U256::from_big_endian(&self.next32()?))
     }
 
     // pop bool value
-    pub fn bool(&mut self) -> bool {
-        let val = self.value().as_u64();
-        val > 0
+    pub fn bool(&mut self) -> Result<bool, EventParseError> {
+        Self::as_bool(&self.next32()?)
+    }
+
+    // pop dynamic value of the data: its offset is in the head,
+    // the length and the content are at the offset.
+    // Returns the offset of the content and its length
+    fn dynamic(&mut self) -> Result<(usize, usize), EventParseError> {
+        let offset = self.usize_at(self.data_offset)?;
+        let len = self.usize_at(offset)?;
+        self.data_offset += 32;
+        Ok((offset + 32, len))
+    }
+
+    // pop bytes
+    pub fn bytes(&mut self) -> Result<Vec<u8>, EventParseError> {
+        let (offset, len) = self.dynamic()?;
+        Ok(self.slice(offset, len)?.to_vec())
+    }
+
+    // pop string
+    pub fn string(&mut self) -> Result<String, EventParseError> {
+        String::from_utf8(self.bytes()?).map_err(|_| EventParseError::InvalidString)
+    }
+
+    // pop meta data as text
+    pub fn text(&mut self) -> Result<String, EventParseError> {
+        Ok(sanitize_text(&self.bytes()?))
+    }
+
+    // pop array of words
+    pub fn words(&mut self) -> Result<Vec<[u8; 32]>, EventParseError> {
+        let (offset, len) = self.dynamic()?;
+        (0..len).map(|i| self.word_at(offset + i * 32)).collect()
+    }
+
+    // pop array of addresses
+    pub fn addresses(&mut self) -> Result<Vec<H160>, EventParseError> {
+        self.words()?.iter().map(Self::as_address).collect()
     }
 }
 
@@ -134,7 +226,25 @@ impl LogReader {
 mod tests {
     use super::*;
     use hex_literal::hex;
-    use web3::types::{Address, Log};
+    use proptest::prelude::*;
+    use web3::ethabi::{encode, Token};
+    use web3::types::{Address, Bytes, Log};
+
+    fn log(topics: Vec<H256>, data: Vec<u8>) -> Log {
+        Log {
+            address: Address::from_low_u64_be(1),
+            topics,
+            data: Bytes(data),
+            block_hash: None,
+            block_number: None,
+            transaction_hash: None,
+            transaction_index: None,
+            log_index: None,
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        }
+    }
 
     #[test]
     pub fn test_it_reads() {
@@ -156,19 +266,19 @@ mod tests {
         };
         let mut r = LogReader::new(&log, 1, Some(3)).unwrap();
         assert_eq!(
-            r.address(),
+            r.address().unwrap(),
             hex!("061b8335e1d2042975c4ed849943334bd07fb504").into()
         );
         assert_eq!(
-            r.value(),
+            r.value().unwrap(),
             hex!("0000000000000000000000000000000000000000000000056bc75e2d63100000").into()
         );
         assert_eq!(
-            r.value(),
+            r.value().unwrap(),
             hex!("0000000000000000000000000000000000000000000000056bb73f60696ee416").into()
         );
         assert_eq!(
-            r.value(),
+            r.value().unwrap(),
             hex!("0000000000000000000000000000000000000000000000000000000060da02bd").into()
         );
     }
@@ -196,16 +306,94 @@ mod tests {
         };
         let mut r = LogReader::new(&log, 2, None).unwrap();
         assert_eq!(
-            r.value(),
+            r.value().unwrap(),
             hex!("0000000000000000000000000000000000000000000000000000000000000000").into()
         );
         assert_eq!(
-            r.address(),
+            r.address().unwrap(),
             hex!("061b8335e1d2042975c4ed849943334bd07fb504").into()
         );
         assert_eq!(
-            r.text(),
-            "G1|transfer(address,uint256)|My first API3 proposal|For testing purposes"
+            r.text().unwrap(),
+            "1|transfer(address,uint256)|My first API3 proposal|For testing purposes"
+        );
+    }
+
+    #[test]
+    fn it_rejects_broken_offsets() {
+        let mut data = vec![0u8; 64];
+        // offset that is far out of the data
+        data[0..32].copy_from_slice(&[0xff; 32]);
+        let l = log(vec![H256::zero()], data.clone());
+        let mut r = LogReader::new(&l, 0, None).unwrap();
+        assert!(matches!(r.bytes(), Err(EventParseError::InvalidOffset(_))));
+        // length that is longer than the data
+        data[31] = 32;
+        data[32..64].copy_from_slice(&[0x7f; 32]);
+        let mut r = LogReader::new(&log(vec![H256::zero()], data), 0, None).unwrap();
+        assert!(matches!(r.words(), Err(EventParseError::InvalidOffset(_))));
+        assert_eq!(r.topic(), Err(EventParseError::MissingTopic(1)));
+        assert_eq!(
+            LogReader::new(&log(vec![], vec![]), 0, None).err(),
+            Some(EventParseError::NoTopics)
         );
     }
+
+    proptest! {
+        // whatever the log is, reading it fails gracefully
+        #[test]
+        fn it_never_panics(
+            topics in prop::collection::vec(any::<[u8; 32]>(), 0..5),
+            data in prop::collection::vec(any::<u8>(), 0..300),
+            reads in prop::collection::vec(0..9u8, 0..12),
+        ) {
+            let topics: Vec<H256> = topics.into_iter().map(H256::from).collect();
+            let expected = topics.len().saturating_sub(1);
+            if let Ok(mut r) = LogReader::new(&log(topics, data), expected, None) {
+                for x in reads {
+                    let _ = match x {
+                        0 => r.topic().map(|_| ()),
+                        1 => r.word().map(|_| ()),
+                        2 => r.address().map(|_| ()),
+                        3 => r.value().map(|_| ()),
+                        4 => r.bool().map(|_| ()),
+                        5 => r.bytes().map(|_| ()),
+                        6 => r.string().map(|_| ()),
+                        7 => r.text().map(|_| ()),
+                        _ => r.addresses().map(|_| ()),
+                    };
+                }
+            }
+        }
+
+        // values encoded by the ABI are read back
+        #[test]
+        fn it_reads_abi_encoded_values(
+            user in any::<[u8; 20]>(),
+            amount in any::<[u8; 32]>(),
+            flag in any::<bool>(),
+            payload in prop::collection::vec(any::<u8>(), 0..100),
+            label in ".{0,40}",
+            list in prop::collection::vec(any::<[u8; 20]>(), 0..5),
+        ) {
+            let user = H160::from(user);
+            let amount = U256::from_big_endian(&amount);
+            let list: Vec<H160> = list.into_iter().map(H160::from).collect();
+            let data = encode(&[
+                Token::Uint(amount),
+                Token::Bytes(payload.clone()),
+                Token::Bool(flag),
+                Token::String(label.clone()),
+                Token::Array(list.iter().map(|x| Token::Address(*x)).collect()),
+            ]);
+            let topics = vec![H256::zero(), H256::from(user)];
+            let mut r = LogReader::new(&log(topics, data), 1, None).unwrap();
+            prop_assert_eq!(r.address().unwrap(), user);
+            prop_assert_eq!(r.value().unwrap(), amount);
+            prop_assert_eq!(r.bytes().unwrap(), payload);
+            prop_assert_eq!(r.bool().unwrap(), flag);
+            prop_assert_eq!(r.string().unwrap(), label);
+            prop_assert_eq!(r.addresses().unwrap(), list);
+        }
+    }
 }
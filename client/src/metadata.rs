@@ -0,0 +1,225 @@
+// Metadata of the proposals, as it is given in StartVote: the spec version,
+// the signature of the function that the proposal calls, the title and the description,
+// separated by 0x1F. States of the older versions have '|' instead
+use crate::script::ScriptCall;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tiny_keccak::{Hasher, Keccak};
+
+// version of the metadata spec that is known
+pub const SPEC_VERSION: &str = "1";
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum MetadataError {
+    #[error("{0} fields found, at least 4 expected")]
+    MissingFields(usize),
+    #[error("unknown spec version {0:?}")]
+    UnknownVersion(String),
+    #[error("invalid function signature {0:?}")]
+    InvalidSignature(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProposalMetadata {
+    pub version: String,
+    /// signature of the called function, i.e. "transfer(address,uint256)"
+    pub signature: String,
+    pub title: String,
+    pub description: String,
+    /// fields after the description, which are not in the spec
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extras: Vec<String>,
+}
+
+impl ProposalMetadata {
+    pub fn parse(metadata: &str) -> Result<Self, MetadataError> {
+        let separator = if metadata.contains('\u{1f}') {
+            '\u{1f}'
+        } else {
+            '|'
+        };
+        let parts: Vec<&str> = metadata.split(separator).collect();
+        if parts.len() < 4 {
+            return Err(MetadataError::MissingFields(parts.len()));
+        }
+        let version = parts[0].trim();
+        if version != SPEC_VERSION {
+            return Err(MetadataError::UnknownVersion(version.to_owned()));
+        }
+        let signature = parts[1].trim();
+        if !signature.is_empty() && !is_signature(signature) {
+            return Err(MetadataError::InvalidSignature(signature.to_owned()));
+        }
+        Ok(Self {
+            version: version.to_owned(),
+            signature: signature.to_owned(),
+            title: parts[2].to_owned(),
+            description: parts[3].to_owned(),
+            extras: parts[4..].iter().map(|x| x.to_string()).collect(),
+        })
+    }
+
+    // selector of the function of the signature
+    pub fn selector(&self) -> Option<[u8; 4]> {
+        if self.signature.is_empty() {
+            return None;
+        }
+        let mut hash = [0u8; 32];
+        let mut hasher = Keccak::v256();
+        hasher.update(self.signature.as_bytes());
+        hasher.finalize(&mut hash);
+        let mut res = [0u8; 4];
+        res.copy_from_slice(&hash[..4]);
+        Some(res)
+    }
+
+    // how the script differs from the function of the metadata,
+    // the function should be called by the agent or directly
+    pub fn mismatch(&self, calls: &[ScriptCall]) -> Option<String> {
+        let selector = match self.selector() {
+            Some(x) => x,
+            None if calls.is_empty() => return None,
+            None => {
+                return Some(format!(
+                    "no function is given, but the script makes {} calls",
+                    calls.len()
+                ))
+            }
+        };
+        if calls.is_empty() {
+            return Some(format!(
+                "{} is given, but the script is empty",
+                self.signature
+            ));
+        }
+        let calls_selector = |c: &ScriptCall| c.calldata.0.starts_with(&selector);
+        if calls
+            .iter()
+            .any(|c| calls_selector(c) || calls_selector(c.effective()))
+        {
+            return None;
+        }
+        let called: Vec<String> = calls
+            .iter()
+            .map(|c| {
+                let c = c.effective();
+                match &c.function {
+                    Some(f) => f.signature.clone(),
+                    None => {
+                        let data = &c.calldata.0;
+                        format!("0x{}", hex::encode(&data[..data.len().min(4)]))
+                    }
+                }
+            })
+            .collect();
+        Some(format!(
+            "{} is given, but the script calls {}",
+            self.signature,
+            called.join(", ")
+        ))
+    }
+}
+
+// function name with the types of the arguments, without spaces
+fn is_signature(s: &str) -> bool {
+    let (name, args) = match s.find('(') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return false,
+    };
+    let args = match args.strip_suffix(')') {
+        Some(x) => x,
+        None => return false,
+    };
+    let ident = |x: &str| {
+        !x.is_empty()
+            && x.chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '$')
+    };
+    let kind = |x: &str| {
+        !x.is_empty()
+            && x.chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || "[](),".contains(ch))
+    };
+    ident(name)
+        && !name.starts_with(|ch: char| ch.is_ascii_digit())
+        && (args.is_empty() || kind(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::AGENT_EXECUTE;
+    use hex_literal::hex;
+    use web3::ethabi::{encode, Token};
+    use web3::types::{H160, U256};
+
+    #[test]
+    fn it_parses_metadata() {
+        let m = ProposalMetadata::parse(
+            "1\u{1f}transfer(address,uint256)\u{1f}Grant for \"<Team>\" | Q1\u{1f}For testing purposes",
+        )
+        .unwrap();
+        assert_eq!(m.signature, "transfer(address,uint256)");
+        assert_eq!(m.title, "Grant for \"<Team>\" | Q1");
+        assert_eq!(m.description, "For testing purposes");
+        assert!(m.extras.is_empty());
+        assert_eq!(m.selector(), Some(hex!("a9059cbb")));
+
+        // the metadata of the older states
+        let m = ProposalMetadata::parse("1|setMaxApr(uint256)|Title|Text|extra").unwrap();
+        assert_eq!(m.title, "Title");
+        assert_eq!(m.extras, vec!["extra".to_owned()]);
+
+        assert_eq!(
+            ProposalMetadata::parse("My first proposal"),
+            Err(MetadataError::MissingFields(1))
+        );
+        assert_eq!(
+            ProposalMetadata::parse("2|f()|Title|Text"),
+            Err(MetadataError::UnknownVersion("2".to_owned()))
+        );
+        assert_eq!(
+            ProposalMetadata::parse("1|transfer(address, uint256)|Title|Text"),
+            Err(MetadataError::InvalidSignature(
+                "transfer(address, uint256)".to_owned()
+            ))
+        );
+        assert!(ProposalMetadata::parse("1|transfer|Title|Text").is_err());
+        assert!(ProposalMetadata::parse("1||Title|Text").is_ok());
+    }
+
+    #[test]
+    fn it_checks_the_script() {
+        let token = H160::from_low_u64_be(0xb1);
+        let mut transfer = hex!("a9059cbb").to_vec();
+        transfer.extend(encode(&[Token::Address(token), Token::Uint(5.into())]));
+        let mut execute = AGENT_EXECUTE.to_vec();
+        execute.extend(encode(&[
+            Token::Address(token),
+            Token::Uint(U256::zero()),
+            Token::Bytes(transfer),
+        ]));
+        let calls = vec![ScriptCall::new(H160::from_low_u64_be(0xa1), &execute)];
+        let metadata = |signature: &str| ProposalMetadata {
+            version: SPEC_VERSION.to_owned(),
+            signature: signature.to_owned(),
+            ..ProposalMetadata::default()
+        };
+        assert_eq!(metadata("transfer(address,uint256)").mismatch(&calls), None);
+        assert_eq!(
+            metadata("approve(address,uint256)").mismatch(&calls),
+            Some(
+                "approve(address,uint256) is given, but the script calls transfer(address,uint256)"
+                    .to_owned()
+            )
+        );
+        assert!(metadata("").mismatch(&calls).is_some());
+        assert!(metadata("transfer(address,uint256)")
+            .mismatch(&[])
+            .is_some());
+        assert_eq!(metadata("").mismatch(&[]), None);
+        // unknown functions are compared by the selector
+        let unknown = ScriptCall::new(token, &hex!("12345678"));
+        assert!(metadata("foo()").mismatch(&[unknown]).is_some());
+    }
+}
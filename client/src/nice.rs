@@ -84,6 +84,18 @@ pub fn pct4_of(amt: U256, total: U256, decimals: usize) -> String {
     format!("{:.4}", value)
 }
 
+// percentage of the share with the given decimals (1.0 is 100%),
+// cut to 2 digits without going through floats
+pub fn pct_fixed(share: U256, decimals: usize) -> String {
+    let hundredths = if decimals >= 4 {
+        share / U256::exp10(decimals - 4)
+    } else {
+        share * U256::exp10(4 - decimals)
+    };
+    let (int, frac) = (hundredths / 100, (hundredths % 100).low_u64());
+    format!("{}.{:02}", with_commas(&int.to_string()), frac)
+}
+
 pub fn pct_val(amt: U256, total: U256, decimals: usize) -> f64 {
     let prec = decimals;
     dec(amt, prec) / dec(total, prec)
@@ -103,6 +115,15 @@ pub fn date(tm: u64) -> String {
 mod tests {
     use super::*;
     use std::str::FromStr;
+    #[test]
+    pub fn test_pct_fixed() {
+        let pct = |x: u64| U256::exp10(16) * x;
+        assert_eq!(pct_fixed(pct(3), 18), "3.00");
+        assert_eq!(pct_fixed(pct(50), 18), "50.00");
+        assert_eq!(pct_fixed(U256::exp10(14) * 1234, 18), "12.34");
+        assert_eq!(pct_fixed(U256::exp10(18), 18), "100.00");
+    }
+
     #[test]
     pub fn test_amount_under_1() -> Result<(), String> {
         let val = U256::from_str("5843424da37c000").unwrap();
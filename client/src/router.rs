@@ -3,12 +3,10 @@ use crate::state::AppState;
 use sauron::prelude::*;
 use web3::types::{H160, H256};
 
-pub fn link_eventlog<T>(chain_id: u64, block_number: u64, tx: H256) -> Node<T> {
-    let link = match chain_id {
-        1 => Some(format!("https://etherscan.io/tx/{:?}#eventlog", tx)),
-        4 => Some(format!("https://rinkeby.etherscan.io/tx/{:?}#eventlog", tx)),
-        _ => None,
-    };
+pub fn link_eventlog<T>(state: &AppState, block_number: u64, tx: H256) -> Node<T> {
+    let link = state
+        .explorer()
+        .map(|x| format!("{}/tx/{:?}#eventlog", x, tx));
     match link {
         Some(link) => node! {
             <a href={link} rel="nofollow noopener noreferrer" target="_blank">
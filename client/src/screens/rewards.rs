@@ -59,7 +59,7 @@ impl Screen {
         node! {
             <tr>
                 <td class="c">{text(nice::int(ep.index))}</td>
-                <td class="c">{link_eventlog(self.state.chain_id, ep.block_number, ep.tx)}</td>
+                <td class="c">{link_eventlog(&self.state, ep.block_number, ep.tx)}</td>
                 <td class="c">{ text(nice::date(ep.tm)) }</td>
                 <td class="r darken">{ text(format!("{:.2}%", 100.0*ep.apr)) }</td>
                 <td class="r accent">{ text(format!("{:.4}%", 100.0*ep.apr*self.rewards_coeff() / 52.0)) }</td>
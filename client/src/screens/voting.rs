@@ -3,10 +3,12 @@ use crate::components::footer;
 use crate::components::header;
 use crate::events::{self, Api3, VotingAgent};
 use crate::eventsnode::wrap_vote_details;
+use crate::logreader::sanitize_text;
 use crate::nice;
 use crate::router::{link_eventlog, link_wallet};
 use crate::screens::meta::{MetaProvider, PageMetaInfo};
-use crate::state::{AppState, OnChainEvent};
+use crate::script::ScriptCall;
+use crate::state::{AppState, Execution, OnChainEvent, PCT_DECIMALS};
 use sauron::prelude::*;
 use serde::{Deserialize, Serialize};
 use web3::types::{H160, U256};
@@ -51,6 +53,90 @@ impl Screen {
             </tr>
         }
     }
+    // call of the script, the calls of the agent are shown with their targets
+    pub fn render_call(&self, call: &ScriptCall) -> Node<Msg> {
+        match &call.function {
+            Some(f) => match &f.inner {
+                Some(inner) => node! {
+                    <span>
+                        <span class="darken">"Agent "</span>
+                        {link_wallet(&self.state, call.target)}
+                        <span class="darken">" calls "</span>
+                        {self.render_call(inner)}
+                    </span>
+                },
+                None => node! {
+                    <span>
+                        {link_wallet(&self.state, call.target)}
+                        <div><small>{text(f.to_string())}</small></div>
+                    </span>
+                },
+            },
+            None => node! {
+                <span>
+                    {link_wallet(&self.state, call.target)}
+                    <div class="darken"><small>{text(format!("0x{}", hex::encode(&call.calldata.0)))}</small></div>
+                </span>
+            },
+        }
+    }
+
+    // steps of the EVM script of the voting
+    pub fn render_script(&self, calls: &[ScriptCall]) -> Node<Msg> {
+        let block = "text-align: left; word-break: break-all; line-height: 1.5";
+        node! {
+            <div>
+                <h2 style="text-align: center">"Proposed Actions"</h2>
+                <ol style={block}>
+                    {for call in calls {
+                        node! { <li>{self.render_call(call)}</li> }
+                    }}
+                </ol>
+            </div>
+        }
+    }
+
+    // calls of the agent when the voting was executed
+    pub fn render_execution(&self, x: &Execution) -> Node<Msg> {
+        let block = "text-align: left; word-break: break-all; line-height: 1.5";
+        node! {
+            <div>
+                <h2 style="text-align: center">"Executed by the Agent"</h2>
+                <ol style={block}>
+                    {for call in &x.calls {
+                        node! {
+                            <li>
+                                <span class="darken">"Call "</span>
+                                {link_wallet(&self.state, call.target)}
+                                {if call.eth_value > U256::from(0) {
+                                    node! {
+                                        <span>
+                                            <span class="darken">" with "</span>
+                                            <strong title={nice::amount(call.eth_value, 18)}>{text(nice::ceil(call.eth_value, 18))}</strong>
+                                            <span class="darken">" ETH"</span>
+                                        </span>
+                                    }
+                                } else {
+                                    text("")
+                                }}
+                                <div class="darken"><small>{text(format!("0x{}", hex::encode(&call.data.0)))}</small></div>
+                            </li>
+                        }
+                    }}
+                </ol>
+                {match &x.return_data {
+                    Some(r) if !r.0.is_empty() => node! {
+                        <p style={block}>
+                            <span class="darken">"Returned "</span>
+                            <small>{text(format!("0x{}", hex::encode(&r.0)))}</small>
+                        </p>
+                    },
+                    _ => text(""),
+                }}
+            </div>
+        }
+    }
+
     pub fn render_event(&self, _e: &OnChainEvent, _total_shares: U256) -> Node<Msg> {
         div(vec![], vec![])
     }
@@ -120,7 +206,7 @@ impl Screen {
             <tr>
                 <td class="c">{text(format!("{}.", index + 1))}</td>
                 <td class="c darken dt">{text(nice::date(e.tm))}</td>
-                <td class="c">{link_eventlog(self.state.chain_id, e.block_number, e.tx)}</td>
+                <td class="c">{link_eventlog(&self.state, e.block_number, e.tx)}</td>
                 <td class="c darken entry">{text(event)}</td>
                 <td class="l eth-address">{
                     match voter {
@@ -175,8 +261,8 @@ impl Component<Msg> for Screen {
             if v.primary { "Primary" } else { "Secondary" }
         );
         let total = v.votes_total;
-        let pct_required = if v.primary { 50u64 } else { 15u64 };
-        let required = total * U256::from(pct_required) / U256::from(100);
+        let params = self.state.params_of(v);
+        let required = params.required(total);
         let pct_yes = nice::pct3_of(v.voted_yes, v.votes_total, 18);
         let pct_no = nice::pct3_of(v.voted_no, v.votes_total, 18);
         let sorted: Vec<OnChainEvent> = self.state.votings_events.get(&v.as_u64()).unwrap().clone();
@@ -188,6 +274,19 @@ impl Component<Msg> for Screen {
                     <h1>{text(v.title.clone())}</h1>
                     <h2 style="text-align: center">{text(subtitle)}</h2>
                     <p style="text-align: center; line-height: 1.5">{text(v.description.clone())}</p>
+                    {match (&v.metadata_error, v.metadata_mismatch()) {
+                        (Some(e), _) => node! {
+                            <p class="warning" style="text-align: center">
+                                {text(format!("Metadata of the proposal is malformed: {}", sanitize_text(e.as_bytes())))}
+                            </p>
+                        },
+                        (None, Some(m)) => node! {
+                            <p class="warning" style="text-align: center">
+                                {text(format!("Metadata doesn't match the script: {}", sanitize_text(m.as_bytes())))}
+                            </p>
+                        },
+                        _ => text(""),
+                    }}
                     <p style="text-align: center; line-height: 3">
                         {wrap_vote_details(&v.details)}
                     </p>
@@ -196,10 +295,12 @@ impl Component<Msg> for Screen {
                         <strong title={nice::amount(total, 18)}>{ text(nice::ceil(total, 18)) }</strong>
                         <span class="darken">" shares staked, "</span>
                         <strong title={nice::amount(required, 18)}>{ text(nice::ceil(required, 18)) }</strong>
-                        <span class="darken">" shares are required for this proposal to be accepted"</span>
+                        <span class="darken">" shares are required for this proposal to be accepted, with "</span>
+                        <strong>{text(format!("{}%", nice::pct_fixed(params.support_required_pct, PCT_DECIMALS)))}</strong>
+                        <span class="darken">" of the cast votes supporting it"</span>
                     </p>
                     {if v.voted_yes > U256::from(0) {
-                        let cls = if v.voted_yes > required { "accent" } else { "" };
+                        let cls = if params.is_passing(v) { "accent" } else { "" };
                         node! {
                             <p class={cls} style="text-align: center">
                                 <strong title={nice::amount(v.voted_yes, 18)}>{ text(nice::ceil(v.voted_yes, 18)) }</strong>
@@ -231,13 +332,21 @@ impl Component<Msg> for Screen {
 
                     {if v.executed {
                         node! { <h3 style={decision} class="accent">"ACCEPTED AND EXECUTED"</h3> }
-                    } else if v.voted_yes > required {
+                    } else if params.is_passing(v) {
                         node! { <h3 style={decision} class="accent">"PROPOSAL IS PASSING, NOT EXECUTED"</h3> }
                     } else if v.voted_no > required {
                         node! { <h3 style={decision} class="warning">"PROPOSAL REJECTED"</h3> }
                     } else {
                         text("")
                     }}
+                    {match &v.details {
+                        Some(d) if !d.calls.is_empty() => self.render_script(&d.calls),
+                        _ => text(""),
+                    }}
+                    {match &v.execution {
+                        Some(x) => self.render_execution(x),
+                        None => text(""),
+                    }}
 
                     <h2 style="text-align: center">"Voting History Log"</h2>
                     {if self.state.votings_events.len() > 0 {
@@ -6,7 +6,6 @@ use crate::screens::meta::{MetaProvider, PageMetaInfo};
 use crate::state::{AppState, Voting};
 use sauron::prelude::*;
 use serde::{Deserialize, Serialize};
-use web3::types::U256;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Screen {
@@ -38,11 +37,11 @@ impl Screen {
         }
     }
     pub fn render_voting_tr(&self, index: usize, v: &Voting) -> Node<Msg> {
-        let pct_required = if v.primary { 50u64 } else { 15u64 };
-        let required = v.votes_total * U256::from(pct_required) / U256::from(100);
+        let params = self.state.params_of(v);
+        let required = params.required(v.votes_total);
         let pct_yes = nice::pct3_of(v.voted_yes, v.votes_total, 18);
         let pct_no = nice::pct3_of(v.voted_no, v.votes_total, 18);
-        let class_yes = if v.voted_yes > required {
+        let class_yes = if params.is_passing(v) {
             "r accent"
         } else {
             "r"
@@ -95,12 +94,12 @@ impl Screen {
             <li>
                 <div class="voting">
                     <a href={format!("votings/{}", voting.key()) }>
-                        { text(format!("{}: {:?}",
+                        { text(format!("{}: {}",
                             if voting.primary {
                                 "Primary"
                             } else {
                                 "Secondary"
-                            }, voting.metadata)) }
+                            }, voting.title)) }
                     </a>
                 </div>
             </li>
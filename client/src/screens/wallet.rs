@@ -46,7 +46,7 @@ impl Screen {
             <tr>
                 <td class="c">{text(format!("{}.", index + 1))}</td>
                 <td class="c darken dt">{text(nice::date(e.tm))}</td>
-                <td class="c">{link_eventlog(self.state.chain_id, e.block_number, e.tx)}</td>
+                <td class="c">{link_eventlog(&self.state, e.block_number, e.tx)}</td>
                 <td class="l entry darken">{entry_node(&e.entry, self.addr, &self.state)}</td>
             </tr>
         }
@@ -244,7 +244,7 @@ impl Screen {
         node! {
             <tr>
                 <td class="c">{text(nice::int(ep.index))}</td>
-                <td class="c">{link_eventlog(self.state.chain_id, ep.block_number, ep.tx)}</td>
+                <td class="c">{link_eventlog(&self.state, ep.block_number, ep.tx)}</td>
                 <td class="c">{ text(nice::date(ep.tm)) }</td>
                 <td class="r darken">{ text(format!("{:.2}%", 100.0*ep.apr)) }</td>
                 <td class="r darken" title={nice::amount(ep.total, 18)}>{ text(nice::ceil(ep.total, 18)) }</td>
@@ -0,0 +1,387 @@
+// EVM scripts of the votings, which are executed by the voting app when the voting passes.
+// The script starts with the id of the executor, the DAO uses CallsScript (1),
+// which is a list of calls: the target address, the length of the calldata as uint32
+// and the calldata itself. The calls of the known functions are decoded
+use crate::logreader::{EventParseError, LogReader};
+use hex_literal::hex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+use web3::types::{Bytes, H160, U256};
+
+// id of the CallsScript executor
+pub const CALLS_SCRIPT: [u8; 4] = hex!("00000001");
+
+// selector of `execute(address,uint256,bytes)` of the agent
+pub const AGENT_EXECUTE: [u8; 4] = hex!("b61d27f6");
+
+// selectors of the known functions, their signatures and the names of the arguments
+const FUNCTIONS: &[([u8; 4], &str, &[&str])] = &[
+    (
+        hex!("a9059cbb"),
+        "transfer(address,uint256)",
+        &["to", "amount"],
+    ),
+    (
+        hex!("095ea7b3"),
+        "approve(address,uint256)",
+        &["spender", "amount"],
+    ),
+    (
+        hex!("23b872dd"),
+        "transferFrom(address,address,uint256)",
+        &["from", "to", "amount"],
+    ),
+    (
+        AGENT_EXECUTE,
+        "execute(address,uint256,bytes)",
+        &["target", "eth_value", "data"],
+    ),
+    (
+        hex!("ea26afd7"),
+        "setStakeTarget(uint256)",
+        &["stake_target"],
+    ),
+    (hex!("e3b34174"), "setMaxApr(uint256)", &["max_apr"]),
+    (hex!("756b2a8b"), "setMinApr(uint256)", &["min_apr"]),
+    (
+        hex!("fcde28cc"),
+        "setUnstakeWaitPeriod(uint256)",
+        &["unstake_wait_period"],
+    ),
+    (
+        hex!("2b2c2262"),
+        "setAprUpdateStep(uint256)",
+        &["apr_update_step"],
+    ),
+    (
+        hex!("7fd63875"),
+        "setProposalVotingPowerThreshold(uint256)",
+        &["proposal_voting_power_threshold"],
+    ),
+    (
+        hex!("a78c6ddc"),
+        "setDaoApps(address,address,address,address)",
+        &[
+            "agent_app_primary",
+            "agent_app_secondary",
+            "voting_app_primary",
+            "voting_app_secondary",
+        ],
+    ),
+    (
+        hex!("f68308c7"),
+        "setClaimsManagerStatus(address,bool)",
+        &["claims_manager", "status"],
+    ),
+    (
+        hex!("16b26a9a"),
+        "setErc20Addresses(address[])",
+        &["addresses"],
+    ),
+    (
+        hex!("7c1d0b87"),
+        "changeSupportRequiredPct(uint64)",
+        &["support_required_pct"],
+    ),
+    (
+        hex!("5eb24332"),
+        "changeMinAcceptQuorumPct(uint64)",
+        &["min_accept_quorum_pct"],
+    ),
+];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    #[error("script of {0} bytes has no executor id")]
+    NoSpec(usize),
+    #[error("executor 0x{0} is not supported")]
+    UnsupportedSpec(String),
+    #[error("call at {0} needs {1} bytes, {2} left")]
+    Truncated(usize, usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Arg {
+    Address(H160),
+    Uint(U256),
+    Bool(bool),
+    Bytes(Bytes),
+    String(String),
+    Addresses(Vec<H160>),
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(x) => write!(f, "{:?}", x),
+            Self::Uint(x) => write!(f, "{}", x),
+            Self::Bool(x) => write!(f, "{}", x),
+            Self::Bytes(x) => write!(f, "0x{}", hex::encode(&x.0)),
+            Self::String(x) => write!(f, "{:?}", x),
+            Self::Addresses(x) => {
+                let list: Vec<String> = x.iter().map(|a| format!("{:?}", a)).collect();
+                write!(f, "[{}]", list.join(", "))
+            }
+        }
+    }
+}
+
+// call of the known function
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    /// signature of the function, i.e. "transfer(address,uint256)"
+    pub signature: String,
+    /// names and values of the arguments
+    pub args: Vec<(String, Arg)>,
+    /// call that is made by the agent in `execute`
+    pub inner: Option<Box<ScriptCall>>,
+}
+
+impl FunctionCall {
+    pub fn name(&self) -> &str {
+        self.signature.split('(').next().unwrap_or_default()
+    }
+
+    pub fn arg(&self, name: &str) -> Option<&Arg> {
+        self.args.iter().find(|(n, _)| n == name).map(|(_, x)| x)
+    }
+}
+
+impl fmt::Display for FunctionCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|(name, x)| format!("{}: {}", name, x))
+            .collect();
+        write!(f, "{}({})", self.name(), args.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScriptCall {
+    pub target: H160,
+    pub calldata: Bytes,
+    /// decoded call, none for the unknown functions and invalid arguments
+    pub function: Option<FunctionCall>,
+}
+
+impl ScriptCall {
+    pub fn new(target: H160, calldata: &[u8]) -> Self {
+        Self {
+            target,
+            calldata: Bytes(calldata.to_vec()),
+            function: decode_call(calldata),
+        }
+    }
+
+    // call that is finally made, through the agent or directly
+    pub fn effective(&self) -> &ScriptCall {
+        match self.function.as_ref().and_then(|x| x.inner.as_ref()) {
+            Some(inner) => inner.effective(),
+            None => self,
+        }
+    }
+}
+
+// token of the ERC20 call, made by the agent or directly
+pub fn token_of(call: &ScriptCall) -> Option<H160> {
+    let call = call.effective();
+    match call.function.as_ref()?.name() {
+        "transfer" | "approve" | "transferFrom" => Some(call.target),
+        _ => None,
+    }
+}
+
+// calls of the script, empty script has no calls
+pub fn parse(script: &[u8]) -> Result<Vec<ScriptCall>, ScriptError> {
+    if script.is_empty() {
+        return Ok(vec![]);
+    }
+    if script.len() < 4 {
+        return Err(ScriptError::NoSpec(script.len()));
+    }
+    if script[..4] != CALLS_SCRIPT {
+        return Err(ScriptError::UnsupportedSpec(hex::encode(&script[..4])));
+    }
+    let mut res = vec![];
+    let mut offset = 4;
+    while offset < script.len() {
+        let left = script.len() - offset;
+        if left < 24 {
+            return Err(ScriptError::Truncated(offset, 24, left));
+        }
+        let target = H160::from_slice(&script[offset..offset + 20]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&script[offset + 20..offset + 24]);
+        let len = u32::from_be_bytes(len) as usize;
+        if left - 24 < len {
+            return Err(ScriptError::Truncated(offset, 24 + len, left));
+        }
+        let start = offset + 24;
+        res.push(ScriptCall::new(target, &script[start..start + len]));
+        offset = start + len;
+    }
+    Ok(res)
+}
+
+// types of the arguments in the signature
+fn arg_types(signature: &str) -> Vec<&str> {
+    let list = signature
+        .split('(')
+        .nth(1)
+        .unwrap_or_default()
+        .trim_end_matches(')');
+    if list.is_empty() {
+        return vec![];
+    }
+    list.split(',').collect()
+}
+
+fn decode_arg(kind: &str, r: &mut LogReader) -> Result<Arg, EventParseError> {
+    Ok(match kind {
+        "address" => Arg::Address(r.address()?),
+        "bool" => Arg::Bool(r.bool()?),
+        "bytes" => Arg::Bytes(Bytes(r.bytes()?)),
+        "string" => Arg::String(r.text()?),
+        "address[]" => Arg::Addresses(r.addresses()?),
+        x if x.starts_with("uint") => Arg::Uint(r.value()?),
+        x => return Err(EventParseError::Unsupported(x.to_owned())),
+    })
+}
+
+// decoded call of the known function
+pub fn decode_call(calldata: &[u8]) -> Option<FunctionCall> {
+    if calldata.len() < 4 {
+        return None;
+    }
+    let (selector, signature, names) = FUNCTIONS.iter().find(|(s, _, _)| *s == calldata[..4])?;
+    let mut r = LogReader::from_data(&calldata[4..]);
+    let mut args = vec![];
+    for (name, kind) in names.iter().zip(arg_types(signature)) {
+        match decode_arg(kind, &mut r) {
+            Ok(x) => args.push((name.to_string(), x)),
+            Err(e) => {
+                warn!("invalid arguments of {}: {}", signature, e);
+                return None;
+            }
+        }
+    }
+    let inner = match (*selector == AGENT_EXECUTE, args.as_slice()) {
+        (true, [(_, Arg::Address(target)), _, (_, Arg::Bytes(data))]) => {
+            Some(Box::new(ScriptCall::new(*target, &data.0)))
+        }
+        _ => None,
+    };
+    Some(FunctionCall {
+        signature: signature.to_string(),
+        args,
+        inner,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use web3::ethabi::{encode, Token};
+
+    fn call(selector: [u8; 4], args: &[Token]) -> Vec<u8> {
+        let mut res = selector.to_vec();
+        res.extend(encode(args));
+        res
+    }
+
+    fn script(calls: &[(H160, Vec<u8>)]) -> Vec<u8> {
+        let mut res = CALLS_SCRIPT.to_vec();
+        for (target, data) in calls {
+            res.extend(target.as_bytes());
+            res.extend(&(data.len() as u32).to_be_bytes());
+            res.extend(data);
+        }
+        res
+    }
+
+    #[test]
+    fn it_parses_calls_script() {
+        let agent = H160::from_low_u64_be(0xa1);
+        let token = H160::from_low_u64_be(0xb1);
+        let pool = H160::from_low_u64_be(0xc1);
+        let to = H160::from_low_u64_be(0xd1);
+        let transfer = call(
+            hex!("a9059cbb"),
+            &[Token::Address(to), Token::Uint(U256::from(1000))],
+        );
+        let execute = call(
+            AGENT_EXECUTE,
+            &[
+                Token::Address(token),
+                Token::Uint(U256::zero()),
+                Token::Bytes(transfer),
+            ],
+        );
+        let set_apr = call(hex!("e3b34174"), &[Token::Uint(U256::from(75))]);
+        let unknown = vec![1, 2, 3, 4, 5];
+        let calls = parse(&script(&[
+            (agent, execute),
+            (pool, set_apr),
+            (pool, unknown.clone()),
+        ]))
+        .unwrap();
+        assert_eq!(calls.len(), 3);
+
+        assert_eq!(calls[0].target, agent);
+        let f = calls[0].function.as_ref().unwrap();
+        assert_eq!(f.name(), "execute");
+        let inner = calls[0].effective();
+        assert_eq!(inner.target, token);
+        let f = inner.function.as_ref().unwrap();
+        assert_eq!(f.arg("to"), Some(&Arg::Address(to)));
+        assert_eq!(f.arg("amount"), Some(&Arg::Uint(U256::from(1000))));
+        assert_eq!(
+            f.to_string(),
+            format!("transfer(to: {:?}, amount: 1000)", to)
+        );
+
+        let f = calls[1].function.as_ref().unwrap();
+        assert_eq!(f.to_string(), "setMaxApr(max_apr: 75)");
+        assert_eq!(calls[1].effective(), &calls[1]);
+
+        assert!(calls[2].function.is_none());
+        assert_eq!(calls[2].calldata.0, unknown);
+
+        assert_eq!(parse(&[]).unwrap(), vec![]);
+        assert_eq!(parse(&CALLS_SCRIPT).unwrap(), vec![]);
+        assert_eq!(
+            parse(&hex!("00000002")),
+            Err(ScriptError::UnsupportedSpec("00000002".to_owned()))
+        );
+        let mut broken = script(&[(pool, vec![1, 2, 3, 4, 5])]);
+        broken.pop();
+        assert_eq!(parse(&broken), Err(ScriptError::Truncated(4, 29, 28)));
+        // known functions with broken arguments are kept as raw data
+        let short = call(hex!("a9059cbb"), &[Token::Address(to)]);
+        assert!(parse(&script(&[(token, short)])).unwrap()[0]
+            .function
+            .is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn it_never_panics_on_arbitrary_scripts(
+            data in prop::collection::vec(any::<u8>(), 0..400),
+            args in prop::collection::vec(any::<[u8; 32]>(), 0..6),
+            index in any::<usize>(),
+        ) {
+            let _ = parse(&data);
+            let mut spec = CALLS_SCRIPT.to_vec();
+            spec.extend(&data);
+            let _ = parse(&spec);
+            // known selectors with the random arguments
+            let (selector, _, _) = FUNCTIONS[index % FUNCTIONS.len()];
+            let _ = decode_call(&call(selector, &[]).into_iter().chain(args.concat()).collect::<Vec<u8>>());
+        }
+    }
+}
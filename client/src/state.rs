@@ -1,9 +1,12 @@
 use crate::action::VotingAction;
 use crate::events::{Api3, VotingAgent};
+use crate::logreader::sanitize_text;
+use crate::metadata::ProposalMetadata;
 use crate::nice;
+use crate::script::{self, ScriptCall};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use web3::types::{H160, H256, U256};
+use std::collections::{BTreeMap, BTreeSet};
+use web3::types::{Bytes, H160, H256, U256};
 
 // General API3 Pool information
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,6 +73,9 @@ pub struct OnChainEvent {
     pub block_number: u64,
     pub tx: H256,
     pub log_index: u64,
+    /// version of the contract that emitted the event, when it is read in a block range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -84,13 +90,21 @@ pub struct VotingStaticData {
 }
 
 impl VotingStaticData {
-    pub fn into_details(&self) -> VotingDetails {
+    pub fn into_details(&self, tokens: &BTreeMap<H160, TokenInfo>) -> VotingDetails {
+        let calls = match script::parse(&self.script) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("invalid script of the voting: {}", e);
+                vec![]
+            }
+        };
         VotingDetails {
             start_date: self.start_date,
             support_required: self.support_required,
             min_quorum: self.min_quorum,
             voting_power: self.voting_power,
-            action: VotingAction::from_script(&self.script),
+            action: VotingAction::from_calls(&calls, tokens),
+            calls,
             user_voting_power_at: self.user_voting_power_at,
             discussion_url: self.discussion_url.clone(),
         }
@@ -104,10 +118,85 @@ pub struct VotingDetails {
     pub min_quorum: f64,       //typically 0.15 for secondary
     pub voting_power: U256,
     pub action: Option<VotingAction>,
+    /// calls of the EVM script of the voting
+    #[serde(default)]
+    pub calls: Vec<ScriptCall>,
     pub user_voting_power_at: U256,
     pub discussion_url: String,
 }
 
+// decimals of the shares in the parameters of the voting apps (PCT_BASE is 10^18)
+pub const PCT_DECIMALS: usize = 18;
+
+// parameters of the voting app that the votings are judged by.
+// Shares are kept as the voting app has them, in `PCT_DECIMALS` decimals
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VoteParams {
+    /// block where the parameters were set
+    pub block_number: u64,
+    /// share of the cast votes that should support the voting
+    pub support_required_pct: U256,
+    /// share of all votes that should support the voting
+    pub min_quorum_pct: U256,
+}
+
+impl VoteParams {
+    // initial parameters of the voting apps of the DAO
+    pub fn initial(agent: &VotingAgent) -> Self {
+        let pct = |x: u64| U256::exp10(PCT_DECIMALS - 2) * x;
+        Self {
+            block_number: 0,
+            support_required_pct: pct(50),
+            min_quorum_pct: match agent {
+                VotingAgent::Primary => pct(50),
+                VotingAgent::Secondary => pct(15),
+            },
+        }
+    }
+
+    fn share(total: U256, pct: U256) -> U256 {
+        total * pct / U256::exp10(PCT_DECIMALS)
+    }
+
+    // shares that should support the voting to be accepted
+    pub fn required(&self, total: U256) -> U256 {
+        Self::share(total, self.min_quorum_pct)
+    }
+
+    // whether the voting has the quorum and the support of the cast votes
+    pub fn is_passing(&self, v: &Voting) -> bool {
+        let cast = v.voted_yes + v.voted_no;
+        v.voted_yes > self.required(v.votes_total)
+            && v.voted_yes > Self::share(cast, self.support_required_pct)
+    }
+}
+
+pub fn get_initial_vote_params() -> BTreeMap<VotingAgent, Vec<VoteParams>> {
+    let mut res = BTreeMap::new();
+    for agent in [VotingAgent::Primary, VotingAgent::Secondary] {
+        res.insert(agent.clone(), vec![VoteParams::initial(&agent)]);
+    }
+    res
+}
+
+// call that the agent made for the executed voting
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AgentCall {
+    /// address of the agent
+    pub agent: H160,
+    pub target: H160,
+    pub eth_value: U256,
+    pub data: Bytes,
+}
+
+// what was done when the voting was executed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Execution {
+    pub calls: Vec<AgentCall>,
+    /// data returned by the script
+    pub return_data: Option<Bytes>,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Voting {
     pub primary: bool,
@@ -117,6 +206,12 @@ pub struct Voting {
     pub tx: H256,
     pub creator: H160,
     pub metadata: String,
+    /// fields of the metadata, when it could be parsed
+    #[serde(default)]
+    pub proposal: Option<ProposalMetadata>,
+    /// why the metadata could not be parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_error: Option<String>,
     pub title: String,
     pub description: String,
     pub voted_yes: U256,
@@ -126,9 +221,23 @@ pub struct Voting {
     pub votes_total: U256,
     pub executed: bool,
     pub details: Option<VotingDetails>,
+    /// calls of the agent in the transaction of the execution
+    #[serde(default)]
+    pub execution: Option<Execution>,
+    /// parameters of the voting app at the start of the voting
+    #[serde(default)]
+    pub params: Option<VoteParams>,
 }
 
 impl Voting {
+    // how the script differs from the function in the metadata
+    pub fn metadata_mismatch(&self) -> Option<String> {
+        match (&self.proposal, &self.details) {
+            (Some(p), Some(d)) => p.mismatch(&d.calls),
+            _ => None,
+        }
+    }
+
     pub fn as_u64(&self) -> u64 {
         let agent = if self.primary {
             VotingAgent::Primary
@@ -279,6 +388,15 @@ impl LabelBadge {
     }
 }
 
+// ERC20 token, as it was read from its contract
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub address: H160,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Treasury {
     pub name: String,
@@ -341,8 +459,23 @@ pub struct AppState {
     pub treasuries: BTreeMap<String, Treasury>,
     /// decimals for tokens
     pub decimals: BTreeMap<String, usize>,
+    /// tokens that were read from their contracts, by the address
+    #[serde(default)]
+    pub tokens: BTreeMap<H160, TokenInfo>,
+    /// tokens that were met in the events and are not read yet
+    #[serde(default)]
+    pub unknown_tokens: BTreeSet<H160>,
+    /// block explorer URL, if it is not the known one of the chain
+    #[serde(default)]
+    pub explorer: Option<String>,
     /// list of wallets that were in voting actions
     pub grants: BTreeMap<H160, u64>,
+    /// history of the parameters of the voting apps
+    #[serde(default = "get_initial_vote_params")]
+    pub vote_params: BTreeMap<VotingAgent, Vec<VoteParams>>,
+    /// agent calls of the transaction that is applied, they belong to its ExecuteVote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_execution: Option<(H256, Execution)>,
 }
 
 pub fn get_known_decimals() -> BTreeMap<String, usize> {
@@ -356,7 +489,7 @@ impl AppState {
     pub fn new(chain_id: u64) -> Self {
         let apr: f64 = 0.3875;
         Self {
-            version: "20210820".to_owned(),
+            version: "20261016.3".to_owned(),
             chain_id,
             epoch_index: 1,
             apr,
@@ -371,7 +504,64 @@ impl AppState {
             circulation: None,
             treasuries: BTreeMap::new(),
             decimals: get_known_decimals(),
+            tokens: BTreeMap::new(),
+            unknown_tokens: BTreeSet::new(),
+            explorer: None,
             grants: BTreeMap::new(),
+            vote_params: get_initial_vote_params(),
+            pending_execution: None,
+        }
+    }
+
+    // adds the token that was read. Its symbol is taken by the balances and decimals,
+    // so the symbol of another token gets the address
+    pub fn add_token(&mut self, mut t: TokenInfo) {
+        self.unknown_tokens.remove(&t.address);
+        let taken = self
+            .tokens
+            .values()
+            .any(|x| x.address != t.address && x.symbol == t.symbol);
+        if t.symbol.is_empty() {
+            t.symbol = format!("{}", t.address);
+        } else if taken {
+            t.symbol = format!("{} ({})", t.symbol, t.address);
+        }
+        self.decimals.insert(t.symbol.clone(), t.decimals);
+        self.tokens.insert(t.address, t);
+    }
+
+    // token symbols with their addresses
+    pub fn token_addresses(&self) -> BTreeMap<String, H160> {
+        self.tokens
+            .values()
+            .map(|t| (t.symbol.clone(), t.address))
+            .collect()
+    }
+
+    // tokens of the scripts, agent calls and vault events that are not read yet
+    pub fn tokens_to_read(&self) -> BTreeSet<H160> {
+        let mut res = self.unknown_tokens.clone();
+        for v in self.votings.values() {
+            if let Some(d) = &v.details {
+                res.extend(d.calls.iter().filter_map(script::token_of));
+            }
+            if let Some(x) = &v.execution {
+                res.extend(
+                    x.calls
+                        .iter()
+                        .filter_map(|c| script::token_of(&ScriptCall::new(c.target, &c.data.0))),
+                );
+            }
+        }
+        res.retain(|x| !self.tokens.contains_key(x));
+        res
+    }
+
+    // block explorer URL of the chain
+    pub fn explorer(&self) -> Option<String> {
+        match &self.explorer {
+            Some(x) => Some(x.trim_end_matches('/').to_owned()),
+            None => crate::chain::explorer(self.chain_id).map(|x| x.to_owned()),
         }
     }
 
@@ -819,6 +1009,53 @@ impl AppState {
         Ok(())
     }
 
+    // parameters of the voting app at the block
+    pub fn vote_params_at(&self, agent: &VotingAgent, block_number: u64) -> VoteParams {
+        self.vote_params
+            .get(agent)
+            .and_then(|list| list.iter().rev().find(|x| x.block_number <= block_number))
+            .cloned()
+            .unwrap_or_else(|| VoteParams::initial(agent))
+    }
+
+    // parameters the voting is judged by
+    pub fn params_of(&self, v: &Voting) -> VoteParams {
+        match &v.params {
+            Some(x) => x.clone(),
+            None => {
+                let agent = if v.primary {
+                    VotingAgent::Primary
+                } else {
+                    VotingAgent::Secondary
+                };
+                self.vote_params_at(&agent, v.block_number)
+            }
+        }
+    }
+
+    fn change_vote_params(
+        &mut self,
+        agent: &VotingAgent,
+        block_number: u64,
+        f: impl Fn(&mut VoteParams),
+    ) {
+        let mut params = self.vote_params_at(agent, block_number);
+        params.block_number = block_number;
+        f(&mut params);
+        self.vote_params
+            .entry(agent.clone())
+            .or_default()
+            .push(params);
+    }
+
+    // agent calls of the transaction, the calls of other transactions are dropped
+    fn pending_execution(&mut self, tx: H256) -> &mut Execution {
+        if self.pending_execution.as_ref().map(|x| x.0) != Some(tx) {
+            self.pending_execution = Some((tx, Execution::default()));
+        }
+        &mut self.pending_execution.as_mut().unwrap().1
+    }
+
     pub fn update(&mut self, e: OnChainEvent, log: web3::types::Log) -> () {
         log.block_number.map(|block_number| {
             self.last_block = block_number.as_u64();
@@ -1068,15 +1305,21 @@ impl AppState {
                     VotingAgent::Primary => true,
                     VotingAgent::Secondary => false,
                 };
-                let parts: Vec<&str> = metadata.split("|").collect();
-                let title = match parts.get(2) {
-                    Some(x) => x.to_string(),
-                    None => metadata.clone(),
-                };
-                let description = match parts.get(3) {
-                    Some(x) => x.to_string(),
-                    None => "".to_owned(),
-                };
+                // the title and description are shown as they are sanitized
+                let (proposal, metadata_error, title, description) =
+                    match ProposalMetadata::parse(metadata) {
+                        Ok(x) => {
+                            let title = sanitize_text(x.title.as_bytes());
+                            let description = sanitize_text(x.description.as_bytes());
+                            (Some(x), None, title, description)
+                        }
+                        Err(e) => (
+                            None,
+                            Some(e.to_string()),
+                            sanitize_text(metadata.as_bytes()),
+                            "".to_owned(),
+                        ),
+                    };
                 let no: BTreeMap<H160, U256> = BTreeMap::new();
                 let mut yes: BTreeMap<H160, U256> = BTreeMap::new();
                 yes.insert(creator.clone(), self.get_voting_power_of(&creator));
@@ -1088,6 +1331,8 @@ impl AppState {
                     vote_id: vote_id.as_u64(),
                     creator: creator.clone(),
                     metadata: metadata.clone(),
+                    proposal,
+                    metadata_error,
                     title,
                     description,
                     votes_total: self.get_votes_total(),
@@ -1097,6 +1342,8 @@ impl AppState {
                     no,
                     executed: false,
                     details: None,
+                    execution: None,
+                    params: Some(self.vote_params_at(agent, e.block_number)),
                 };
                 self.votings.insert(v.as_u64(), v);
                 if let Some(w) = self.wallets.get_mut(&creator) {
@@ -1133,10 +1380,68 @@ impl AppState {
             }
             Api3::ExecuteVote { agent, vote_id } => {
                 let key = crate::events::voting_to_u64(agent, vote_id.as_u64());
+                let execution = match self.pending_execution.take() {
+                    Some((tx, x)) if tx == e.tx => Some(x),
+                    _ => None,
+                };
                 if let Some(v) = self.votings.get_mut(&key) {
                     v.executed = true;
+                    v.execution = execution;
                 }
             }
+            Api3::ChangeSupportRequired {
+                agent,
+                support_required_pct,
+            } => {
+                let pct = *support_required_pct;
+                self.change_vote_params(agent, e.block_number, |x| x.support_required_pct = pct);
+            }
+            Api3::ChangeMinQuorum {
+                agent,
+                min_accept_quorum_pct,
+            } => {
+                let pct = *min_accept_quorum_pct;
+                self.change_vote_params(agent, e.block_number, |x| x.min_quorum_pct = pct);
+            }
+            Api3::Execute {
+                agent: _,
+                sender: _,
+                target,
+                eth_value,
+                data,
+            } => {
+                let call = AgentCall {
+                    agent: log.address,
+                    target: *target,
+                    eth_value: *eth_value,
+                    data: data.clone(),
+                };
+                self.pending_execution(e.tx).calls.push(call);
+            }
+            Api3::ScriptResult {
+                agent: _,
+                executor: _,
+                script: _,
+                input: _,
+                return_data,
+            } => {
+                self.pending_execution(e.tx).return_data = Some(return_data.clone());
+            }
+            Api3::VaultDeposit {
+                agent: _,
+                token,
+                sender: _,
+                amount: _,
+            }
+            | Api3::VaultTransfer {
+                agent: _,
+                token,
+                to: _,
+                amount: _,
+            } if !token.is_zero() && !self.tokens.contains_key(token) => {
+                // zero address is ETH, it is not a token
+                self.unknown_tokens.insert(*token);
+            }
             Api3::SetVestingAddresses { addresses } => {
                 // println!("{:?}", e.entry);
                 self.set_vesting_addresses(addresses);
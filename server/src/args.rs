@@ -1,4 +1,6 @@
-use clap::arg_enum;
+use clap::{arg_enum, AppSettings};
+use std::ffi::OsString;
+use std::path::Path;
 use structopt::StructOpt;
 
 arg_enum! {
@@ -6,6 +8,18 @@ arg_enum! {
     pub enum DumpMode {
         Events,
         Unknown,
+        Raw,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone)]
+    pub enum CacheMode {
+        Verify,
+        Compact,
+        Gaps,
+        Export,
+        Import,
     }
 }
 
@@ -21,12 +35,24 @@ pub struct Args {
     /// Cache folder to store responses from ETH to avoid scan
     #[structopt(long, default_value = "", env = "CACHE_DIR")]
     pub cache_dir: String,
-    /// Ethereum JSON+RPC HTTP address
+    /// Ethereum JSON+RPC endpoint: IPC file, WS(S) or HTTP(S) address, or JSONL recording of `--dump raw`
     #[structopt(long, default_value = "/root/.ethereum/geth.ipc", env = "RPC_ENDPOINT")]
     pub rpc_endpoint: String,
+    /// Seconds between polling for new blocks when watching HTTP(S) endpoint
+    #[structopt(long, default_value = "15", env = "RPC_POLL_INTERVAL")]
+    pub rpc_poll_interval: u64,
+    /// Seconds to wait before reconnecting to IPC or WS(S) endpoint when watching
+    #[structopt(long, default_value = "5", env = "RPC_RECONNECT_DELAY")]
+    pub rpc_reconnect_delay: u64,
     /// Ethereum JSON+RPC batch size for reading
     #[structopt(long, default_value = "500", env = "RPC_BATCH_SIZE")]
     pub rpc_batch_size: u64,
+    /// Number of block ranges that are read from Ethereum JSON+RPC at the same time
+    #[structopt(long, default_value = "4", env = "RPC_CONCURRENCY")]
+    pub rpc_concurrency: usize,
+    /// TOML file with the contracts, tokens and treasuries of every chain, instead of ADDR_* variables
+    #[structopt(long, env = "CONFIG")]
+    pub config: Option<String>,
     /// USDC token contract address
     #[structopt(long, default_value = "", env = "ADDR_USDC_TOKEN")]
     pub address_usdc_token: String,
@@ -64,15 +90,33 @@ pub struct Args {
     /// Max block to stop contract events listening
     #[structopt(long, env = "MAX_BLOCK")]
     pub max_block: Option<u64>,
+    /// Number of blocks between the state checkpoints in the cache folder, 0 to save them only on shutdown
+    #[structopt(long, default_value = "50000", env = "CHECKPOINT_BLOCKS")]
+    pub checkpoint_blocks: u64,
+    /// Number of events between the snapshots of the state for the historical queries, 0 to disable them
+    #[structopt(long, default_value = "5000", env = "HISTORY_EVENTS")]
+    pub history_events: usize,
+    /// Number of blocks on top of the event block before it is committed to the state
+    #[structopt(long, default_value = "0", env = "CONFIRMATIONS")]
+    pub confirmations: u64,
     /// Dump (show logs) mode instead of running HTTP server
     #[structopt(short, long, possible_values = &DumpMode::variants(), case_insensitive = true)]
     pub dump: Option<DumpMode>,
+    /// Check, compact, show the gaps, export or import the cache instead of running HTTP server
+    #[structopt(long, possible_values = &CacheMode::variants(), case_insensitive = true)]
+    pub cache: Option<CacheMode>,
+    /// Cache bundle file to export the cache to or to import it from
+    #[structopt(long, env = "CACHE_BUNDLE")]
+    pub cache_bundle: Option<String>,
     /// Continue listening to blockchain events
     #[structopt(short, long)]
     pub watch: bool,
     /// Disable ENS reserve resolution for the wallets
     #[structopt(long)]
     pub no_ens: bool,
+    /// Instances to track in the same process as NAME=ENV_FILE, served under /NAME/
+    #[structopt(long = "instance", env = "INSTANCES", use_delimiter = true)]
+    pub instances: Vec<String>,
 }
 
 pub fn parse() -> anyhow::Result<Args> {
@@ -85,3 +129,189 @@ pub fn parse() -> anyhow::Result<Args> {
     tracing::debug!("{:?}", res);
     Ok(res)
 }
+
+// name and env file of the instance from NAME=ENV_FILE
+pub fn parse_instance(spec: &str) -> anyhow::Result<(String, String)> {
+    let (name, env_file) = match spec.split_once('=') {
+        Some(x) => x,
+        None => {
+            return Err(anyhow::Error::msg(format!(
+                "instance {} should be NAME=ENV_FILE",
+                spec
+            )))
+        }
+    };
+    let reserved = ["api", "ws", "_liveness"];
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || !valid || reserved.contains(&name) {
+        return Err(anyhow::Error::msg(format!(
+            "invalid instance name {:?}",
+            name
+        )));
+    }
+    Ok((name.to_owned(), env_file.to_owned()))
+}
+
+// variables of the env file as KEY=VALUE lines, with optional quotes around the value
+pub fn read_env_file(path: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut res = vec![];
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        match line.split_once('=') {
+            Some((k, v)) => {
+                let v = v.trim();
+                let unquoted = v
+                    .strip_prefix('"')
+                    .and_then(|x| x.strip_suffix('"'))
+                    .or_else(|| v.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')));
+                res.push((k.trim().to_owned(), unquoted.unwrap_or(v).to_owned()));
+            }
+            None => return Err(anyhow::Error::msg(format!("invalid line {}", line))),
+        }
+    }
+    Ok(res)
+}
+
+// variables of the settings with their options, the env file of the instance sets them
+const VARS: &[(&str, &str)] = &[
+    ("LISTEN", "listen"),
+    ("STATIC_DIR", "static-dir"),
+    ("CACHE_DIR", "cache-dir"),
+    ("RPC_ENDPOINT", "rpc-endpoint"),
+    ("RPC_POLL_INTERVAL", "rpc-poll-interval"),
+    ("RPC_RECONNECT_DELAY", "rpc-reconnect-delay"),
+    ("RPC_BATCH_SIZE", "rpc-batch-size"),
+    ("RPC_CONCURRENCY", "rpc-concurrency"),
+    ("CONFIG", "config"),
+    ("ADDR_USDC_TOKEN", "address-usdc-token"),
+    ("ADDR_API3_TOKEN", "address-api3-token"),
+    ("ADDR_API3_POOL", "address-api3-pool"),
+    ("ADDR_API3_CIRCULATION", "address-api3-circulation"),
+    ("ADDR_API3_CIRCULATION", "address-circulation"),
+    ("ADDR_API3_CONVENIENCE", "address-convenience"),
+    ("ADDR_API3_VOTING_PRIMARY", "address-voting1"),
+    ("ADDR_API3_AGENT_PRIMARY", "address-agent1"),
+    ("ADDR_API3_VOTING_SECONDARY", "address-voting2"),
+    ("ADDR_API3_AGENT_SECONDARY", "address-agent2"),
+    ("GENESIS_BLOCK", "genesis-block"),
+    ("MAX_BLOCK", "max-block"),
+    ("CHECKPOINT_BLOCKS", "checkpoint-blocks"),
+    ("HISTORY_EVENTS", "history-events"),
+    ("CONFIRMATIONS", "confirmations"),
+    ("CACHE_BUNDLE", "cache-bundle"),
+];
+
+// settings with the variables on top of the environment of the process. The variables
+// are given as the options before the command line, so the command line overrides them.
+// The environment of the process is not changed
+pub fn with_vars(vars: &[(String, String)], args: Vec<OsString>) -> anyhow::Result<Args> {
+    let mut args = args.into_iter();
+    let mut argv: Vec<OsString> = args.next().into_iter().collect();
+    for (k, v) in vars {
+        for (_, long) in VARS.iter().filter(|(name, _)| name == k) {
+            argv.push(format!("--{}={}", long, v).into());
+        }
+    }
+    argv.extend(args);
+    let matches = Args::clap()
+        .setting(AppSettings::AllArgsOverrideSelf)
+        .get_matches_from_safe(argv)?;
+    Ok(Args::from_clap(&matches))
+}
+
+// settings of every instance: the settings of the process with the variables of the instance
+// env file on top of them, and the cache in the subfolder named after the instance.
+// Without instances, there is only one and it has no name
+pub fn instances(args: &Args) -> anyhow::Result<Vec<(String, Args)>> {
+    if args.instances.is_empty() {
+        return Ok(vec![(String::new(), args.clone())]);
+    }
+    let mut res: Vec<(String, Args)> = vec![];
+    for spec in &args.instances {
+        let (name, env_file) = parse_instance(spec)?;
+        if res.iter().any(|(x, _)| *x == name) {
+            return Err(anyhow::Error::msg(format!("duplicate instance {}", name)));
+        }
+        let vars = read_env_file(&env_file)
+            .map_err(|e| anyhow::Error::msg(format!("{}: {}", env_file, e)))?;
+        let mut instance = with_vars(&vars, std::env::args_os().collect())?;
+        instance.instances = vec![];
+        if !instance.cache_dir.is_empty() {
+            let cache_dir = Path::new(&instance.cache_dir).join(&name);
+            std::fs::create_dir_all(&cache_dir)?;
+            instance.cache_dir = cache_dir.to_string_lossy().to_string();
+        }
+        tracing::debug!("instance {}: {:?}", name, instance);
+        res.push((name, instance));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_instances() {
+        let (name, env_file) = parse_instance("rinkeby=.env.rinkeby").unwrap();
+        assert_eq!(name, "rinkeby");
+        assert_eq!(env_file, ".env.rinkeby");
+        assert!(parse_instance("mainnet").is_err());
+        assert!(parse_instance("=.env").is_err());
+        assert!(parse_instance("api=.env").is_err());
+        assert!(parse_instance("main/net=.env").is_err());
+
+        let dir = crate::testrpc::TempDir::new("env");
+        let path = dir.join(".env");
+        std::fs::write(
+            &path,
+            "# rinkeby\nGENESIS_BLOCK=8842400\n\nRPC_ENDPOINT=\"/geth.ipc\"\n",
+        )
+        .unwrap();
+        let vars = read_env_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("GENESIS_BLOCK".to_owned(), "8842400".to_owned()),
+                ("RPC_ENDPOINT".to_owned(), "/geth.ipc".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_sets_instance_variables_under_the_command_line() {
+        let vars = vec![
+            ("GENESIS_BLOCK".to_owned(), "5".to_owned()),
+            ("ADDR_API3_CIRCULATION".to_owned(), "0xa3".to_owned()),
+            ("CACHE_BUNDLE".to_owned(), "-bundle".to_owned()),
+            ("LOG_LEVEL".to_owned(), "debug".to_owned()),
+        ];
+        let argv = ["api3tracker", "--genesis-block", "7", "-w"];
+        let args = with_vars(&vars, argv.iter().map(OsString::from).collect()).unwrap();
+        assert_eq!(args.genesis_block, 7);
+        assert!(args.watch);
+        assert_eq!(args.address_circulation.as_deref(), Some("0xa3"));
+        assert_eq!(args.address_api3_circulation.as_deref(), Some("0xa3"));
+        assert_eq!(args.cache_bundle.as_deref(), Some("-bundle"));
+        assert_ne!(std::env::var("GENESIS_BLOCK").ok().as_deref(), Some("5"));
+
+        // every variable of the settings could be set by the instance
+        let mut help = vec![];
+        Args::clap().write_long_help(&mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        for chunk in help.split("[env: ").skip(1) {
+            let name = chunk.split(|c| c == '=' || c == ']').next().unwrap();
+            let known = name == "INSTANCES" || VARS.iter().any(|(k, _)| *k == name);
+            assert!(known, "{} is not in VARS", name);
+        }
+        for (_, long) in VARS {
+            assert!(help.contains(&format!("--{} ", long)), "--{}", long);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+// Cache bundle: the cache of the chain and ENS names packed in one file,
+// so a new instance could start without reading the history from the node.
+//
+// The file starts with MAGIC, then goes `[manifest length: u32][manifest JSON]`,
+// followed by the files as `[name length: u16][name][size: u64][content]`, little-endian.
+use crate::store::{contracts_checksum, Store};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use web3::types::H160;
+
+pub const MAGIC: &[u8; 8] = b"A3TBNDL1";
+pub const VERSION: u32 = 1;
+// the manifest lists the ranges and the files, it is never this large
+pub const MAX_MANIFEST: u32 = 16 << 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BundleFile {
+    pub name: String,
+    pub size: u64,
+    pub crc32: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub chain_id: u64,
+    /// contracts which logs are cached, in the order of the cache checksum
+    pub addresses: Vec<H160>,
+    /// first and last cached blocks
+    pub from_block: u64,
+    pub to_block: u64,
+    /// ranges of the cached blocks
+    pub ranges: Vec<(u64, u64)>,
+    pub files: Vec<BundleFile>,
+}
+
+impl Manifest {
+    // checks that the bundle was made for the same chain and contracts
+    pub fn check(
+        &self,
+        chain_id: u64,
+        addresses: &[H160],
+        genesis_block: u64,
+    ) -> anyhow::Result<()> {
+        if self.version != VERSION {
+            return Err(anyhow::Error::msg(format!(
+                "bundle version {} is not supported",
+                self.version
+            )));
+        }
+        if self.chain_id != chain_id {
+            return Err(anyhow::Error::msg(format!(
+                "bundle is made for chain {}, not {}",
+                self.chain_id, chain_id
+            )));
+        }
+        if contracts_checksum(&self.addresses) != contracts_checksum(addresses) {
+            return Err(anyhow::Error::msg(format!(
+                "bundle is made for contracts {:?}",
+                self.addresses
+            )));
+        }
+        if self.from_block > genesis_block {
+            tracing::warn!(
+                "bundle starts at block {}, blocks from {} will be scanned",
+                self.from_block,
+                genesis_block
+            );
+        }
+        Ok(())
+    }
+}
+
+// ENS reverse resolution results that are cached in the same folder
+fn is_ens_file(name: &str) -> bool {
+    name.ends_with(".addr.reverse.txt") && !name.contains(['/', '\\'])
+}
+
+// packs the cache of the chain and ENS names into the bundle file
+pub fn export(
+    cache_dir: &str,
+    chain_id: u64,
+    addresses: &[H160],
+    bundle: &Path,
+) -> anyhow::Result<Manifest> {
+    let store_path = Store::file_name(cache_dir, chain_id, addresses);
+    if !store_path.exists() {
+        return Err(anyhow::Error::msg(format!(
+            "{} not found",
+            store_path.display()
+        )));
+    }
+    // the file is not changed, only the valid part before the broken tail is packed
+    let report = Store::verify(&store_path)?;
+    if let Some((0, reason)) = &report.broken {
+        return Err(anyhow::Error::msg(format!(
+            "{}: {}",
+            store_path.display(),
+            reason
+        )));
+    }
+    let ranges: Vec<(u64, u64)> = report.ranges.iter().map(|(&a, &b)| (a, b)).collect();
+
+    let mut names = vec![store_path
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string()];
+    let mut ens: Vec<String> = std::fs::read_dir(cache_dir)?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| is_ens_file(name))
+        .collect();
+    ens.sort();
+    names.extend(ens);
+
+    let mut contents = vec![];
+    let mut files = vec![];
+    for (i, name) in names.into_iter().enumerate() {
+        let mut data = std::fs::read(Path::new(cache_dir).join(&name))?;
+        if let (0, Some((pos, _))) = (i, &report.broken) {
+            data.truncate(*pos as usize);
+        }
+        files.push(BundleFile {
+            name,
+            size: data.len() as u64,
+            crc32: crc32fast::hash(&data),
+        });
+        contents.push(data);
+    }
+    let manifest = Manifest {
+        version: VERSION,
+        chain_id,
+        addresses: addresses.to_vec(),
+        from_block: ranges.first().map_or(0, |r| r.0),
+        to_block: ranges.last().map_or(0, |r| r.1),
+        ranges,
+        files,
+    };
+
+    let tmp = bundle.with_extension("tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    w.write_all(MAGIC)?;
+    let json = serde_json::to_vec(&manifest)?;
+    w.write_all(&(json.len() as u32).to_le_bytes())?;
+    w.write_all(&json)?;
+    for (f, data) in manifest.files.iter().zip(contents) {
+        w.write_all(&(f.name.len() as u16).to_le_bytes())?;
+        w.write_all(f.name.as_bytes())?;
+        w.write_all(&f.size.to_le_bytes())?;
+        w.write_all(&data)?;
+    }
+    w.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp, bundle)?;
+    Ok(manifest)
+}
+
+fn read_manifest(r: &mut impl Read) -> anyhow::Result<Manifest> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(anyhow::Error::msg("not a cache bundle"));
+    }
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MANIFEST {
+        return Err(anyhow::Error::msg("manifest of the bundle is damaged"));
+    }
+    let mut json = vec![0u8; len as usize];
+    r.read_exact(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+// reads the manifest of the bundle file
+pub fn manifest(bundle: &Path) -> anyhow::Result<Manifest> {
+    read_manifest(&mut BufReader::new(File::open(bundle)?))
+}
+
+// unpacks the bundle into the cache folder, after checking it was made for the same setup.
+// Existing cache of the chain is not overwritten
+pub fn import(
+    cache_dir: &str,
+    chain_id: u64,
+    addresses: &[H160],
+    genesis_block: u64,
+    bundle: &Path,
+) -> anyhow::Result<Manifest> {
+    let mut r = BufReader::new(File::open(bundle)?);
+    let manifest = read_manifest(&mut r)?;
+    manifest.check(chain_id, addresses, genesis_block)?;
+    let store_path = Store::file_name(cache_dir, chain_id, addresses);
+    if store_path.exists() {
+        return Err(anyhow::Error::msg(format!(
+            "{} already exists",
+            store_path.display()
+        )));
+    }
+    // everything is read and checked before the first file is written.
+    // Headers of the files must match the manifest before their contents are read
+    let mut contents = vec![];
+    for f in &manifest.files {
+        let damaged = || anyhow::Error::msg(format!("{} is damaged in the bundle", f.name));
+        let mut len = [0u8; 2];
+        r.read_exact(&mut len)?;
+        if u16::from_le_bytes(len) as usize != f.name.len() {
+            return Err(damaged());
+        }
+        let mut name = vec![0u8; f.name.len()];
+        r.read_exact(&mut name)?;
+        let mut size = [0u8; 8];
+        r.read_exact(&mut size)?;
+        if name != f.name.as_bytes() || u64::from_le_bytes(size) != f.size {
+            return Err(damaged());
+        }
+        let mut data = vec![];
+        r.by_ref().take(f.size).read_to_end(&mut data)?;
+        if data.len() as u64 != f.size || crc32fast::hash(&data) != f.crc32 {
+            return Err(damaged());
+        }
+        let name = f.name.clone();
+        // only the names that the bundle could contain, without any path
+        let expected = store_path.file_name().unwrap().to_string_lossy();
+        if name != expected && !is_ens_file(&name) {
+            return Err(anyhow::Error::msg(format!(
+                "unexpected file {} in the bundle",
+                name
+            )));
+        }
+        contents.push((name, data));
+    }
+    std::fs::create_dir_all(cache_dir)?;
+    for (name, data) in contents {
+        let path = Path::new(cache_dir).join(&name);
+        if is_ens_file(&name) && path.exists() {
+            continue;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &path)?;
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::types::H256;
+
+    #[test]
+    fn it_exports_and_imports() {
+        let root = crate::testrpc::TempDir::new("bundle");
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        std::fs::create_dir_all(&src).unwrap();
+        let (src_dir, dst_dir) = (src.to_str().unwrap(), dst.to_str().unwrap());
+        let addresses = vec![H160::from_low_u64_be(1), H160::from_low_u64_be(2)];
+
+        let mut store = Store::open_dir(src_dir, 5, &addresses).unwrap();
+        store.append_logs(100, 199, &[]).unwrap();
+        store.append_logs(300, 399, &[]).unwrap();
+        let times = vec![(H256::from_low_u64_be(1), 1_600_000_000)]
+            .into_iter()
+            .collect();
+        store.append_blocks_time(&times).unwrap();
+        drop(store);
+        // the broken tail is left out of the bundle, but kept in the file
+        let store_path = Store::file_name(src_dir, 5, &addresses);
+        let mut data = std::fs::read(&store_path).unwrap();
+        data.extend([1, 0, 0, 0, 0, 0, 0, 0, 1]);
+        std::fs::write(&store_path, &data).unwrap();
+        std::fs::write(src.join("abc.addr.reverse.txt"), "abc.eth").unwrap();
+        std::fs::write(src.join("unrelated.txt"), "-").unwrap();
+
+        let bundle = root.join("bundle.bin");
+        let manifest = export(src_dir, 5, &addresses, &bundle).unwrap();
+        assert_eq!((manifest.from_block, manifest.to_block), (100, 399));
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(super::manifest(&bundle).unwrap(), manifest);
+        assert_eq!(manifest.files[0].size + 9, data.len() as u64);
+        assert_eq!(std::fs::read(&store_path).unwrap(), data);
+
+        // damaged lengths are found before anything is allocated for them
+        let packed = std::fs::read(&bundle).unwrap();
+        let json_len = u32::from_le_bytes([packed[8], packed[9], packed[10], packed[11]]) as usize;
+        let mut damaged = packed.clone();
+        damaged[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&bundle, &damaged).unwrap();
+        assert!(super::manifest(&bundle).is_err());
+        let mut damaged = packed.clone();
+        let at = 12 + json_len + 2 + manifest.files[0].name.len();
+        damaged[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&bundle, &damaged).unwrap();
+        assert_eq!(
+            import(dst_dir, 5, &addresses, 100, &bundle)
+                .unwrap_err()
+                .to_string(),
+            format!("{} is damaged in the bundle", manifest.files[0].name)
+        );
+        std::fs::write(&bundle, &packed).unwrap();
+
+        let other = vec![H160::from_low_u64_be(1)];
+        assert!(import(dst_dir, 5, &other, 100, &bundle).is_err());
+        assert!(import(dst_dir, 4, &addresses, 100, &bundle).is_err());
+        import(dst_dir, 5, &addresses, 100, &bundle).unwrap();
+        // the cache is not overwritten
+        assert!(import(dst_dir, 5, &addresses, 100, &bundle).is_err());
+
+        let store = Store::open_dir(dst_dir, 5, &addresses).unwrap();
+        let ens = std::fs::read_to_string(dst.join("abc.addr.reverse.txt")).unwrap();
+        let unrelated = dst.join("unrelated.txt").exists();
+        assert_eq!(store.gaps(100, 399), vec![(200, 299)]);
+        assert_eq!(store.blocks_time, times);
+        assert_eq!(ens, "abc.eth");
+        assert!(!unrelated);
+    }
+}
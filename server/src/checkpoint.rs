@@ -0,0 +1,194 @@
+// Checkpoints of the application state, so the restart scans only the blocks after
+// the newest checkpoint instead of the whole history.
+// Checkpoints are JSON files in the cache folder, named by the chain, contracts and block
+use crate::reader::DaoApps;
+use crate::store::contracts_checksum;
+use client::state::{AppState, OnChainEvent};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use web3::types::H160;
+
+// format of the checkpoint file, changed when the old files can't be used
+pub const VERSION: u32 = 2;
+// how many checkpoints are kept
+pub const KEEP: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    /// all events up to this block are applied to the state
+    pub block_number: u64,
+    pub app: AppState,
+    /// voting apps that were set up to the block, they are not searched again
+    pub apps: Vec<DaoApps>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+    dir: String,
+    prefix: String,
+    /// number of blocks between checkpoints, 0 means only on shutdown
+    pub every: u64,
+    /// block of the last saved or loaded checkpoint
+    pub last_block: u64,
+    /// voting apps that were set by the applied events
+    pub apps: Vec<DaoApps>,
+}
+
+impl Checkpoints {
+    pub fn new(cache_dir: &str, chain_id: u64, addresses: &[H160], every: u64) -> Self {
+        Self {
+            dir: cache_dir.to_owned(),
+            prefix: format!("state{}-{}-", chain_id, contracts_checksum(addresses)),
+            every,
+            last_block: 0,
+            apps: vec![],
+        }
+    }
+
+    fn file_name(&self, block_number: u64) -> PathBuf {
+        Path::new(&self.dir).join(format!("{}{}.json", self.prefix, block_number))
+    }
+
+    // saved checkpoints, the newest first
+    fn list(&self) -> Vec<(u64, PathBuf)> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(x) if !self.dir.is_empty() => x,
+            _ => return vec![],
+        };
+        let mut res: Vec<(u64, PathBuf)> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let block = name.strip_prefix(&self.prefix)?.strip_suffix(".json")?;
+                Some((block.parse().ok()?, entry.path()))
+            })
+            .collect();
+        res.sort_by_key(|x| std::cmp::Reverse(x.0));
+        res
+    }
+
+    fn read(path: &Path, chain_id: u64) -> anyhow::Result<Checkpoint> {
+        let c: Checkpoint = serde_json::from_slice(&std::fs::read(path)?)?;
+        let current = AppState::new(chain_id);
+        if c.version != VERSION || c.app.version != current.version {
+            return Err(anyhow::Error::msg(format!(
+                "incompatible version {}/{}",
+                c.version, c.app.version
+            )));
+        }
+        if c.app.chain_id != chain_id {
+            return Err(anyhow::Error::msg(format!("chain {}", c.app.chain_id)));
+        }
+        Ok(c)
+    }
+
+    // the newest valid checkpoint. Checkpoints that can't be used are removed
+    pub fn load(&mut self, chain_id: u64) -> Option<Checkpoint> {
+        for (_, path) in self.list() {
+            match Self::read(&path, chain_id) {
+                Ok(c) => {
+                    tracing::info!("resuming from {}", path.display());
+                    self.last_block = c.block_number;
+                    self.apps = c.apps.clone();
+                    return Some(c);
+                }
+                Err(e) => {
+                    tracing::warn!("checkpoint {} is dropped: {}", path.display(), e);
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        None
+    }
+
+    // the newest valid checkpoint, nothing is removed
+    pub fn newest(&self, chain_id: u64) -> Option<Checkpoint> {
+        self.list()
+            .into_iter()
+            .find_map(|(_, path)| Self::read(&path, chain_id).ok())
+    }
+
+    // keeps the apps of SetDaoApps event for the next checkpoints
+    pub fn on_event(&mut self, e: &OnChainEvent) {
+        if let Some(apps) = DaoApps::of(e.block_number, &e.entry) {
+            self.apps.retain(|a| a.block_number < apps.block_number);
+            self.apps.push(apps);
+        }
+    }
+
+    pub fn save(&mut self, app: &AppState, block_number: u64) -> anyhow::Result<()> {
+        if self.dir.is_empty() {
+            return Ok(());
+        }
+        let path = self.file_name(block_number);
+        let tmp = path.with_extension("tmp");
+        let c = Checkpoint {
+            version: VERSION,
+            block_number,
+            app: app.clone(),
+            apps: self
+                .apps
+                .iter()
+                .filter(|a| a.block_number <= block_number)
+                .cloned()
+                .collect(),
+        };
+        std::fs::write(&tmp, serde_json::to_vec(&c)?)?;
+        std::fs::rename(&tmp, &path)?;
+        self.last_block = block_number;
+        tracing::info!("checkpoint {} saved", path.display());
+        for (_, old) in self.list().into_iter().skip(KEEP) {
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+
+    // saves the checkpoint when enough blocks passed since the last one
+    pub fn on_block(&mut self, app: &AppState, block_number: u64) {
+        if self.every == 0 || block_number < self.last_block + self.every {
+            return;
+        }
+        if let Err(e) = self.save(app, block_number) {
+            tracing::warn!("checkpoint failure at {}: {}", block_number, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_resumes_from_the_newest_valid_checkpoint() {
+        let dir = crate::testrpc::TempDir::new("state");
+        let cache_dir = dir.to_str().unwrap();
+        let addresses = vec![H160::from_low_u64_be(1)];
+        let mut checkpoints = Checkpoints::new(cache_dir, 1, &addresses, 100);
+
+        let mut app = AppState::new(1);
+        for block in [50, 100, 150, 220, 250, 330, 400, 440] {
+            app.last_block = block;
+            checkpoints.on_block(&app, block);
+        }
+        let mut blocks: Vec<u64> = checkpoints.list().into_iter().map(|x| x.0).collect();
+        assert_eq!(blocks, vec![440, 330, 220]);
+
+        // the newest one is from another version, the next one is broken
+        let path = checkpoints.file_name(440);
+        let data = std::fs::read_to_string(&path).unwrap();
+        let version = format!("\"version\":{}", VERSION);
+        std::fs::write(&path, data.replace(&version, "\"version\":0")).unwrap();
+        std::fs::write(checkpoints.file_name(330), "{").unwrap();
+        let other = Checkpoints::new(cache_dir, 1, &[], 100);
+        let mut reloaded = Checkpoints::new(cache_dir, 1, &addresses, 100);
+        let c = reloaded.load(1).unwrap();
+        blocks = reloaded.list().into_iter().map(|x| x.0).collect();
+        let unrelated = other.list().len();
+        assert_eq!(c.block_number, 220);
+        assert_eq!(c.app.last_block, 220);
+        assert_eq!(reloaded.last_block, 220);
+        assert_eq!(blocks, vec![220]);
+        assert_eq!(unrelated, 0);
+    }
+}
@@ -0,0 +1,519 @@
+// Settings of the DAO per chain: contracts, tokens, treasuries, genesis block and block explorer.
+// They are read from TOML file, or made of ADDR_* variables when there is no file,
+// and checked at the start, before anything is read from the chain
+use crate::args::Args;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use web3::types::H160;
+
+// address as hex string, with or without 0x prefix
+fn address<'de, D: Deserializer<'de>>(d: D) -> Result<H160, D::Error> {
+    let s = String::deserialize(d)?;
+    H160::from_str(&s).map_err(|_| serde::de::Error::custom(format!("invalid address {:?}", s)))
+}
+
+fn optional_address<'de, D: Deserializer<'de>>(d: D) -> Result<Option<H160>, D::Error> {
+    address(d).map(Some)
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Contracts {
+    #[serde(deserialize_with = "address")]
+    pub pool: H160,
+    #[serde(deserialize_with = "address")]
+    pub token: H160,
+    #[serde(deserialize_with = "address")]
+    pub convenience: H160,
+    /// voting and agent apps, learned from SetDaoApps events of the pool when they are not set
+    #[serde(default, deserialize_with = "optional_address")]
+    pub voting_primary: Option<H160>,
+    #[serde(default, deserialize_with = "optional_address")]
+    pub agent_primary: Option<H160>,
+    #[serde(default, deserialize_with = "optional_address")]
+    pub voting_secondary: Option<H160>,
+    #[serde(default, deserialize_with = "optional_address")]
+    pub agent_secondary: Option<H160>,
+    /// contract with the circulating supply, which logs are also read
+    #[serde(default, deserialize_with = "optional_address")]
+    pub circulation: Option<H160>,
+}
+
+impl Contracts {
+    // contracts which logs are read, besides the voting apps
+    pub fn addresses(&self) -> Vec<H160> {
+        let mut addresses = vec![self.pool, self.convenience];
+        if let Some(circulation) = self.circulation {
+            addresses.push(circulation);
+        }
+        addresses
+    }
+
+    // primary and secondary voting and agent apps, if they are set
+    pub fn apps(&self) -> Option<(Vec<H160>, Vec<H160>)> {
+        Some((
+            vec![self.voting_primary?, self.agent_primary?],
+            vec![self.voting_secondary?, self.agent_secondary?],
+        ))
+    }
+
+    fn named(&self) -> Vec<(&'static str, Option<H160>)> {
+        vec![
+            ("pool", Some(self.pool)),
+            ("token", Some(self.token)),
+            ("convenience", Some(self.convenience)),
+            ("voting_primary", self.voting_primary),
+            ("agent_primary", self.agent_primary),
+            ("voting_secondary", self.voting_secondary),
+            ("agent_secondary", self.agent_secondary),
+            ("circulation", self.circulation),
+        ]
+    }
+}
+
+// contract that is read only in the range of blocks, like the pool before its upgrade.
+// Its events are tagged with the version
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ContractVersion {
+    pub version: String,
+    #[serde(deserialize_with = "address")]
+    pub address: H160,
+    /// first and last blocks of the range, both included
+    #[serde(default)]
+    pub from_block: Option<u64>,
+    #[serde(default)]
+    pub to_block: Option<u64>,
+}
+
+impl ContractVersion {
+    pub fn contains(&self, block_number: u64) -> bool {
+        self.overlaps(block_number, block_number)
+    }
+
+    pub fn overlaps(&self, from: u64, to: u64) -> bool {
+        self.from_block.map_or(true, |x| x <= to) && self.to_block.map_or(true, |x| x >= from)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    pub symbol: String,
+    #[serde(deserialize_with = "address")]
+    pub address: H160,
+    pub decimals: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TreasuryWallet {
+    pub name: String,
+    #[serde(deserialize_with = "address")]
+    pub address: H160,
+}
+
+// treasuries of the primary and secondary agents
+pub fn treasury_wallets(primary: &[H160], secondary: &[H160]) -> Vec<TreasuryWallet> {
+    let mut res = vec![];
+    if let Some(&address) = primary.get(1) {
+        res.push(TreasuryWallet {
+            name: "Primary Treasury".to_owned(),
+            address,
+        });
+    }
+    if let Some(&address) = secondary.get(1) {
+        res.push(TreasuryWallet {
+            name: "Secondary Treasury".to_owned(),
+            address,
+        });
+    }
+    res
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChainConfig {
+    /// number of the first block with the DAO events
+    pub genesis_block: u64,
+    /// block explorer URL, the known one of the chain is used without it
+    #[serde(default)]
+    pub explorer: Option<String>,
+    pub contracts: Contracts,
+    /// tokens which balances are shown for the treasuries
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+    #[serde(default)]
+    pub treasuries: Vec<TreasuryWallet>,
+    /// block ranges of the contracts, the contracts without them are read in all blocks
+    #[serde(default)]
+    pub versions: Vec<ContractVersion>,
+}
+
+impl ChainConfig {
+    // settings from ADDR_* variables, as they were before the config file.
+    // All missing variables are reported at once
+    pub fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let mut errors: Vec<String> = vec![];
+        let mut required = |name: &str, value: &str| -> H160 {
+            if value.is_empty() {
+                errors.push(format!("{} is not set", name));
+            } else if let Ok(x) = H160::from_str(value) {
+                return x;
+            } else {
+                errors.push(format!("{} is not an address: {:?}", name, value));
+            }
+            H160::zero()
+        };
+        let apps = [
+            ("ADDR_API3_VOTING_PRIMARY", &args.address_voting1),
+            ("ADDR_API3_AGENT_PRIMARY", &args.address_agent1),
+            ("ADDR_API3_VOTING_SECONDARY", &args.address_voting2),
+            ("ADDR_API3_AGENT_SECONDARY", &args.address_agent2),
+        ];
+        // the apps are discovered when none of them is set
+        let discovered = apps.iter().all(|(_, value)| value.is_empty());
+        let app: Vec<Option<H160>> = apps
+            .iter()
+            .map(|(name, value)| {
+                if discovered {
+                    None
+                } else {
+                    Some(required(name, value))
+                }
+            })
+            .collect();
+        let contracts = Contracts {
+            pool: required("ADDR_API3_POOL", &args.address_api3_pool),
+            token: required("ADDR_API3_TOKEN", &args.address_api3_token),
+            convenience: required("ADDR_API3_CONVENIENCE", &args.address_convenience),
+            voting_primary: app[0],
+            agent_primary: app[1],
+            voting_secondary: app[2],
+            agent_secondary: app[3],
+            circulation: args
+                .address_api3_circulation
+                .as_ref()
+                .or(args.address_circulation.as_ref())
+                .map(|x| required("ADDR_API3_CIRCULATION", x)),
+        };
+        let usdc = if args.address_usdc_token.is_empty() {
+            None
+        } else {
+            Some(required("ADDR_USDC_TOKEN", &args.address_usdc_token))
+        };
+        if !errors.is_empty() {
+            return Err(anyhow::Error::msg(errors.join(", ")));
+        }
+        let mut tokens = vec![Token {
+            symbol: "API3".to_owned(),
+            address: contracts.token,
+            decimals: 18,
+        }];
+        if let Some(address) = usdc {
+            tokens.push(Token {
+                symbol: "USDC".to_owned(),
+                address,
+                decimals: 6,
+            });
+        }
+        // without the agents, the treasuries are the discovered ones
+        let treasuries = match contracts.apps() {
+            Some((primary, secondary)) => treasury_wallets(&primary, &secondary),
+            None => vec![],
+        };
+        Ok(Self {
+            genesis_block: args.genesis_block,
+            explorer: None,
+            contracts,
+            tokens,
+            treasuries,
+            versions: vec![],
+        })
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        for (name, address) in self.contracts.named() {
+            if address.map_or(false, |x| x.is_zero()) {
+                return Err(anyhow::Error::msg(format!("contracts.{} is zero", name)));
+            }
+        }
+        let c = &self.contracts;
+        let apps = [
+            c.voting_primary,
+            c.agent_primary,
+            c.voting_secondary,
+            c.agent_secondary,
+        ];
+        let set = apps.iter().filter(|x| x.is_some()).count();
+        if set != 0 && set != apps.len() {
+            return Err(anyhow::Error::msg(
+                "contracts should have all voting and agent apps, or none of them to discover them",
+            ));
+        }
+        let mut symbols = BTreeSet::new();
+        for t in &self.tokens {
+            if t.symbol.is_empty() || !symbols.insert(t.symbol.as_str()) {
+                return Err(anyhow::Error::msg(format!(
+                    "token symbol {:?} is empty or repeated",
+                    t.symbol
+                )));
+            }
+            if t.decimals > 36 {
+                return Err(anyhow::Error::msg(format!(
+                    "token {} has {} decimals",
+                    t.symbol, t.decimals
+                )));
+            }
+        }
+        let mut names = BTreeSet::new();
+        for t in &self.treasuries {
+            if t.name.is_empty() || !names.insert(t.name.as_str()) {
+                return Err(anyhow::Error::msg(format!(
+                    "treasury name {:?} is empty or repeated",
+                    t.name
+                )));
+            }
+        }
+        for v in &self.versions {
+            if v.version.is_empty() || v.address.is_zero() {
+                return Err(anyhow::Error::msg(format!(
+                    "contract version {:?} of {:?} has no name or address",
+                    v.version, v.address
+                )));
+            }
+            if let (Some(from), Some(to)) = (v.from_block, v.to_block) {
+                if from > to {
+                    return Err(anyhow::Error::msg(format!(
+                        "contract version {} ends at block {} before it starts at {}",
+                        v.version, to, from
+                    )));
+                }
+            }
+        }
+        if let Some(explorer) = &self.explorer {
+            if !explorer.starts_with("https://") && !explorer.starts_with("http://") {
+                return Err(anyhow::Error::msg(format!(
+                    "explorer {:?} is not HTTP(S) URL",
+                    explorer
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // contracts which logs are read besides the voting apps, with the ones of the versions
+    pub fn addresses(&self) -> Vec<H160> {
+        let mut addresses = self.contracts.addresses();
+        for v in &self.versions {
+            if !addresses.contains(&v.address) {
+                addresses.push(v.address);
+            }
+        }
+        addresses
+    }
+
+    pub fn watched(&self) -> Vec<H160> {
+        let (primary, secondary) = self.contracts.apps().unwrap_or_default();
+        crate::reader::watched_addresses(&primary, &secondary, &self.addresses())
+    }
+
+    // token symbols with their addresses
+    pub fn token_addresses(&self) -> BTreeMap<String, H160> {
+        self.tokens
+            .iter()
+            .map(|t| (t.symbol.clone(), t.address))
+            .collect()
+    }
+
+    pub fn decimals(&self) -> BTreeMap<String, usize> {
+        self.tokens
+            .iter()
+            .map(|t| (t.symbol.clone(), t.decimals))
+            .collect()
+    }
+
+    // treasury names with their wallets
+    pub fn treasury_wallets(&self) -> BTreeMap<String, H160> {
+        self.treasuries
+            .iter()
+            .map(|t| (t.name.clone(), t.address))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// settings by the chain ID
+    pub chains: BTreeMap<String, ChainConfig>,
+}
+
+impl Config {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        for (chain_id, chain) in &config.chains {
+            if u64::from_str(chain_id).is_err() {
+                return Err(anyhow::Error::msg(format!(
+                    "chains.{}: chain ID should be a number",
+                    chain_id
+                )));
+            }
+            if let Err(e) = chain.check() {
+                return Err(anyhow::Error::msg(format!("chains.{}: {}", chain_id, e)));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn read(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::Error::msg(format!("config {}: {}", path, e)))?;
+        Self::parse(&content).map_err(|e| anyhow::Error::msg(format!("config {}: {}", path, e)))
+    }
+
+    pub fn chain(&self, chain_id: u64) -> anyhow::Result<ChainConfig> {
+        match self.chains.get(&chain_id.to_string()) {
+            Some(x) => Ok(x.clone()),
+            None => Err(anyhow::Error::msg(format!(
+                "config has no settings for chain {}",
+                chain_id
+            ))),
+        }
+    }
+}
+
+// settings that are known before connecting to the node
+#[derive(Debug, Clone)]
+pub enum Source {
+    File(Config),
+    Args(Box<ChainConfig>),
+}
+
+impl Source {
+    pub fn load(args: &Args) -> anyhow::Result<Self> {
+        match &args.config {
+            Some(path) => Ok(Self::File(Config::read(path)?)),
+            None => {
+                let chain = ChainConfig::from_args(args)?;
+                chain.check()?;
+                Ok(Self::Args(Box::new(chain)))
+            }
+        }
+    }
+
+    pub fn chain(self, chain_id: u64) -> anyhow::Result<ChainConfig> {
+        match self {
+            Self::File(config) => config.chain(chain_id),
+            Self::Args(chain) => Ok(*chain),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[chains.4]
+genesis_block = 8842400
+explorer = "https://rinkeby.etherscan.io"
+
+[chains.4.contracts]
+pool = "f10952f418da8da5ece292b1b82a20479633f173"
+token = "0xd3e7bc3f88a39af6cb19394ccc4c0705f2c6f0c2"
+convenience = "269e1baceb37d22aaddddd3ed83e54ae6e8c2672"
+voting_primary = "2c5c6557d4b9874411adf1c126cb3bae7242c1c0"
+agent_primary = "43e78f2911c3a8db79cf03269b691f27a2551e2e"
+voting_secondary = "51e9737734b7ae1456ce174f046fb784c3a8d8b1"
+agent_secondary = "36d09b485fe0c3a24e92fa24cfdc0e8ebf981ef9"
+
+[[chains.4.tokens]]
+symbol = "USDC"
+address = "eb8f08a975ab53e34d8a0330e0d34de942c95926"
+decimals = 6
+
+[[chains.4.treasuries]]
+name = "Primary Treasury"
+address = "43e78f2911c3a8db79cf03269b691f27a2551e2e"
+
+[[chains.4.versions]]
+version = "pool-v0"
+address = "0000000000000000000000000000000000000a30"
+to_block = 8900000
+
+[[chains.4.versions]]
+version = "pool-v1"
+address = "f10952f418da8da5ece292b1b82a20479633f173"
+from_block = 8900001
+"#;
+
+    #[test]
+    fn it_reads_and_checks_config() {
+        let config = Config::parse(CONFIG).unwrap();
+        let chain = config.chain(4).unwrap();
+        assert_eq!(chain.genesis_block, 8842400);
+        assert_eq!(chain.watched().len(), 7);
+        assert_eq!(chain.decimals().get("USDC"), Some(&6));
+        assert_eq!(chain.treasury_wallets().len(), 1);
+        assert_eq!(chain.versions.len(), 2);
+        assert!(chain.versions[0].contains(8900000) && !chain.versions[0].contains(8900001));
+        assert!(chain.versions[1].overlaps(8800000, 8900001));
+        assert!(!chain.versions[1].overlaps(8800000, 8900000));
+        assert!(config.chain(1).is_err());
+
+        let broken = CONFIG.replace("\"f10952f418", "\"x10952f418");
+        let e = Config::parse(&broken).unwrap_err().to_string();
+        assert!(e.contains("invalid address") && e.contains("pool"), "{}", e);
+        let missing = CONFIG.replace("pool = ", "# pool = ");
+        let e = Config::parse(&missing).unwrap_err().to_string();
+        assert!(e.contains("missing field `pool`"), "{}", e);
+        let discovered = CONFIG
+            .replace("voting_", "# voting_")
+            .replace("agent_", "# agent_");
+        let bare = Config::parse(&discovered).unwrap().chain(4).unwrap();
+        assert_eq!(bare.contracts.apps(), None);
+        assert_eq!(bare.watched().len(), 3);
+        let partial = CONFIG.replace("agent_secondary", "# agent_secondary");
+        assert!(Config::parse(&partial).is_err());
+        let repeated = CONFIG.replace("symbol = \"USDC\"", "symbol = \"\"");
+        assert!(Config::parse(&repeated).is_err());
+        let explorer = CONFIG.replace("https://rinkeby", "rinkeby");
+        assert!(Config::parse(&explorer).is_err());
+
+        // the config that comes with the tracker
+        let shipped = Config::parse(include_str!("../config.toml")).unwrap();
+        assert_eq!(shipped.chain(4).unwrap().contracts, chain.contracts);
+        let reversed = CONFIG.replace("to_block = 8900000", "from_block = 1\nto_block = 0");
+        assert!(Config::parse(&reversed).is_err());
+        assert_eq!(shipped.chain(1).unwrap().tokens.len(), 2);
+        let zero = "0000000000000000000000000000000000000000";
+        let circulation = CONFIG.replace(
+            "[[chains.4.tokens]]",
+            &format!("circulation = \"{}\"\n\n[[chains.4.tokens]]", zero),
+        );
+        let e = Config::parse(&circulation).unwrap_err().to_string();
+        assert!(e.contains("contracts.circulation is zero"), "{}", e);
+    }
+
+    #[test]
+    fn it_checks_settings_of_variables() {
+        use structopt::StructOpt;
+        let args = |circulation: &str| {
+            let argv = [
+                "api3tracker",
+                "--address-api3-pool=f10952f418da8da5ece292b1b82a20479633f173",
+                "--address-api3-token=d3e7bc3f88a39af6cb19394ccc4c0705f2c6f0c2",
+                "--address-convenience=269e1baceb37d22aaddddd3ed83e54ae6e8c2672",
+                circulation,
+            ];
+            Args::from_iter_safe(argv.iter()).unwrap()
+        };
+        let valid = args("--address-circulation=0x00000000000000000000000000000000000000a3");
+        assert!(Source::load(&valid).is_ok());
+        let zero = args("--address-circulation=0x0000000000000000000000000000000000000000");
+        let e = Source::load(&zero).unwrap_err().to_string();
+        assert!(e.contains("contracts.circulation is zero"), "{}", e);
+    }
+}
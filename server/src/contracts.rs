@@ -1,8 +1,8 @@
 use client::nice;
-use client::state::{Api3Circulation, Api3PoolInfo, VotingStaticData};
+use client::state::{Api3Circulation, Api3PoolInfo, TokenInfo, VotingStaticData};
 use tracing::warn;
 use web3::contract::{Contract, Options};
-use web3::types::{H160, U256};
+use web3::types::{CallRequest, H160, U256};
 
 #[derive(Debug)]
 pub struct Convenience<T>
@@ -407,6 +407,7 @@ where
     T: web3::Transport,
 {
     contract: Contract<T>,
+    eth: web3::api::Eth<T>,
 }
 
 impl<T: web3::Transport> Erc20Contract<T> {
@@ -417,7 +418,10 @@ impl<T: web3::Transport> Erc20Contract<T> {
             include_bytes!("./contract/erc20.abi.json"),
         )
         .expect("fail contract::from_json(erc20.abi.json)");
-        Erc20Contract { contract: contract }
+        Erc20Contract {
+            contract: contract,
+            eth: web3.eth(),
+        }
     }
 
     pub async fn get_balance(&self, wallet: H160) -> Option<U256> {
@@ -433,4 +437,60 @@ impl<T: web3::Transport> Erc20Contract<T> {
             }
         }
     }
+
+    pub async fn get_symbol(&self) -> Option<String> {
+        self.get_text("symbol").await
+    }
+
+    pub async fn get_name(&self) -> Option<String> {
+        self.get_text("name").await
+    }
+
+    // string of the token, or bytes32 with the trailing zeros cut off
+    async fn get_text(&self, method: &str) -> Option<String> {
+        match self
+            .contract
+            .query(method, (), None, Options::default(), None)
+            .await
+        {
+            Ok(x) => return Some(x),
+            Err(e) => warn!("{} {}", method, e),
+        }
+        let data = self
+            .contract
+            .abi()
+            .function(method)
+            .ok()?
+            .encode_input(&[])
+            .ok()?;
+        let req = CallRequest {
+            to: Some(self.contract.address()),
+            data: Some(data.into()),
+            ..CallRequest::default()
+        };
+        let res = self.eth.call(req, None).await.ok()?;
+        if res.0.len() != 32 {
+            return None;
+        }
+        let end = res.0.iter().rposition(|&b| b != 0).map_or(0, |x| x + 1);
+        String::from_utf8(res.0[..end].to_vec()).ok()
+    }
+
+    // decimals of the token, the call fails when the contract is not a token
+    pub async fn decimals(&self) -> web3::contract::Result<U256> {
+        self.contract
+            .query("decimals", (), None, Options::default(), None)
+            .await
+    }
+
+    // token of the contract with its decimals.
+    // Some tokens have no name or symbol, or give them as bytes32
+    pub async fn read_info(&self, decimals: usize) -> TokenInfo {
+        TokenInfo {
+            address: self.contract.address(),
+            symbol: self.get_symbol().await.unwrap_or_default(),
+            name: self.get_name().await.unwrap_or_default(),
+            decimals,
+        }
+    }
 }
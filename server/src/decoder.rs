@@ -0,0 +1,497 @@
+// Decoding of the logs into `Api3` events, driven by the bundled ABI files:
+// topics, indexed parameters and the layout of the data come from the ABI,
+// and the parameters of the event become the fields of the variant with the same name
+use client::events::{Api3, VotingAgent};
+use client::logreader::{EventParseError, LogReader};
+use hex_literal::hex;
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use web3::ethabi::{Contract, Event, ParamType};
+use web3::types::{Bytes, Log, H256, U256};
+
+// ABI of the watched contracts and the suffix of the variants of their events.
+// Events are registered by their topic in this order, so the events of the pool
+// before the upgrade get `V0` variants only when they differ from the current ones
+const SOURCES: &[(&str, &[u8], &str)] = &[
+    (
+        "api3_pool_v1.abi.json",
+        include_bytes!("./contract/api3_pool_v1.abi.json"),
+        "",
+    ),
+    (
+        "api3_pool.abi.json",
+        include_bytes!("./contract/api3_pool.abi.json"),
+        "V0",
+    ),
+    (
+        "api3_voting.abi.json",
+        include_bytes!("./contract/api3_voting.abi.json"),
+        "",
+    ),
+    (
+        "api3_agent.abi.json",
+        include_bytes!("./contract/api3_agent.abi.json"),
+        "",
+    ),
+    (
+        "api3_convenience.abi.json",
+        include_bytes!("./contract/api3_convenience.abi.json"),
+        "",
+    ),
+    (
+        "api3_supply.abi.json",
+        include_bytes!("./contract/api3_supply.abi.json"),
+        "",
+    ),
+    (
+        "api3_token.abi.json",
+        include_bytes!("./contract/api3_token.abi.json"),
+        "",
+    ),
+];
+
+// parameters that have other names in the variants: (variant, parameter, field)
+const RENAMES: &[(&str, &str, &str)] = &[
+    ("Delegated", "user", "from"),
+    ("Delegated", "delegate", "to"),
+    ("DelegatedV0", "user", "from"),
+    ("DelegatedV0", "delegate", "to"),
+    ("Undelegated", "user", "from"),
+    ("Undelegated", "delegate", "to"),
+    ("UndelegatedV0", "user", "from"),
+    ("UndelegatedV0", "delegate", "to"),
+    ("StakedV0", "shares", "minted_shares"),
+    ("OwnershipTransferred", "previousOwner", "from"),
+    ("OwnershipTransferred", "newOwner", "to"),
+    ("SetErc20Addresses", "erc20Addresses", "addresses"),
+    ("SetVestingAddresses", "vestingAddresses", "addresses"),
+    ("Transfer", "value", "amount"),
+    ("Approval", "value", "amount"),
+];
+
+// topics that are known but not decoded, they are ignored
+const UNCLASSIFIED: &[[u8; 32]] = &[
+    // not an event: the hash of the transaction where the agent events were found
+    // (0xc59489a8...), it was taken for the topic of the agent call
+    hex!("c59489a810a16d84f59a04fb90817354d9afac3bd0a0b6787c8ccb4ff25ed119"),
+];
+
+lazy_static! {
+    static ref DECODER: Decoder = Decoder::new();
+}
+
+// decodes the log. `voting` is the agent of the voting app that emitted it
+pub fn decode(voting: Option<VotingAgent>, log: &Log) -> Result<Api3, EventParseError> {
+    DECODER.decode(voting, log)
+}
+
+pub struct Decoder {
+    // variant and ABI of the events, by the topic
+    events: BTreeMap<H256, (String, Event)>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        let mut events = BTreeMap::new();
+        for (name, abi, suffix) in SOURCES {
+            let contract =
+                Contract::load(*abi).unwrap_or_else(|e| panic!("fail to load {}: {}", name, e));
+            for event in contract.events() {
+                events
+                    .entry(event.signature())
+                    .or_insert_with(|| (format!("{}{}", event.name, suffix), event.clone()));
+            }
+        }
+        Self { events }
+    }
+
+    pub fn decode(&self, voting: Option<VotingAgent>, log: &Log) -> Result<Api3, EventParseError> {
+        let t0 = log.topics.first().ok_or(EventParseError::NoTopics)?;
+        let (variant, event) = match self.events.get(t0) {
+            Some(x) => x,
+            None if UNCLASSIFIED.iter().any(|x| H256::from(*x) == *t0) => {
+                return Ok(Api3::Unclassified)
+            }
+            None => return Ok(Api3::Unknown),
+        };
+        let indexed = event.inputs.iter().filter(|x| x.indexed).count();
+        let mut r = LogReader::new(log, indexed, None)?;
+        let mut fields = Map::new();
+        fields.insert("type".to_owned(), json!(variant));
+        if let Some(agent) = voting {
+            fields.insert("agent".to_owned(), json!(agent));
+        }
+        for input in &event.inputs {
+            let value = if input.indexed {
+                topic_json(&input.kind, r.topic()?)?
+            } else {
+                data_json(&input.kind, &mut r)?
+            };
+            fields.insert(field_name(variant, &input.name), value);
+        }
+        serde_json::from_value(Value::Object(fields))
+            .map_err(|e| EventParseError::InvalidFields(variant.clone(), e.to_string()))
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// name of the field for the parameter, snake case unless it is renamed
+fn field_name(variant: &str, param: &str) -> String {
+    if let Some((_, _, field)) = RENAMES
+        .iter()
+        .find(|(v, p, _)| *v == variant && *p == param)
+    {
+        return field.to_string();
+    }
+    let mut res = String::new();
+    for ch in param.chars() {
+        if ch.is_ascii_uppercase() {
+            res.push('_');
+            res.push(ch.to_ascii_lowercase());
+        } else {
+            res.push(ch);
+        }
+    }
+    res
+}
+
+// value of the type that takes one word
+fn word_json(kind: &ParamType, word: &[u8; 32]) -> Result<Value, EventParseError> {
+    Ok(match kind {
+        ParamType::Address => json!(LogReader::as_address(word)?),
+        ParamType::Uint(_) | ParamType::Int(_) => json!(U256::from_big_endian(word)),
+        ParamType::Bool => json!(LogReader::as_bool(word)?),
+        ParamType::FixedBytes(n) if *n <= 32 => json!(Bytes(word[..*n].to_vec())),
+        _ => return Err(EventParseError::Unsupported(kind.to_string())),
+    })
+}
+
+// indexed parameters of dynamic types are given by their hashes
+fn topic_json(kind: &ParamType, topic: H256) -> Result<Value, EventParseError> {
+    match kind {
+        ParamType::String | ParamType::Bytes | ParamType::Array(_) => Ok(json!(topic)),
+        _ => word_json(kind, topic.as_fixed_bytes()),
+    }
+}
+
+fn data_json(kind: &ParamType, r: &mut LogReader) -> Result<Value, EventParseError> {
+    match kind {
+        // strings are kept as they are, they are sanitized where they are shown
+        ParamType::String => Ok(json!(String::from_utf8_lossy(&r.bytes()?))),
+        ParamType::Bytes => Ok(json!(Bytes(r.bytes()?))),
+        ParamType::Array(inner) => {
+            let words = r.words()?;
+            let list: Result<Vec<Value>, EventParseError> =
+                words.iter().map(|w| word_json(inner, w)).collect();
+            Ok(Value::Array(list?))
+        }
+        _ => word_json(kind, &r.word()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrpc;
+    use client::metadata::ProposalMetadata;
+    use client::state::{AppState, OnChainEvent};
+    use proptest::prelude::*;
+    use web3::ethabi::{encode, Token};
+    use web3::types::H160;
+
+    fn zero(kind: &ParamType) -> Token {
+        match kind {
+            ParamType::Address => Token::Address(H160::zero()),
+            ParamType::Uint(_) => Token::Uint(U256::zero()),
+            ParamType::Int(_) => Token::Int(U256::zero()),
+            ParamType::Bool => Token::Bool(false),
+            ParamType::String => Token::String(String::new()),
+            ParamType::Bytes => Token::Bytes(vec![]),
+            ParamType::FixedBytes(n) => Token::FixedBytes(vec![0; *n]),
+            ParamType::Array(_) => Token::Array(vec![]),
+            ParamType::FixedArray(kind, n) => Token::FixedArray(vec![zero(kind); *n]),
+            ParamType::Tuple(kinds) => Token::Tuple(kinds.iter().map(zero).collect()),
+        }
+    }
+
+    #[test]
+    fn it_has_variants_for_all_abi_events() {
+        let decoder = Decoder::new();
+        for (topic, (variant, event)) in &decoder.events {
+            let mut topics = vec![*topic];
+            let mut data = vec![];
+            for input in &event.inputs {
+                if input.indexed {
+                    topics.push(H256::zero());
+                } else {
+                    data.push(zero(&input.kind));
+                }
+            }
+            let log = testrpc::log(H160::zero(), topics, encode(&data));
+            match decoder.decode(Some(VotingAgent::Primary), &log) {
+                Ok(Api3::Unknown) | Ok(Api3::Unclassified) => panic!("{} is not decoded", variant),
+                Ok(_) => {}
+                Err(e) => panic!("{} has no matching variant: {}", variant, e),
+            }
+        }
+    }
+
+    #[test]
+    fn it_decodes_logs() {
+        let user: H160 = hex!("061b8335e1d2042975c4ed849943334bd07fb504").into();
+        let log = testrpc::log(
+            H160::zero(),
+            vec![
+                hex!("06fbd2297e6f6f7701a9cf99685a6af911cab275ec5c75ac7aaaf13b5cf3d61f").into(),
+                H256::from(user),
+            ],
+            hex!("0000000000000000000000000000000000000000000000056bc75e2d631000000000000000000000000000000000000000000000000000056bb73f60696ee4160000000000000000000000000000000000000000000000000000000060da02bd").to_vec(),
+        );
+        match decode(None, &log).unwrap() {
+            Api3::ScheduledUnstakeV0 {
+                user: u,
+                amount,
+                shares: _,
+                scheduled_for,
+            } => {
+                assert_eq!(u, user);
+                assert_eq!(amount, U256::from(100_000_000_000_000_000_000u128));
+                assert_eq!(scheduled_for, U256::from(0x60da02bd));
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+
+        let log = testrpc::log(
+            H160::zero(),
+            vec![
+                hex!("4d72fe0577a3a3f7da968d7b892779dde102519c25527b29cf7054f245c791b9").into(),
+                H256::from_low_u64_be(3),
+                H256::from(user),
+            ],
+            hex!("00000000000000000000000000000000000000000000000000000000000000200000000000000000000000000000000000000000000000000000000000000047311f7472616e7366657228616464726573732c75696e74323536291f4d7920666972737420415049332070726f706f73616c1f466f722074657374696e6720707572706f73657300000000000000000000000000000000000000000000000000").to_vec(),
+        );
+        match decode(Some(VotingAgent::Secondary), &log).unwrap() {
+            Api3::StartVote {
+                agent,
+                vote_id,
+                creator,
+                metadata,
+            } => {
+                assert_eq!(agent, VotingAgent::Secondary);
+                assert_eq!(vote_id, U256::from(3));
+                assert_eq!(creator, user);
+                assert_eq!(
+                    metadata,
+                    "1\u{1f}transfer(address,uint256)\u{1f}My first API3 proposal\u{1f}For testing purposes"
+                );
+                let proposal = ProposalMetadata::parse(&metadata).unwrap();
+                assert_eq!(proposal.signature, "transfer(address,uint256)");
+                assert_eq!(proposal.title, "My first API3 proposal");
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+        // votes are decoded only with the agent of the voting app
+        assert!(decode(None, &log).is_err());
+        // the layout of the data is checked
+        let mut broken = log.clone();
+        broken.topics.pop();
+        assert!(decode(Some(VotingAgent::Primary), &broken).is_err());
+
+        let topic: H256 =
+            hex!("c59489a810a16d84f59a04fb90817354d9afac3bd0a0b6787c8ccb4ff25ed119").into();
+        let log = testrpc::log(H160::zero(), vec![topic], vec![]);
+        assert!(matches!(decode(None, &log).unwrap(), Api3::Unclassified));
+        let log = testrpc::log(H160::zero(), vec![H256::zero()], vec![]);
+        assert!(matches!(decode(None, &log).unwrap(), Api3::Unknown));
+    }
+
+    #[test]
+    fn it_links_agent_calls_to_the_executed_vote() {
+        let voting = H160::from_low_u64_be(0xa1);
+        let agent = H160::from_low_u64_be(0xa2);
+        let target = H160::from_low_u64_be(0xb0);
+        let topic = |name: &str| {
+            let (topic, _) = DECODER.events.iter().find(|(_, x)| x.0 == name).unwrap();
+            *topic
+        };
+        let vote_id = H256::from_low_u64_be(7);
+        let logs = vec![
+            (
+                1,
+                voting,
+                vec![topic("StartVote"), vote_id, H256::from(target)],
+                encode(&[Token::String("title".to_owned())]),
+            ),
+            (
+                2,
+                voting,
+                vec![
+                    topic("LogScriptCall"),
+                    H256::zero(),
+                    H256::from(voting),
+                    H256::from(agent),
+                ],
+                vec![],
+            ),
+            (
+                2,
+                agent,
+                vec![topic("Execute"), H256::from(voting), H256::from(target)],
+                encode(&[
+                    Token::Uint(5.into()),
+                    Token::Bytes(vec![0xa9, 0x05, 0x9c, 0xbb]),
+                ]),
+            ),
+            (
+                2,
+                voting,
+                vec![topic("ScriptResult"), H256::zero()],
+                encode(&[
+                    Token::Bytes(vec![1]),
+                    Token::Bytes(vec![]),
+                    Token::Bytes(vec![2, 3]),
+                ]),
+            ),
+            (2, voting, vec![topic("ExecuteVote"), vote_id], vec![]),
+        ];
+        let mut app = AppState::new(1);
+        for (block, address, topics, data) in logs {
+            let l = testrpc::log(address, topics, data);
+            let e = OnChainEvent {
+                entry: decode(Some(VotingAgent::Primary), &l).unwrap(),
+                tm: 0,
+                block_number: block,
+                tx: H256::from_low_u64_be(block),
+                log_index: 0,
+                version: None,
+            };
+            app.update(e, l);
+        }
+        let v = app.votings.values().next().unwrap();
+        assert!(v.executed);
+        let execution = v.execution.as_ref().unwrap();
+        assert_eq!(execution.calls.len(), 1);
+        assert_eq!(execution.calls[0].agent, agent);
+        assert_eq!(execution.calls[0].target, target);
+        assert_eq!(execution.calls[0].eth_value, U256::from(5));
+        assert_eq!(execution.calls[0].data.0, vec![0xa9, 0x05, 0x9c, 0xbb]);
+        assert_eq!(execution.return_data.as_ref().unwrap().0, vec![2, 3]);
+        assert!(app.pending_execution.is_none());
+    }
+
+    #[test]
+    fn it_judges_votings_by_the_params_at_their_start() {
+        let voting = H160::from_low_u64_be(0xa1);
+        let topic = |name: &str| {
+            let (topic, _) = DECODER.events.iter().find(|(_, x)| x.0 == name).unwrap();
+            *topic
+        };
+        let start = |vote_id: u64| {
+            (
+                vec![
+                    topic("StartVote"),
+                    H256::from_low_u64_be(vote_id),
+                    H256::zero(),
+                ],
+                encode(&[Token::String("title".to_owned())]),
+            )
+        };
+        // 30% of all votes and 60% of the cast votes
+        let pct = |x: u64| U256::exp10(16) * x;
+        let logs = vec![
+            start(1),
+            (
+                vec![topic("ChangeMinQuorum")],
+                encode(&[Token::Uint(pct(30))]),
+            ),
+            (
+                vec![topic("ChangeSupportRequired")],
+                encode(&[Token::Uint(pct(60))]),
+            ),
+            start(2),
+        ];
+        let mut app = AppState::new(1);
+        for (block, (topics, data)) in logs.into_iter().enumerate() {
+            let l = testrpc::log(voting, topics, data);
+            let e = OnChainEvent {
+                entry: decode(Some(VotingAgent::Secondary), &l).unwrap(),
+                tm: 0,
+                block_number: block as u64,
+                tx: H256::from_low_u64_be(block as u64),
+                log_index: 0,
+                version: None,
+            };
+            app.update(e, l);
+        }
+        let mut votings = app.votings.values().map(|v| app.params_of(v));
+        let first = votings.next().unwrap();
+        assert_eq!(
+            (first.min_quorum_pct, first.support_required_pct),
+            (pct(15), pct(50))
+        );
+        let second = votings.next().unwrap();
+        assert_eq!(
+            (second.min_quorum_pct, second.support_required_pct),
+            (pct(30), pct(60))
+        );
+        assert_eq!(
+            app.vote_params_at(&VotingAgent::Primary, 3).min_quorum_pct,
+            pct(50)
+        );
+        assert_eq!(
+            app.vote_params_at(&VotingAgent::Secondary, 0)
+                .min_quorum_pct,
+            pct(15)
+        );
+        assert_eq!(
+            app.vote_params_at(&VotingAgent::Secondary, 1)
+                .min_quorum_pct,
+            pct(30)
+        );
+
+        let v = app.votings.values().nth(1).unwrap().clone();
+        let total = U256::exp10(18) * 100;
+        let judged = |yes: u64, no: u64| {
+            let mut x = v.clone();
+            x.votes_total = total;
+            x.voted_yes = U256::exp10(18) * yes;
+            x.voted_no = U256::exp10(18) * no;
+            second.is_passing(&x)
+        };
+        assert!(judged(31, 0));
+        assert!(!judged(29, 0));
+        assert!(!judged(30, 0));
+        assert!(!judged(31, 30));
+
+        // the history is kept in the saved state
+        let saved: AppState = serde_json::from_str(&serde_json::to_string(&app).unwrap()).unwrap();
+        assert_eq!(saved.vote_params, app.vote_params);
+    }
+
+    proptest! {
+        // logs of the known events with any topics and data are decoded or rejected
+        #[test]
+        fn it_never_panics_on_arbitrary_logs(
+            index in any::<usize>(),
+            topics in prop::collection::vec(any::<[u8; 32]>(), 0..4),
+            data in prop::collection::vec(any::<u8>(), 0..300),
+            words in prop::collection::vec(any::<[u8; 32]>(), 0..8),
+        ) {
+            let known: Vec<H256> = DECODER.events.keys().cloned().collect();
+            let mut all = vec![known[index % known.len()]];
+            all.extend(topics.into_iter().map(H256::from));
+            let log = testrpc::log(H160::zero(), all.clone(), data);
+            let _ = decode(Some(VotingAgent::Primary), &log);
+            let _ = decode(None, &log);
+            // word aligned data gets past the sizes more often
+            let log = testrpc::log(H160::zero(), all, words.concat());
+            let _ = decode(Some(VotingAgent::Secondary), &log);
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use crate::reader;
+use crate::replay::Record;
+use async_trait::async_trait;
 use client::events::Api3;
 use client::state::OnChainEvent;
 use std::collections::BTreeMap;
+use std::io::Write;
 use web3::types::H256;
 
 pub struct Unknown {
@@ -22,8 +25,9 @@ impl Unknown {
     }
 }
 
+#[async_trait]
 impl reader::EventHandler for Unknown {
-    fn on(&mut self, e: OnChainEvent, l: web3::types::Log) -> () {
+    async fn on(&mut self, e: OnChainEvent, l: web3::types::Log) {
         if let Api3::Unknown = e.entry {
             if !self.unknown_topics.contains_key(&l.topics[0]) {
                 self.unknown_topics
@@ -42,8 +46,51 @@ impl Events {
     }
 }
 
+#[async_trait]
 impl reader::EventHandler for Events {
-    fn on(&mut self, entry: OnChainEvent, _: web3::types::Log) -> () {
+    async fn on(&mut self, entry: OnChainEvent, _: web3::types::Log) {
         println!("{}", serde_json::to_string(&entry).unwrap());
     }
 }
+
+// events are not handled, when only the logs are recorded
+pub struct Ignore;
+
+#[async_trait]
+impl reader::EventHandler for Ignore {
+    async fn on(&mut self, _: OnChainEvent, _: web3::types::Log) {}
+}
+
+// records the logs with their timestamps, to be replayed later instead of a node.
+// It gets the logs from the scanner as they were read, including those that are not decoded
+#[derive(Debug)]
+pub struct Raw<W: Write> {
+    pub out: W,
+}
+
+impl<W: Write> Raw<W> {
+    pub fn new(out: W, chain_id: u64) -> Self {
+        let mut res = Self { out };
+        res.write(&Record::Chain { chain_id });
+        res
+    }
+
+    fn write(&mut self, r: &Record) {
+        let line = serde_json::to_string(r).unwrap();
+        writeln!(self.out, "{}", line).expect("Unable to write record");
+    }
+
+    // marks the last block that was scanned
+    pub fn done(&mut self, block_number: u64) {
+        self.write(&Record::Head { block_number });
+    }
+}
+
+impl<W: Write + Send + std::fmt::Debug> reader::LogRecorder for Raw<W> {
+    fn record(&mut self, l: &web3::types::Log, tm: u64) {
+        self.write(&Record::Log {
+            log: Box::new(l.clone()),
+            tm,
+        });
+    }
+}
@@ -1,3 +1,4 @@
+use crate::history::{HistoryQuery, Readings};
 use crate::inject;
 use client::screens;
 use client::screens::meta::{MetaProvider, PageMetaInfo};
@@ -40,13 +41,56 @@ pub fn render_meta(content: &str, info: PageMetaInfo) -> String {
     doc.html().to_string()
 }
 
+// where the pages are served from
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub static_dir: String,
+    /// name of the instance, its pages are under `/{instance}/`
+    pub instance: String,
+}
+
+// relative links of the page
+fn is_local_link(href: &str) -> bool {
+    !href.is_empty() && !href.starts_with('/') && !href.starts_with('#') && !href.contains(':')
+}
+
+// links of the page lead to the pages of the same instance,
+// as the state was at the block or the date of the query
+pub fn render_links(content: &str, instance: &str, query: &HistoryQuery) -> String {
+    if instance.is_empty() && query.is_empty() {
+        return content.to_owned();
+    }
+    let doc = nipper::Document::from(content);
+    if !instance.is_empty() {
+        let base = doc.select("base").attr_or("href", "/").to_string();
+        let href = format!("{}/{}/", base.trim_end_matches('/'), instance);
+        doc.select("base").remove();
+        doc.select("head")
+            .append_html(format!(r#"<base href="{}">"#, href));
+    }
+    if !query.is_empty() {
+        for mut a in doc.select("a[href]").iter() {
+            let href = a.attr_or("href", "").to_string();
+            if is_local_link(&href) {
+                let separator = if href.contains('?') { '&' } else { '?' };
+                a.set_attr(
+                    "href",
+                    &format!("{}{}{}", href, separator, query.to_query()),
+                );
+            }
+        }
+    }
+    doc.html().to_string()
+}
+
 pub fn render_html(
-    static_dir: &str,
+    site: &Site,
+    query: &HistoryQuery,
     _app: &AppState,
     component: Box<dyn Render>,
     meta: Box<dyn MetaProvider>,
 ) -> impl warp::Reply {
-    let file = format!("{}/index.html", static_dir);
+    let file = format!("{}/index.html", site.static_dir);
     let content = std::fs::read_to_string(file.as_str()).expect("index.html not found");
     let with_meta: String = render_meta(&content, meta.meta());
 
@@ -60,7 +104,7 @@ pub fn render_html(
                 inject::replace(c1.as_str(), "<script type=\"module\">", "</script>", "");
             let c3: String = inject::replace(c2.as_str(), "<link rel=\"preload\"", ">", "");
             let c4: String = inject::replace(c3.as_str(), "<link rel=\"modulepreload\"", ">", "");
-            c4
+            render_links(&c4, &site.instance, query)
         }
         Err(_) => {
             // inject::it(content.as_str(), "main(`", "`)", &state_json),
@@ -70,14 +114,14 @@ pub fn render_html(
     warp::reply::html(rendered)
 }
 
-pub fn render_err(static_dir: &str, app: &AppState, msg: &'static str) -> warp::reply::Response {
+pub fn render_err(site: &Site, app: &AppState, msg: &str) -> warp::reply::Response {
     let screen = screens::failure::Screen {
         msg: msg.to_owned(),
         state: app.clone(),
     };
     let (comp, page) = (Box::new(screen.view()), Box::new(screen));
     warp::reply::with_status(
-        render_html(static_dir.to_owned().as_str(), app, comp, page),
+        render_html(site, &HistoryQuery::default(), app, comp, page),
         warp::http::StatusCode::BAD_REQUEST,
     )
     .into_response()
@@ -99,17 +143,76 @@ where
     res
 }
 
+// state of the application at the block or the date of the query, or the current one.
+// The past state is built after the lock is released, so the events are not held up
+pub fn app_at(state: &Mutex<crate::State>, query: &HistoryQuery) -> anyhow::Result<AppState> {
+    let earlier = {
+        let mut state = state.lock().unwrap();
+        if query.is_empty() {
+            return Ok(state.app.clone());
+        }
+        state.history.take_earlier(query)
+    };
+    if let Some(earlier) = earlier {
+        let history = earlier.read()?;
+        state.lock().unwrap().history.prepend(history);
+    }
+    let (past, readings) = {
+        let state = state.lock().unwrap();
+        (state.history.query(query)?, Readings::of(&state.app))
+    };
+    let mut app = past.build();
+    readings.apply(&mut app);
+    Ok(app)
+}
+
+// page of the screen with the state at the block or the date of the query.
+// The screen is not made when the state lacks what the page shows
+fn render_at<S, M>(
+    state: &Mutex<crate::State>,
+    site: &Site,
+    query: &HistoryQuery,
+    make_screen: impl FnOnce(AppState) -> Result<S, &'static str>,
+) -> warp::reply::Response
+where
+    S: Component<M> + MetaProvider + 'static,
+    M: 'static,
+{
+    let app = app_at(state, query);
+    let state = state.lock().unwrap();
+    let screen = match app.map(make_screen) {
+        Ok(Ok(x)) => x,
+        Ok(Err(msg)) => return render_err(site, &state.app, msg),
+        Err(e) => return render_err(site, &state.app, &e.to_string()),
+    };
+    let (comp, page) = (Box::new(screen.view()), Box::new(screen));
+    render_html(site, query, &state.app, comp, page).into_response()
+}
+
 pub fn routes(
     static_dir: String,
+    instance: String,
     state: Arc<Mutex<crate::State>>,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    let dir = static_dir.clone();
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let dir = Site {
+        static_dir: static_dir.clone(),
+        instance,
+    };
 
-    let api_state = warp::path!("api" / "state").map({
+    let api_state = warp::path!("api" / "state")
+        .and(warp::query::<HistoryQuery>())
+        .map({
+            let state_rc = state.clone();
+            move |query: HistoryQuery| match app_at(&state_rc, &query) {
+                Ok(app) => warp::reply::json(&app).into_response(),
+                Err(e) => json_error(&e.to_string()),
+            }
+        });
+    let api_pending = warp::path!("api" / "pending").map({
         let state_rc = state.clone();
         move || {
             let state = state_rc.lock().unwrap();
-            warp::reply::json(&state.app)
+            warp::reply::json(&wrap_result(&state.pending))
         }
     });
     let api_wallets = warp::path!("api" / "wallets").map({
@@ -162,91 +265,84 @@ pub fn routes(
         }
     });
     let api = api_state
+        .or(api_pending)
         .or(api_rewards)
         .or(api_wallets)
         .or(api_wallet)
         .or(api_votings)
         .or(api_voting);
 
-    let wallets = warp::path!("wallets").map({
-        let state_rc = state.clone();
-        let d = dir.clone();
-        move || {
-            let state = state_rc.lock().unwrap();
-            let screen = screens::wallets::Screen {
-                state: state.clone().app,
-            };
-            let (comp, page) = (Box::new(screen.view()), Box::new(screen));
-            render_html(&d, &state.app, comp, page).into_response()
-        }
-    });
-    let votings = warp::path!("votings").map({
-        let state_rc = state.clone();
-        let d = dir.clone();
-        move || {
-            let state = state_rc.lock().unwrap();
-            let screen = screens::votings::Screen {
-                state: state.clone().app,
-            };
-            let (comp, page) = (Box::new(screen.view()), Box::new(screen));
-            render_html(&d, &state.app, comp, page).into_response()
-        }
-    });
-    let rewards = warp::path!("rewards").map({
-        let state_rc = state.clone();
-        let d = dir.clone();
-        move || {
-            let state = state_rc.lock().unwrap();
-            let screen = screens::rewards::Screen {
-                state: state.clone().app,
-            };
-            let (comp, page) = (Box::new(screen.view()), Box::new(screen));
-            render_html(&d, &state.app, comp, page).into_response()
-        }
-    });
+    let wallets = warp::path!("wallets")
+        .and(warp::query::<HistoryQuery>())
+        .map({
+            let state_rc = state.clone();
+            let d = dir.clone();
+            move |query: HistoryQuery| {
+                render_at(&state_rc, &d, &query, |app| {
+                    Ok(screens::wallets::Screen { state: app })
+                })
+            }
+        });
+    let votings = warp::path!("votings")
+        .and(warp::query::<HistoryQuery>())
+        .map({
+            let state_rc = state.clone();
+            let d = dir.clone();
+            move |query: HistoryQuery| {
+                render_at(&state_rc, &d, &query, |app| {
+                    Ok(screens::votings::Screen { state: app })
+                })
+            }
+        });
+    let rewards = warp::path!("rewards")
+        .and(warp::query::<HistoryQuery>())
+        .map({
+            let state_rc = state.clone();
+            let d = dir.clone();
+            move |query: HistoryQuery| {
+                render_at(&state_rc, &d, &query, |app| {
+                    Ok(screens::rewards::Screen { state: app })
+                })
+            }
+        });
 
-    let wallet = warp::path!("wallets" / String).map({
-        let state_rc = state.clone();
-        let d = dir.clone();
-        move |id: String| {
-            let state = state_rc.lock().unwrap();
-            if let Ok(addr) = H160::from_str(id.clone().as_str()) {
-                if let Some(_) = state.app.wallets.get(&addr) {
-                    let screen = screens::wallet::Screen {
-                        addr,
-                        state: state.clone().app,
-                    };
-                    let (comp, page) = (Box::new(screen.view()), Box::new(screen));
-                    render_html(&d, &state.app, comp, page).into_response()
-                } else {
-                    render_err(&d, &state.app, "Not a member of the DAO")
-                }
-            } else {
-                render_err(&d, &state.app, "Invalid Ethereum address")
+    let wallet = warp::path!("wallets" / String)
+        .and(warp::query::<HistoryQuery>())
+        .map({
+            let state_rc = state.clone();
+            let d = dir.clone();
+            move |id: String, query: HistoryQuery| {
+                let addr = H160::from_str(id.as_str());
+                render_at(&state_rc, &d, &query, |app| match addr {
+                    Ok(addr) if app.wallets.contains_key(&addr) => {
+                        Ok(screens::wallet::Screen { addr, state: app })
+                    }
+                    Ok(_) => Err("Not a member of the DAO"),
+                    Err(_) => Err("Invalid Ethereum address"),
+                })
             }
-        }
-    });
-    let voting = warp::path!("votings" / String).map({
-        let state_rc = state.clone();
-        let d = dir.clone();
-        move |id: String| {
-            let (agent, vote_id) = client::events::voting_from_str(&id);
-            let vote_ref = client::events::voting_to_u64(&agent, vote_id);
-            let state = state_rc.lock().unwrap();
-            if let Some(_) = state.app.votings.get(&vote_ref) {
-                let screen = screens::voting::Screen {
-                    vote_ref,
-                    vote_id,
-                    agent,
-                    state: state.clone().app,
-                };
-                let (comp, page) = (Box::new(screen.view()), Box::new(screen));
-                render_html(&d, &state.app, comp, page).into_response()
-            } else {
-                render_err(&d, &state.app, "Invalid voting ID")
+        });
+    let voting = warp::path!("votings" / String)
+        .and(warp::query::<HistoryQuery>())
+        .map({
+            let state_rc = state.clone();
+            let d = dir.clone();
+            move |id: String, query: HistoryQuery| {
+                let (agent, vote_id) = client::events::voting_from_str(&id);
+                let vote_ref = client::events::voting_to_u64(&agent, vote_id);
+                render_at(&state_rc, &d, &query, |app| {
+                    if !app.votings.contains_key(&vote_ref) {
+                        return Err("Invalid voting ID");
+                    }
+                    Ok(screens::voting::Screen {
+                        vote_ref,
+                        vote_id,
+                        agent,
+                        state: app,
+                    })
+                })
             }
-        }
-    });
+        });
     let treasury = warp::path!("treasury")
         .map({
             let state_rc = state.clone();
@@ -257,7 +353,7 @@ pub fn routes(
                     state: state.clone().app,
                 };
                 let (comp, page) = (Box::new(screen.view()), Box::new(screen));
-                render_html(&d, &state.app, comp, page).into_response()
+                render_html(&d, &HistoryQuery::default(), &state.app, comp, page).into_response()
             }
         })
         .or(warp::fs::dir(static_dir.clone()));
@@ -272,7 +368,7 @@ pub fn routes(
                     state: state.clone().app,
                 };
                 let (comp, page) = (Box::new(screen.view()), Box::new(screen));
-                render_html(&d, &state.app, comp, page).into_response()
+                render_html(&d, &HistoryQuery::default(), &state.app, comp, page).into_response()
             }
         })
         .or(warp::fs::dir(static_dir.clone()));
@@ -361,3 +457,32 @@ pub fn routes_loading() -> impl Filter<Extract = impl warp::Reply, Error = warp:
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_links_in_the_instance() {
+        let content = r#"<html><head><base href="/tracker/"></head><body><main>
+            <a href="./wallets">Wallets</a>
+            <a href="votings/1?tab=votes">Voting</a>
+            <a href="https://etherscan.io">Etherscan</a>
+            <a href="/_liveness">Liveness</a>
+            </main></body></html>"#;
+        let query = HistoryQuery {
+            block: Some(5),
+            date: None,
+        };
+        let html = render_links(content, "goerli", &query);
+        assert!(html.contains(r#"<base href="/tracker/goerli/">"#));
+        assert!(!html.contains(r#"<base href="/tracker/">"#));
+        assert!(html.contains(r#"href="./wallets?block=5""#));
+        assert!(html.contains(r#"href="votings/1?tab=votes&amp;block=5""#));
+        assert!(html.contains(r#"href="https://etherscan.io""#));
+        assert!(html.contains(r#"href="/_liveness""#));
+        // the only instance keeps the page as it is
+        let current = HistoryQuery::default();
+        assert_eq!(render_links(content, "", &current), content);
+    }
+}
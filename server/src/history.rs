@@ -0,0 +1,492 @@
+// States of the application in the past: snapshots of the state taken every few events
+// and the events after them, which are applied again to the snapshot on request
+use crate::reader;
+use client::state::{
+    Api3Circulation, Api3PoolInfo, AppState, OnChainEvent, TokenInfo, Treasury, VotingDetails,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use web3::types::{Log, H160, U256};
+
+// the point in the past that is asked for
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HistoryQuery {
+    pub block: Option<u64>,
+    /// date as YYYY-MM-DD, the state at the end of that day (UTC)
+    pub date: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn is_empty(&self) -> bool {
+        self.block.is_none() && self.date.is_none()
+    }
+
+    // query string of the page URL, i.e. "block=123", empty for the current state
+    pub fn to_query(&self) -> String {
+        let mut res = vec![];
+        if let Some(block) = self.block {
+            res.push(format!("block={}", block));
+        }
+        if let Some(date) = &self.date {
+            res.push(format!("date={}", date));
+        }
+        res.join("&")
+    }
+}
+
+// snapshot of the state and the events after it, up to the block that is asked for.
+// The events are applied to it without holding the current state
+#[derive(Debug, Clone)]
+pub struct PastState {
+    snapshot: AppState,
+    events: Vec<(OnChainEvent, Log)>,
+}
+
+impl PastState {
+    pub fn build(self) -> AppState {
+        let mut app = self.snapshot;
+        for (e, l) in self.events {
+            app.update(e, l);
+        }
+        app
+    }
+}
+
+// what is read from the contracts and the names after the events, they are not a part
+// of the history. The snapshots of the scan were taken before them, so they are
+// taken from the current state
+#[derive(Debug, Clone)]
+pub struct Readings {
+    pool_info: Option<Api3PoolInfo>,
+    circulation: Option<Api3Circulation>,
+    treasuries: BTreeMap<String, Treasury>,
+    tokens: BTreeMap<H160, TokenInfo>,
+    decimals: BTreeMap<String, usize>,
+    ens: BTreeMap<H160, String>,
+    // static data of the votings with the voting power at their start
+    details: BTreeMap<u64, (U256, VotingDetails)>,
+}
+
+impl Readings {
+    pub fn of(app: &AppState) -> Self {
+        Self {
+            pool_info: app.pool_info.clone(),
+            circulation: app.circulation.clone(),
+            treasuries: app.treasuries.clone(),
+            tokens: app.tokens.clone(),
+            decimals: app.decimals.clone(),
+            ens: app
+                .wallets
+                .iter()
+                .filter_map(|(addr, w)| Some((*addr, w.ens.clone()?)))
+                .collect(),
+            details: app
+                .votings
+                .iter()
+                .filter_map(|(id, v)| Some((*id, (v.votes_total, v.details.clone()?))))
+                .collect(),
+        }
+    }
+
+    pub fn apply(self, app: &mut AppState) {
+        app.pool_info = self.pool_info;
+        app.circulation = self.circulation;
+        app.treasuries = self.treasuries;
+        app.tokens.extend(self.tokens);
+        app.decimals.extend(self.decimals);
+        for (addr, name) in self.ens {
+            if let Some(w) = app.wallets.get_mut(&addr) {
+                w.ens.get_or_insert(name);
+            }
+        }
+        for (id, (votes_total, details)) in self.details {
+            if let Some(v) = app.votings.get_mut(&id).filter(|v| v.details.is_none()) {
+                v.votes_total = votes_total;
+                v.details = Some(details);
+            }
+        }
+    }
+}
+
+// most snapshots that are kept, the older ones are thinned out
+pub const MAX_SNAPSHOTS: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    /// number of events between snapshots, 0 disables the history
+    pub every: usize,
+    // states after all events of the block, by the block number
+    snapshots: BTreeMap<u64, AppState>,
+    // events after the first snapshot
+    events: Vec<(OnChainEvent, Log)>,
+    // events since the last snapshot
+    since_snapshot: usize,
+    // events before the first snapshot, which are read from the cache when asked for
+    earlier: Option<Earlier>,
+}
+
+// the cache with the events before the history, from the genesis block.
+// They are read on the first query for them, not when the history starts
+#[derive(Debug, Clone)]
+pub struct Earlier {
+    scanner: reader::Scanner,
+    genesis_block: u64,
+    to: u64,
+    chain_id: u64,
+    every: usize,
+}
+
+impl Earlier {
+    // history of the events up to the start of the later one
+    pub fn read(&self) -> anyhow::Result<History> {
+        let mut app = AppState::new(self.chain_id);
+        let mut history = History::new(&app, self.every);
+        self.scanner
+            .replay_cached(self.genesis_block, self.to, |e, l| {
+                history.record(&app, &e, &l);
+                app.update(e, l);
+            })?;
+        Ok(history)
+    }
+}
+
+// end of the date as YYYY-MM-DD (UTC)
+fn day_end(date: &str) -> anyhow::Result<u64> {
+    let day = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| anyhow::Error::msg("Invalid date, YYYY-MM-DD expected"))?;
+    let end = day
+        .succ_opt()
+        .and_then(|x| x.and_hms_opt(0, 0, 0))
+        .ok_or_else(|| anyhow::Error::msg("Invalid date"))?;
+    Ok(end.and_utc().timestamp() as u64)
+}
+
+impl History {
+    // `app` is the state the history starts from
+    pub fn new(app: &AppState, every: usize) -> Self {
+        let mut snapshots = BTreeMap::new();
+        if every > 0 {
+            snapshots.insert(app.last_block, app.clone());
+        }
+        Self {
+            every,
+            snapshots,
+            events: vec![],
+            since_snapshot: 0,
+            earlier: None,
+        }
+    }
+
+    // the events from the genesis block up to the start of the history
+    // are read with the scanner from the cache when they are asked for
+    pub fn set_earlier(&mut self, scanner: reader::Scanner, genesis_block: u64) {
+        let (&to, first) = match self.snapshots.iter().next() {
+            Some(x) => x,
+            None => return,
+        };
+        if to >= genesis_block {
+            self.earlier = Some(Earlier {
+                scanner,
+                genesis_block,
+                to,
+                chain_id: first.chain_id,
+                every: self.every,
+            });
+        }
+    }
+
+    // should be called before the event is applied to the state
+    pub fn record(&mut self, app: &AppState, e: &OnChainEvent, l: &Log) {
+        if self.every == 0 {
+            return;
+        }
+        if self.since_snapshot >= self.every && e.block_number > app.last_block {
+            self.snapshots.insert(app.last_block, app.clone());
+            self.since_snapshot = 0;
+            self.thin();
+        }
+        self.events.push((e.clone(), l.clone()));
+        self.since_snapshot += 1;
+    }
+
+    // drops every other snapshot of the older half when there are too many,
+    // so the older snapshots are farther apart. The first one is kept,
+    // the events before it are not needed
+    fn thin(&mut self) {
+        if self.snapshots.len() <= MAX_SNAPSHOTS {
+            return;
+        }
+        let older: Vec<u64> = self
+            .snapshots
+            .keys()
+            .take(self.snapshots.len() / 2)
+            .copied()
+            .collect();
+        for block_number in older.into_iter().skip(1).step_by(2) {
+            self.snapshots.remove(&block_number);
+        }
+        if let Some(&first) = self.snapshots.keys().next() {
+            let start = self
+                .events
+                .partition_point(|(e, _)| e.block_number <= first);
+            self.events.drain(..start);
+        }
+    }
+
+    // forgets the events after the block
+    pub fn rollback(&mut self, block_number: u64) {
+        self.events.retain(|(e, _)| e.block_number <= block_number);
+        let _ = self.snapshots.split_off(&(block_number + 1));
+        self.since_snapshot = self.every;
+    }
+
+    // the last block which events were applied before the end of the date
+    pub fn block_at_date(&self, date: &str) -> anyhow::Result<u64> {
+        let end = day_end(date)?;
+        match self.events.iter().take_while(|(e, _)| e.tm < end).last() {
+            Some((e, _)) => Ok(e.block_number),
+            // the day ends before the first recorded event, the state of that day is not kept
+            None => Err(self.not_kept()),
+        }
+    }
+
+    fn not_kept(&self) -> anyhow::Error {
+        anyhow::Error::msg(match self.snapshots.keys().next() {
+            Some(x) => format!("The history starts at block {}", x),
+            None => "The history is not kept".to_owned(),
+        })
+    }
+
+    // snapshot before the block with the events up to the end of it
+    pub fn past_at(&self, block_number: u64) -> anyhow::Result<PastState> {
+        let (&from, snapshot) = match self.snapshots.range(..=block_number).next_back() {
+            Some(x) => x,
+            None => return Err(self.not_kept()),
+        };
+        let start = self.events.partition_point(|(e, _)| e.block_number <= from);
+        let end = self
+            .events
+            .partition_point(|(e, _)| e.block_number <= block_number);
+        Ok(PastState {
+            snapshot: snapshot.clone(),
+            events: self.events[start..end].to_vec(),
+        })
+    }
+
+    // state after all events of the block
+    pub fn state_at(&self, block_number: u64) -> anyhow::Result<AppState> {
+        Ok(self.past_at(block_number)?.build())
+    }
+
+    fn block_of(&self, q: &HistoryQuery) -> anyhow::Result<u64> {
+        match (&q.block, &q.date) {
+            (Some(x), _) => Ok(*x),
+            (None, Some(date)) => self.block_at_date(date),
+            (None, None) => Err(anyhow::Error::msg("block or date expected")),
+        }
+    }
+
+    pub fn query(&self, q: &HistoryQuery) -> anyhow::Result<PastState> {
+        self.past_at(self.block_of(q)?)
+    }
+
+    // the cache of the earlier events when the query goes before the history.
+    // It is taken, so they are read once
+    pub fn take_earlier(&mut self, q: &HistoryQuery) -> Option<Earlier> {
+        if let Some(date) = &q.date {
+            day_end(date).ok()?;
+        }
+        let first = *self.snapshots.keys().next()?;
+        match self.block_of(q) {
+            Ok(block_number) if block_number >= first => None,
+            _ => self.earlier.take(),
+        }
+    }
+
+    // adds the history of the events before this one
+    pub fn prepend(&mut self, earlier: History) {
+        let mut snapshots = earlier.snapshots;
+        snapshots.append(&mut self.snapshots);
+        self.snapshots = snapshots;
+        let mut events = earlier.events;
+        events.append(&mut self.events);
+        self.events = events;
+        while self.snapshots.len() > MAX_SNAPSHOTS {
+            self.thin();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::events::Api3;
+    use web3::types::{H160, H256, U256};
+
+    fn deposited(app: &mut AppState, history: &mut History, block_number: u64, amount: u64) {
+        let e = OnChainEvent {
+            entry: Api3::Deposited {
+                user: H160::from_low_u64_be(1),
+                amount: amount.into(),
+                user_unstaked: U256::zero(),
+            },
+            // a day per block
+            tm: 1_600_000_000 + (block_number - 10) * 86400,
+            block_number,
+            tx: H256::from_low_u64_be(block_number),
+            log_index: 0,
+            version: None,
+        };
+        let mut l = crate::testrpc::log(H160::zero(), vec![], vec![]);
+        l.block_number = Some(block_number.into());
+        history.record(app, &e, &l);
+        app.update(e, l);
+    }
+
+    fn deposits(app: &AppState) -> U256 {
+        app.wallets
+            .values()
+            .fold(U256::zero(), |acc, w| acc + w.deposited)
+    }
+
+    #[test]
+    fn it_rebuilds_past_states() {
+        let mut app = AppState::new(1);
+        app.last_block = 9;
+        let mut history = History::new(&app, 2);
+        for block in 10..20 {
+            deposited(&mut app, &mut history, block, 1);
+            deposited(&mut app, &mut history, block, 10);
+        }
+        assert_eq!(history.snapshots.len(), 10);
+        assert!(history.state_at(8).is_err());
+        assert_eq!(deposits(&history.state_at(9).unwrap()), U256::zero());
+        assert_eq!(deposits(&history.state_at(12).unwrap()), U256::from(33));
+        assert_eq!(history.state_at(12).unwrap().last_block, 12);
+        assert_eq!(deposits(&history.state_at(100).unwrap()), deposits(&app));
+
+        // block 10 is mined on 2020-09-13
+        assert_eq!(history.block_at_date("2020-09-13").unwrap(), 10);
+        // the history starts after that day
+        assert_eq!(
+            history.block_at_date("2020-09-01").unwrap_err().to_string(),
+            "The history starts at block 9"
+        );
+        assert!(history.block_at_date("14.09.2020").is_err());
+        let q = HistoryQuery {
+            block: None,
+            date: Some("2020-09-14".to_owned()),
+        };
+        assert_eq!(
+            deposits(&history.query(&q).unwrap().build()),
+            U256::from(22)
+        );
+
+        history.rollback(14);
+        deposited(&mut app, &mut history, 15, 100);
+        assert_eq!(deposits(&history.state_at(15).unwrap()), U256::from(155));
+
+        // names and readings are taken from the current state
+        let user = H160::from_low_u64_be(1);
+        app.wallets.get_mut(&user).unwrap().ens = Some("user.eth".to_owned());
+        app.decimals.insert("USDC".to_owned(), 6);
+        let mut past = history.state_at(11).unwrap();
+        Readings::of(&app).apply(&mut past);
+        assert_eq!(past.wallets[&user].ens.as_deref(), Some("user.eth"));
+        assert_eq!(past.decimals.get("USDC"), Some(&6));
+        assert_eq!(deposits(&past), U256::from(22));
+    }
+
+    #[test]
+    fn it_thins_out_older_snapshots() {
+        let mut app = AppState::new(1);
+        let mut history = History::new(&app, 1);
+        for block in 10..200 {
+            deposited(&mut app, &mut history, block, 1);
+        }
+        assert!(history.snapshots.len() <= MAX_SNAPSHOTS);
+        // the start and the recent blocks are kept
+        assert_eq!(history.snapshots.keys().next(), Some(&0));
+        assert!(history.snapshots.contains_key(&198));
+        let gaps: Vec<u64> = history
+            .snapshots
+            .keys()
+            .zip(history.snapshots.keys().skip(1))
+            .map(|(a, b)| b - a)
+            .collect();
+        assert!(gaps[1] > gaps[gaps.len() - 1]);
+        for block in [15, 50, 120, 199] {
+            assert_eq!(
+                deposits(&history.state_at(block).unwrap()),
+                U256::from(block - 9)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_reads_the_history_before_the_checkpoint_when_asked() {
+        use crate::reader::{get_transport, Scanner, ScannerOptions};
+        use crate::testrpc::{self, TestChain};
+        use std::sync::{Arc, Mutex};
+
+        let address = H160::from_low_u64_be(0xa3);
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        for i in 0..10u64 {
+            let l = testrpc::log(address, vec![H256::from_low_u64_be(i)], vec![]);
+            chain.lock().unwrap().mine(vec![l]);
+        }
+        let web3 = web3::Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![address],
+                genesis_block: 1,
+                batch_size: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        scanner.stop_at(6);
+        // the checkpoint at block 6
+        let mut checkpoint = AppState::new(1);
+        let mut before = History::new(&checkpoint, 2);
+        struct Recorder<'a>(&'a mut AppState, &'a mut History);
+        #[async_trait::async_trait]
+        impl<'a> reader::EventHandler for Recorder<'a> {
+            async fn on(&mut self, e: OnChainEvent, l: Log) {
+                self.1.record(self.0, &e, &l);
+                self.0.update(e, l);
+            }
+        }
+        scanner
+            .scan(&web3, &mut Recorder(&mut checkpoint, &mut before))
+            .await
+            .unwrap();
+        assert_eq!(checkpoint.last_block, 6);
+        let requests = chain.lock().unwrap().requests.len();
+
+        let mut history = History::new(&checkpoint, 2);
+        history.set_earlier(scanner, 1);
+        let current = HistoryQuery {
+            block: Some(6),
+            date: None,
+        };
+        assert!(history.take_earlier(&current).is_none());
+        let q = HistoryQuery {
+            block: Some(2),
+            date: None,
+        };
+        assert_eq!(
+            history.query(&q).unwrap_err().to_string(),
+            "The history starts at block 6"
+        );
+        let earlier = history.take_earlier(&q).unwrap();
+        assert!(history.take_earlier(&q).is_none());
+        history.prepend(earlier.read().unwrap());
+        // read from the cache, not from the node
+        assert_eq!(chain.lock().unwrap().requests.len(), requests);
+        assert_eq!(history.events.len(), 6);
+        assert_eq!(history.query(&q).unwrap().build().last_block, 2);
+        assert_eq!(history.state_at(100).unwrap().last_block, 6);
+    }
+}
@@ -0,0 +1,127 @@
+use client::state::{AppState, OnChainEvent};
+use std::collections::BTreeMap;
+
+// state of the application before the block and the events that the block applied
+#[derive(Debug, Clone)]
+struct JournalBlock {
+    before: AppState,
+    events: Vec<OnChainEvent>,
+}
+
+// Keeps the application state before each of the recent blocks,
+// so the state could be rolled back when the chain is reorganized
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    blocks: BTreeMap<u64, JournalBlock>,
+    /// how many blocks are kept behind the last one
+    pub depth: u64,
+}
+
+impl Journal {
+    pub fn new(depth: u64) -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            depth,
+        }
+    }
+
+    // should be called before the event is applied to the state
+    pub fn record(&mut self, app: &AppState, e: &OnChainEvent) {
+        self.blocks
+            .entry(e.block_number)
+            .or_insert_with(|| JournalBlock {
+                before: app.clone(),
+                events: vec![],
+            })
+            .events
+            .push(e.clone());
+        if e.block_number > self.depth {
+            let keep = self.blocks.split_off(&(e.block_number - self.depth));
+            self.blocks = keep;
+        }
+    }
+
+    // the state before the first recorded block after `block_number`
+    pub fn before(&self, block_number: u64) -> Option<&AppState> {
+        self.blocks
+            .range(block_number + 1..)
+            .next()
+            .map(|(_, b)| &b.before)
+    }
+
+    // forgets the blocks after `block_number`, returns the state before them
+    // and the events they applied, in the order of applying
+    pub fn rollback(&mut self, block_number: u64) -> Option<(AppState, Vec<OnChainEvent>)> {
+        let dropped = self.blocks.split_off(&(block_number + 1));
+        let mut blocks = dropped.into_values();
+        let first = blocks.next()?;
+        let mut events = first.events;
+        for b in blocks {
+            events.extend(b.events);
+        }
+        Some((first.before, events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::events::Api3;
+    use web3::types::{H160, H256, U256};
+
+    fn deposited(app: &mut AppState, journal: &mut Journal, block_number: u64, amount: u64) {
+        let e = OnChainEvent {
+            entry: Api3::Deposited {
+                user: H160::from_low_u64_be(1),
+                amount: amount.into(),
+                user_unstaked: U256::zero(),
+            },
+            tm: 1_600_000_000 + block_number,
+            block_number,
+            tx: H256::from_low_u64_be(block_number),
+            log_index: 0,
+            version: None,
+        };
+        journal.record(app, &e);
+        let mut l = crate::testrpc::log(H160::zero(), vec![], vec![]);
+        l.block_number = Some(block_number.into());
+        app.update(e, l);
+    }
+
+    fn deposits(app: &AppState) -> U256 {
+        app.wallets
+            .values()
+            .fold(U256::zero(), |acc, w| acc + w.deposited)
+    }
+
+    #[test]
+    fn it_rolls_back_to_the_fork() {
+        let mut app = AppState::new(1);
+        let mut journal = Journal::new(10);
+        deposited(&mut app, &mut journal, 5, 100);
+        deposited(&mut app, &mut journal, 7, 20);
+        deposited(&mut app, &mut journal, 7, 3);
+        deposited(&mut app, &mut journal, 9, 4000);
+        assert_eq!(deposits(&app), U256::from(4123));
+
+        assert_eq!(deposits(journal.before(6).unwrap()), U256::from(100));
+        assert_eq!(journal.before(7).unwrap().last_block, 7);
+        assert!(journal.before(9).is_none());
+        assert!(journal.rollback(9).is_none());
+        let (app, retracted) = journal.rollback(6).unwrap();
+        assert_eq!(deposits(&app), U256::from(100));
+        assert_eq!(app.last_block, 5);
+        let blocks: Vec<u64> = retracted.iter().map(|e| e.block_number).collect();
+        assert_eq!(blocks, vec![7, 7, 9]);
+        assert!(journal.rollback(6).is_none());
+    }
+
+    #[test]
+    fn it_keeps_only_recent_blocks() {
+        let mut app = AppState::new(1);
+        let mut journal = Journal::new(10);
+        deposited(&mut app, &mut journal, 5, 1);
+        deposited(&mut app, &mut journal, 20, 1);
+        assert_eq!(journal.rollback(4).unwrap().1.len(), 1);
+    }
+}
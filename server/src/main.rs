@@ -1,22 +1,38 @@
 pub mod args;
+pub mod bundle;
+pub mod checkpoint;
+pub mod config;
 pub mod contracts;
+pub mod decoder;
 pub mod dumper;
 pub mod endpoints;
 pub mod ens;
+pub mod history;
 pub mod inject;
+pub mod journal;
 pub mod reader;
+pub mod replay;
+pub mod rpc;
+pub mod sink;
+pub mod store;
+#[cfg(test)]
+mod testrpc;
+pub mod tokens;
 pub mod treasury;
 
 use args::DumpMode;
+use async_trait::async_trait;
+use client::action::VotingAction;
 use client::state::{AppState, OnChainEvent};
 use futures::{FutureExt, StreamExt};
+use serde::Serialize;
+use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, HashMap};
-use std::path::Path;
-use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::sync::{mpsc, oneshot, RwLock};
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::filters::BoxedFilter;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 use web3::types::H160;
@@ -24,49 +40,286 @@ use web3::types::H160;
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 type Subscribers = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Result<Message, warp::Error>>>>>;
 
+/// message to websocket subscribers, event fields with its status
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum WsMessage {
+    /// event was applied to the state
+    Committed(OnChainEvent),
+    /// event is waiting for the confirmations
+    Pending(OnChainEvent),
+    /// event was undone by chain reorganization
+    Retracted(OnChainEvent),
+}
+
 #[derive(Debug, Clone)]
 pub struct State {
     /// whether to log incoming messages
     pub verbose: bool,
-    /// subscribers of the chat
-    pub subscribers: Subscribers,
+    /// whether new blocks are watched, which could be reorganized
+    pub watching: bool,
     /// client application state
     pub app: AppState,
     /// whether it is loading
     pub loading: bool,
+    /// states before the recent blocks, for rolling back reorganized blocks
+    pub journal: journal::Journal,
+    /// events that are not confirmed yet
+    pub pending: Vec<OnChainEvent>,
+    /// saved states to resume from
+    pub checkpoints: Option<checkpoint::Checkpoints>,
+    /// past states for the historical queries
+    pub history: history::History,
+}
+
+// state with all events up to the block and none after it, if it is known
+fn state_at<'a>(
+    journal: &'a journal::Journal,
+    app: &'a AppState,
+    block_number: u64,
+) -> Option<&'a AppState> {
+    let app = journal.before(block_number).unwrap_or(app);
+    Some(app).filter(|a| a.last_block <= block_number)
+}
+
+// actions of the votings with the decimals of their tokens, and the wallets they grant to
+fn set_actions(app: &mut AppState) {
+    let known = app.tokens.clone();
+    let mut new_wallets: BTreeMap<H160, u64> = BTreeMap::new();
+    for v in app.votings.values_mut() {
+        if let Some(details) = &mut v.details {
+            details.action = VotingAction::from_calls(&details.calls, &known);
+            if let Some(wallet) = details.action.as_ref().and_then(|x| x.wallet) {
+                new_wallets.insert(wallet, v.tm);
+            }
+        }
+    }
+    for (wallet, tm) in new_wallets {
+        app.grants.insert(wallet, tm);
+        // insert wallets that are missing
+        if let Entry::Vacant(x) = app.wallets_events.entry(wallet) {
+            x.insert(vec![]);
+            let w = client::state::Wallet {
+                delegated: BTreeMap::new(),
+                address: wallet,
+                created_at: tm,
+                ..client::state::Wallet::default()
+            };
+            app.wallets.insert(wallet, w);
+        }
+    }
+}
+
+// whether both are the same event in the same block
+fn same_event(a: &OnChainEvent, b: &OnChainEvent) -> bool {
+    a.tx == b.tx && a.log_index == b.log_index && a.block_number == b.block_number
 }
 
 impl State {
-    pub fn new(subscribers: Subscribers, chain_id: u64) -> Self {
+    pub fn new(chain_id: u64) -> Self {
         Self {
-            subscribers,
             verbose: false,
+            watching: false,
             loading: true,
             app: AppState::new(chain_id),
+            journal: journal::Journal::new(reader::REORG_DEPTH),
+            pending: vec![],
+            checkpoints: None,
+            history: history::History::default(),
+        }
+    }
+
+    // the block that is saved with the state. While watching, the recent blocks
+    // could be reorganized, so only the blocks behind the reorg depth are saved
+    fn final_block(&self, last_block: u64) -> Option<u64> {
+        if self.watching {
+            last_block.checked_sub(self.journal.depth)
+        } else {
+            Some(last_block)
+        }
+    }
+
+    // saves the state on shutdown. Events of the last block could be applied partially
+    // while watching, the state behind the reorg depth doesn't have them.
+    // When no watched block is behind it, the state is saved as it was loaded
+    pub fn checkpoint(&mut self) {
+        let (journal, current) = (&self.journal, &self.app);
+        let behind = self
+            .final_block(current.last_block)
+            .and_then(|block_number| {
+                Some((state_at(journal, current, block_number)?, block_number))
+            });
+        let (app, block_number) = match behind {
+            Some(x) => x,
+            None => {
+                let app = journal.before(0).unwrap_or(current);
+                (app, app.last_block)
+            }
+        };
+        if let Some(checkpoints) = &mut self.checkpoints {
+            if let Err(e) = checkpoints.save(app, block_number) {
+                tracing::warn!("checkpoint failure at {}: {}", block_number, e);
+            }
         }
     }
-}
 
-impl reader::EventHandler for State {
-    fn on(&mut self, e: OnChainEvent, log: web3::types::Log) -> () {
+    pub fn commit(&mut self, e: OnChainEvent, log: web3::types::Log) {
+        if e.block_number > self.app.last_block {
+            // all events of the previous blocks are applied
+            if let Some(block_number) = self.final_block(e.block_number - 1) {
+                let app = state_at(&self.journal, &self.app, block_number);
+                if let (Some(checkpoints), Some(app)) = (&mut self.checkpoints, app) {
+                    checkpoints.on_block(app, block_number);
+                }
+            }
+        }
+        if let Some(checkpoints) = &mut self.checkpoints {
+            checkpoints.on_event(&e);
+        }
         if self.verbose {
-            // it becomes verbose in watching mode
             tracing::info!("{}", serde_json::to_string(&e).unwrap());
         }
-        self.app.update(e.clone(), log);
-        if self.verbose {
-            futures::executor::block_on(async {
-                let list = self.subscribers.read().await;
-                // tracing::info!("sending to {:?} subscribers", list.len());
-                // broadcasting event to all subscribers
-                for (&subscriber_id, tx) in list.iter() {
-                    let json_msg = serde_json::to_string(&e).unwrap();
-                    tracing::debug!("<sent to #{}> {}", subscriber_id, json_msg);
-                    if let Err(err) = tx.send(Ok(Message::text(json_msg))) {
-                        tracing::warn!("<disconnected #{}> {}", subscriber_id, err);
-                    }
-                }
-            });
+        if self.watching {
+            self.journal.record(&self.app, &e);
+        }
+        self.history.record(&self.app, &e, &log);
+        self.pending.retain(|p| !same_event(p, &e));
+        self.app.update(e, log);
+    }
+
+    pub fn rollback(&mut self, block_number: u64) {
+        self.history.rollback(block_number);
+        let (before, retracted) = match self.journal.rollback(block_number) {
+            Some(x) => x,
+            None => return,
+        };
+        tracing::warn!(
+            "{} events retracted after block {}",
+            retracted.len(),
+            block_number
+        );
+        // contract readings are not a part of the journal, they are kept as they are
+        let mut app = before;
+        app.pool_info = self.app.pool_info.take();
+        app.circulation = self.app.circulation.take();
+        app.treasuries = std::mem::take(&mut self.app.treasuries);
+        self.app = app;
+    }
+}
+
+// the state is locked only for the time of applying one event
+#[async_trait]
+impl reader::EventHandler for Arc<Mutex<State>> {
+    async fn on(&mut self, e: OnChainEvent, log: web3::types::Log) {
+        self.lock().unwrap().commit(e, log);
+    }
+
+    async fn pending(&mut self, events: Vec<OnChainEvent>) {
+        self.lock().unwrap().pending = events;
+    }
+
+    async fn rollback(&mut self, block_number: u64) {
+        self.lock().unwrap().rollback(block_number);
+    }
+}
+
+// applies the events to the state while watching and reads the tokens that the new votings
+// and transfers meet, so their amounts and actions get the decimals
+pub struct WithTokens {
+    state: Arc<Mutex<State>>,
+    registry: tokens::Registry,
+    web3: web3::Web3<reader::AnyTransport>,
+}
+
+#[async_trait]
+impl reader::EventHandler for WithTokens {
+    async fn on(&mut self, e: OnChainEvent, log: web3::types::Log) {
+        let to_read = {
+            let mut s = self.state.lock().unwrap();
+            s.commit(e, log);
+            s.app.tokens_to_read()
+        };
+        let mut found = false;
+        for address in to_read {
+            found |= self.registry.read(&self.web3, address).await.is_some();
+        }
+        if found {
+            let mut s = self.state.lock().unwrap();
+            self.registry.add_to(&mut s.app);
+            set_actions(&mut s.app);
+        }
+    }
+
+    async fn pending(&mut self, events: Vec<OnChainEvent>) {
+        self.state.pending(events).await;
+    }
+
+    async fn rollback(&mut self, block_number: u64) {
+        self.state.rollback(block_number).await;
+    }
+}
+
+// sends the events to websocket subscribers, with their status
+pub struct Broadcast {
+    subscribers: Subscribers,
+    pending: Vec<OnChainEvent>,
+    // events of the blocks that could be reorganized
+    recent: Vec<OnChainEvent>,
+}
+
+impl Broadcast {
+    pub fn new(subscribers: Subscribers) -> Self {
+        Self {
+            subscribers,
+            pending: vec![],
+            recent: vec![],
+        }
+    }
+
+    async fn send(&self, msg: &WsMessage) {
+        let list = self.subscribers.read().await;
+        let json_msg = serde_json::to_string(msg).unwrap();
+        for (&subscriber_id, tx) in list.iter() {
+            tracing::debug!("<sent to #{}> {}", subscriber_id, json_msg);
+            if let Err(err) = tx.send(Ok(Message::text(json_msg.clone()))) {
+                tracing::warn!("<disconnected #{}> {}", subscriber_id, err);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl reader::EventHandler for Broadcast {
+    async fn on(&mut self, e: OnChainEvent, _: web3::types::Log) {
+        self.pending.retain(|p| !same_event(p, &e));
+        let depth = reader::REORG_DEPTH;
+        self.recent
+            .retain(|r| r.block_number + depth >= e.block_number);
+        self.recent.push(e.clone());
+        self.send(&WsMessage::Committed(e)).await;
+    }
+
+    async fn pending(&mut self, events: Vec<OnChainEvent>) {
+        // pending events that are gone without being committed
+        for e in &self.pending {
+            if !events.iter().any(|x| same_event(x, e)) {
+                self.send(&WsMessage::Retracted(e.clone())).await;
+            }
+        }
+        for e in &events {
+            if !self.pending.iter().any(|x| same_event(x, e)) {
+                self.send(&WsMessage::Pending(e.clone())).await;
+            }
+        }
+        self.pending = events;
+    }
+
+    async fn rollback(&mut self, block_number: u64) {
+        let at = self
+            .recent
+            .partition_point(|e| e.block_number <= block_number);
+        for e in self.recent.split_off(at).into_iter().rev() {
+            self.send(&WsMessage::Retracted(e)).await;
         }
     }
 }
@@ -121,134 +374,234 @@ async fn ws_disconnected(subscriber_id: usize, subscribers: &Subscribers) {
     tracing::info!("disconnected {}, {} online", subscriber_id, s.len());
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = match args::parse() {
-        Ok(x) => x,
-        Err(e) => return Err(anyhow::Error::msg(format!("Args parsing error {}", e))),
-    };
-    let addr_pool = H160::from_str(args.address_api3_pool.as_str()).expect("ADDR_API3_POOL");
-    let addr_token = H160::from_str(args.address_api3_token.as_str()).expect("ADDR_API3_TOKEN");
-    let addr_usdc_token =
-        H160::from_str(args.address_usdc_token.as_str()).expect("ADDR_USDC_TOKEN");
-    let addr_convenience =
-        H160::from_str(args.address_convenience.as_str()).expect("ADDR_API3_CONVENIENCE");
-    let addr_voting1 =
-        H160::from_str(args.address_voting1.as_str()).expect("ADDR_API3_VOTING_PRIMARY");
-    let addr_agent1 =
-        H160::from_str(args.address_agent1.as_str()).expect("ADDR_API3_AGENT_PRIMARY");
-    let addr_voting2 =
-        H160::from_str(args.address_voting2.as_str()).expect("ADDR_API3_VOTING_SECONDARY");
-    let addr_agent2 =
-        H160::from_str(args.address_agent2.as_str()).expect("ADDR_API3_AGENT_SECONDARY");
-
-    let mut treasury_tokens: BTreeMap<String, H160> = BTreeMap::new();
-    treasury_tokens.insert("USDC".into(), addr_usdc_token);
-    treasury_tokens.insert("API3".into(), addr_token);
-
-    if let Some(_) = args.rpc_endpoint.find("http://") {
-        return Err(anyhow::Error::msg(
-            "only IPC endpoint is allowed. No real time events tracking with HTTP",
-        ));
-    } else if let Some(_) = args.rpc_endpoint.find("https://") {
-        return Err(anyhow::Error::msg(
-            "only IPC endpoint is allowed. No real time events tracking with HTTPS",
-        ));
-    }
-    if !Path::new(args.rpc_endpoint.as_str()).exists() {
-        return Err(anyhow::Error::msg("IPC file doesn't exists"));
-    }
-    let transport = web3::transports::Ipc::new(args.rpc_endpoint.as_str())
-        .await
-        .expect("Failed to connect to IPC");
-    let web3 = web3::Web3::new(transport);
-    let chain_id = web3.eth().chain_id().await?.as_u64();
-
-    let mut addresses = vec![addr_pool, addr_convenience];
-    if let Some(address_api3_circulation) = args
-        .address_api3_circulation
-        .map(|x| H160::from_str(&x).expect("ADDR_API3_CIRCULATION"))
-    {
-        addresses.push(address_api3_circulation);
-    }
-
-    let mut scanner = reader::Scanner::new(
-        chain_id,
-        args.cache_dir.as_str(),
-        vec![addr_voting1, addr_agent1],
-        vec![addr_voting2, addr_agent2],
-        addresses,
-        args.genesis_block,
-        args.max_block,
-        args.rpc_batch_size,
-    );
+// tracked DAO with its own chain, contracts, cache and state
+pub struct Instance {
+    /// name of the instance that prefixes its routes, empty when it is the only one
+    pub name: String,
+    pub args: args::Args,
+    pub config: config::ChainConfig,
+    pub web3: web3::Web3<reader::AnyTransport>,
+    pub chain_id: u64,
+    pub state: Arc<Mutex<State>>,
+    /// subscribers of the instance websocket
+    pub subscribers: Subscribers,
+    // scanner with the last block it read, watching continues after it
+    scanner: Option<(reader::Scanner, u64)>,
+}
 
-    let socket_addr: std::net::SocketAddr = args.listen.parse().expect("invalid bind to listen");
-    let (tx, rx) = oneshot::channel();
-    // starting a "loading" only server
-    // and do not start if we are in dump-mode
-    let loading_server = match args.dump {
-        None => Some(tokio::spawn(async move {
-            let routes = endpoints::routes_loading();
-            let (_addr, server) = warp::serve(routes.with(warp::trace::request()))
-                .bind_with_graceful_shutdown(socket_addr, async {
-                    rx.await.ok();
-                });
-            server.await
-        })),
-        _ => None,
-    };
+impl Instance {
+    pub async fn connect(name: String, args: args::Args) -> anyhow::Result<Self> {
+        // the settings are checked before connecting to the node
+        let source = config::Source::load(&args)?;
+        let transport = reader::get_transport(args.rpc_endpoint.clone()).await?;
+        let web3 = web3::Web3::new(transport);
+        let chain_id = web3.eth().chain_id().await?.as_u64();
+        let config = source.chain(chain_id)?;
+        if !name.is_empty() {
+            tracing::info!("instance {} is on chain {}", name, chain_id);
+        }
+        let subscribers = Subscribers::default();
+        let state = Arc::new(Mutex::new(State::new(chain_id)));
+        Ok(Self {
+            name,
+            args,
+            config,
+            web3,
+            chain_id,
+            state,
+            subscribers,
+            scanner: None,
+        })
+    }
 
-    if let Some(mode) = &args.dump {
-        match mode {
-            DumpMode::Unknown => {
+    // scanner from the block, with the apps that were learned before it
+    fn scanner(
+        &self,
+        genesis_block: u64,
+        resumed: Option<&checkpoint::Checkpoint>,
+    ) -> anyhow::Result<reader::Scanner> {
+        let c = &self.config.contracts;
+        let (primary, secondary) = c.apps().unwrap_or_default();
+        let mut scanner = reader::Scanner::new(
+            self.chain_id,
+            reader::ScannerOptions {
+                cache_dir: self.args.cache_dir.clone(),
+                addr_primary: primary,
+                addr_secondary: secondary,
+                addr: self.config.addresses(),
+                genesis_block,
+                max_block: self.args.max_block,
+                batch_size: self.args.rpc_batch_size,
+                confirmations: self.args.confirmations,
+                concurrency: self.args.rpc_concurrency,
+            },
+        )?;
+        if c.apps().is_none() {
+            // the apps could be set before the checkpoint, they are kept in it
+            scanner.set_apps_from(self.config.genesis_block);
+            if let Some(c) = resumed {
+                scanner.resume_apps(&c.apps, c.block_number);
+            }
+        }
+        scanner.set_versions(self.config.versions.clone());
+        Ok(scanner)
+    }
+
+    // tokens of the settings and of the cache, the others are read from their contracts
+    fn registry(&self) -> tokens::Registry {
+        let mut registry = tokens::Registry::new(self.args.cache_dir.as_str(), self.chain_id);
+        registry.configure(&self.config.tokens);
+        registry
+    }
+
+    // contracts which logs are cached, with the apps that are discovered on the chain.
+    // The bundle is imported with its contracts, when they include the configured ones
+    async fn watched(&self, mode: &args::CacheMode) -> anyhow::Result<Vec<H160>> {
+        if self.config.contracts.apps().is_some() {
+            return Ok(self.config.watched());
+        }
+        if let (args::CacheMode::Import, Some(bundle)) = (mode, &self.args.cache_bundle) {
+            let manifest = bundle::manifest(std::path::Path::new(bundle))?;
+            let configured = self.config.addresses();
+            if configured.iter().all(|a| manifest.addresses.contains(a)) {
+                return Ok(manifest.addresses);
+            }
+            return Ok(configured);
+        }
+        // the apps are searched without the cache, one batch at a time
+        let mut scanner = reader::Scanner::new(
+            self.chain_id,
+            reader::ScannerOptions {
+                addr: self.config.addresses(),
+                genesis_block: self.config.genesis_block,
+                batch_size: self.args.rpc_batch_size,
+                ..Default::default()
+            },
+        )?;
+        // the apps that were learned before the newest checkpoint are not searched again
+        let checkpoints = checkpoint::Checkpoints::new(
+            self.args.cache_dir.as_str(),
+            self.chain_id,
+            &self.config.watched(),
+            0,
+        );
+        if let Some(c) = checkpoints.newest(self.chain_id) {
+            scanner.resume_apps(&c.apps, c.block_number);
+        }
+        let head = self.web3.eth().block_number().await?.as_u64();
+        let head = self.args.max_block.map_or(head, |x| std::cmp::min(x, head));
+        scanner.discover_apps(&self.web3, head).await?;
+        Ok(scanner.watched().to_vec())
+    }
+
+    // checks the cache or dumps the history instead of serving it
+    pub async fn run_offline(&self) -> anyhow::Result<()> {
+        if let Some(mode) = &self.args.cache {
+            // the cache is checked before the scanner opens it, which cuts the broken tail
+            let cache_dir = self.args.cache_dir.as_str();
+            let bundle = self.args.cache_bundle.as_deref();
+            return store::run(
+                mode,
+                cache_dir,
+                self.chain_id,
+                &self.watched(mode).await?,
+                self.config.genesis_block,
+                self.args.max_block,
+                bundle,
+            );
+        }
+        // dump modes read the whole history
+        let mut scanner = self.scanner(self.config.genesis_block, None)?;
+        match self.args.dump {
+            Some(DumpMode::Unknown) => {
                 let mut dumper = dumper::Unknown::new();
-                scanner.scan(&web3, &mut dumper).await?;
+                scanner.scan(&self.web3, &mut dumper).await?;
                 dumper.done();
+                for (l, e) in scanner.failures() {
+                    tracing::warn!("{:?} is not decoded: {}", l.transaction_hash, e);
+                }
             }
-            DumpMode::Events => {
+            Some(DumpMode::Events) => {
                 let mut dumper = dumper::Events::new();
-                scanner.scan(&web3, &mut dumper).await?;
+                scanner.scan(&self.web3, &mut dumper).await?;
+            }
+            Some(DumpMode::Raw) => {
+                let dumper = Arc::new(Mutex::new(dumper::Raw::new(
+                    std::io::stdout(),
+                    self.chain_id,
+                )));
+                scanner.set_recorder(dumper.clone());
+                let last_block = scanner.scan(&self.web3, &mut dumper::Ignore).await?;
+                dumper.lock().unwrap().done(last_block);
             }
+            None => {}
         };
-        std::process::exit(0);
+        Ok(())
     }
 
-    let addr_circulation: Option<H160> = args
-        .address_circulation
-        .map(|x| H160::from_str(x.as_str()).expect("ADDR_API3_CIRCULATION"));
-
-    // Keep track of all connected users, key is usize, value
-    // is a websocket sender.
-    let subscribers = Subscribers::default();
-    let server_state = State::new(subscribers.clone(), chain_id);
-    let state = Arc::new(Mutex::new(server_state));
-
-    let mut treasury_wallets: BTreeMap<String, H160> = BTreeMap::new();
-    treasury_wallets.insert("Primary Treasury".into(), addr_agent1);
-    treasury_wallets.insert("Secondary Treasury".into(), addr_agent2);
+    // reads the history up to the last block, with the contracts state and ENS names
+    pub async fn load(&mut self) -> anyhow::Result<()> {
+        let args = &self.args;
+        let c = self.config.contracts.clone();
+        let web3 = &self.web3;
+        let mut checkpoints = checkpoint::Checkpoints::new(
+            args.cache_dir.as_str(),
+            self.chain_id,
+            &self.config.watched(),
+            args.checkpoint_blocks,
+        );
+        let resumed = checkpoints.load(self.chain_id);
+        let genesis_block = match &resumed {
+            Some(c) => std::cmp::max(c.block_number + 1, self.config.genesis_block),
+            None => self.config.genesis_block,
+        };
+        let mut scanner = self.scanner(genesis_block, resumed.as_ref())?;
+        let is_resumed = resumed.is_some();
+        {
+            let mut s = self.state.lock().unwrap();
+            if let Some(c) = resumed {
+                s.app = c.app;
+            }
+            s.app.decimals.extend(self.config.decimals());
+            s.app.explorer = self.config.explorer.clone();
+            s.history = history::History::new(&s.app, args.history_events);
+            s.checkpoints = Some(checkpoints);
+        }
 
-    // Turn our "state" into a new Filter...
-    let subscribers = warp::any().map(move || subscribers.clone());
-    let last_block = {
-        let rc = state.clone();
-        let last_block = scanner.scan(&web3, &mut *rc.lock().unwrap()).await?;
+        let rc = self.state.clone();
+        let last_block = scanner.scan(web3, &mut rc.clone()).await?;
+        if is_resumed {
+            // the history goes back to the genesis, not only to the checkpoint.
+            // The events before it are read from the cache on the first query for them
+            let genesis_block = self.config.genesis_block;
+            rc.lock()
+                .unwrap()
+                .history
+                .set_earlier(scanner.clone(), genesis_block);
+        }
+        let apps = scanner.dao_apps().cloned();
+        let mut treasury_wallets = self.config.treasury_wallets();
+        if let (true, Some(apps)) = (self.config.treasuries.is_empty(), &apps) {
+            for t in config::treasury_wallets(&apps.primary, &apps.secondary) {
+                treasury_wallets.insert(t.name, t.address);
+            }
+        }
         let mut s = rc.lock().unwrap();
         tracing::info!(
             "found: {} wallets, {} votings",
             s.app.wallets.len(),
             s.app.votings.len()
         );
-        s.app.pool_info = crate::contracts::Pool::new(&web3, addr_pool).read().await;
+        s.app.pool_info = crate::contracts::Pool::new(web3, c.pool).read().await;
         tracing::info!("pool info {:?}", s.app.pool_info);
-        if let Some(addr_supply) = addr_circulation {
+        if let (Some(addr_supply), Some(apps)) = (c.circulation, &apps) {
             s.app.circulation = crate::contracts::Supply::new(
-                &web3,
+                web3,
                 addr_supply,
-                addr_token,
-                addr_convenience,
-                addr_voting1,
-                addr_voting2,
+                c.token,
+                c.convenience,
+                apps.primary[0],
+                apps.secondary[0],
             )
             .read()
             .await;
@@ -258,13 +611,8 @@ async fn main() -> anyhow::Result<()> {
             }
         }
 
-        s.app.treasuries =
-            crate::treasury::read_treasuries(&web3, &treasury_tokens, &treasury_wallets).await;
-        tracing::info!("treasuries {:?}", s.app.treasuries);
-
         // re-read votings and extract static data for votes
-        let conv = crate::contracts::Convenience::new(&web3, addr_convenience);
-        let mut new_wallets: BTreeMap<H160, u64> = BTreeMap::new();
+        let conv = crate::contracts::Convenience::new(web3, c.convenience);
         for (_, v) in &mut s.app.votings {
             if let None = v.details {
                 let static_data = conv
@@ -273,72 +621,102 @@ async fn main() -> anyhow::Result<()> {
                 println!("voting_static_data = {:?}", static_data);
                 if let Some(data) = static_data {
                     v.votes_total = data.voting_power; // adjust with precise #
-                    let details = data.into_details();
-                    if let Some(action) = &details.action {
-                        if let Some(wallet) = action.wallet {
-                            new_wallets.insert(wallet.clone(), v.tm);
-                        }
-                    }
-                    v.details = Some(details);
+                    v.details = Some(data.into_details(&BTreeMap::new()));
                 }
             }
         }
-        for (wallet, tm) in new_wallets {
-            s.app.grants.insert(wallet, tm);
-            // insert wallets that are missing
-            if let None = s.app.wallets_events.get(&wallet) {
-                s.app.wallets_events.insert(wallet.clone(), vec![]);
-                let mut w = client::state::Wallet::default();
-                w.delegated = BTreeMap::new();
-                w.address = wallet.clone();
-                w.created_at = tm;
-                s.app.wallets.insert(wallet.clone(), w);
+        // tokens of the scripts and transfers, then actions are made with their decimals
+        self.registry().update(web3, &mut s.app).await;
+        set_actions(&mut s.app);
+
+        let treasury_tokens = s.app.token_addresses();
+        s.app.treasuries =
+            crate::treasury::read_treasuries(web3, &treasury_tokens, &treasury_wallets).await;
+        tracing::info!("treasuries {:?}", s.app.treasuries);
+
+        if replay::is_replay(&args.rpc_endpoint) {
+            tracing::info!("ENS names are not resolved in the replay");
+        } else if !args.no_ens {
+            let ens = crate::ens::ENS::new(web3.clone(), args.cache_dir.as_str());
+            // names that were resolved before the checkpoint are kept
+            for (addr, w) in s.app.wallets.iter_mut().filter(|(_, w)| w.ens.is_none()) {
+                if let Some(name) = ens.name(addr.clone()).await {
+                    tracing::info!("ENS for {:?} is {:?}", addr, name);
+                    w.ens = Some(name);
+                };
             }
+            tracing::info!("done with ENS");
         }
-        last_block
-    };
-    if !args.no_ens {
-        let ens = crate::ens::ENS::new(web3.clone(), args.cache_dir.as_str());
-        let rc = state.clone();
-        let mut s = rc.lock().unwrap();
-        for (addr, w) in &mut s.app.wallets {
-            if let Some(name) = ens.name(addr.clone()).await {
-                tracing::info!("ENS for {:?} is {:?}", addr, name);
-                w.ens = Some(name);
-            };
-        }
-        tracing::info!("done with ENS");
+        drop(s);
+        self.scanner = Some((scanner, last_block));
+        Ok(())
     }
 
-    loading_server.map(|server| {
-        tracing::info!("Killing temporary HTTP server");
-        let _ = tx.send(());
-        std::thread::sleep(std::time::Duration::from_secs(3)); // wait for server to shutdown
-        server.abort();
-    });
-
-    if args.watch {
-        let w3 = web3.clone();
-        let rc = state.clone();
-        rc.lock().unwrap().verbose = true;
-        let rc = state.clone();
+    // continues listening to the blockchain events in the background
+    pub fn watch(&mut self) {
+        let (mut scanner, last_block) = match self.scanner.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let apps = scanner.dao_apps().cloned();
+        let web3 = self.web3.clone();
+        let w3 = self.web3.clone();
+        {
+            let mut s = self.state.lock().unwrap();
+            s.verbose = true;
+            s.watching = true;
+        }
+        // the state must get every event, while websocket subscribers could miss some
+        let handler = sink::FanOut::new()
+            .with(sink::Sink::spawn(
+                "state",
+                sink::QUEUE_SIZE,
+                sink::Overflow::Wait,
+                WithTokens {
+                    state: self.state.clone(),
+                    registry: self.registry(),
+                    web3: self.web3.clone(),
+                },
+            ))
+            .with(sink::Sink::spawn(
+                "websocket",
+                sink::QUEUE_SIZE,
+                sink::Overflow::Drop,
+                Broadcast::new(self.subscribers.clone()),
+            ));
+        let rpc_endpoint = self.args.rpc_endpoint.clone();
+        let poll_interval = std::time::Duration::from_secs(self.args.rpc_poll_interval);
+        let reconnect_delay = std::time::Duration::from_secs(self.args.rpc_reconnect_delay);
         tokio::spawn(async move {
-            scanner.watch_ipc(&web3, last_block, rc).await.unwrap();
+            // the recording is polled as a node that has no new blocks
+            let res = if reader::is_http(&rpc_endpoint) || replay::is_replay(&rpc_endpoint) {
+                scanner
+                    .watch_http(&web3, last_block, handler, poll_interval)
+                    .await
+            } else {
+                scanner
+                    .watch_subscription(&rpc_endpoint, last_block, handler, reconnect_delay)
+                    .await
+            };
+            if let Err(e) = res {
+                tracing::error!("watching failure: {}", e);
+            }
         });
 
         // one more thread fto update ppol and circulation hourly
-        if let Some(addr_supply) = addr_circulation {
-            let rc = state.clone();
+        let c = self.config.contracts.clone();
+        if let (Some(addr_supply), Some(apps)) = (c.circulation, apps) {
+            let rc = self.state.clone();
             tokio::spawn(async move {
                 let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
-                let contract_pool = crate::contracts::Pool::new(&w3, addr_pool.clone());
+                let contract_pool = crate::contracts::Pool::new(&w3, c.pool);
                 let contract_circulation = crate::contracts::Supply::new(
                     &w3,
                     addr_supply,
-                    addr_token,
-                    addr_convenience,
-                    addr_voting1,
-                    addr_voting2,
+                    c.token,
+                    c.convenience,
+                    apps.primary[0],
+                    apps.secondary[0],
                 );
                 loop {
                     interval.tick().await; // wait an hour
@@ -359,21 +737,203 @@ async fn main() -> anyhow::Result<()> {
                 }
             });
         }
+    }
+
+    // API, pages and websocket of the instance, under its name
+    pub fn routes(&self, static_dir: &str, watch: bool) -> BoxedFilter<(Box<dyn warp::Reply>,)> {
+        let routes =
+            endpoints::routes(static_dir.to_owned(), self.name.clone(), self.state.clone())
+                .map(|r| Box::new(r) as Box<dyn warp::Reply>);
+        let routes = if watch {
+            let subscribers = self.subscribers.clone();
+            let subscribers = warp::any().map(move || subscribers.clone());
+            let chat = warp::path("ws").and(warp::ws()).and(subscribers).map(
+                |ws: warp::ws::Ws, subscribers| {
+                    let reply = ws.on_upgrade(move |socket| ws_connected(socket, subscribers));
+                    Box::new(reply) as Box<dyn warp::Reply>
+                },
+            );
+            routes.or(chat).unify().boxed()
+        } else {
+            routes.boxed()
+        };
+        if self.name.is_empty() {
+            routes
+        } else {
+            warp::path(self.name.clone()).and(routes).boxed()
+        }
+    }
+}
 
-        let chat = warp::path("ws").and(warp::ws()).and(subscribers).map(
-            |ws: warp::ws::Ws, subscribers| {
-                ws.on_upgrade(move |socket| ws_connected(socket, subscribers))
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = match args::parse() {
+        Ok(x) => x,
+        Err(e) => return Err(anyhow::Error::msg(format!("Args parsing error {}", e))),
+    };
+    let mut instances = vec![];
+    for (name, instance_args) in args::instances(&args)? {
+        instances.push(Instance::connect(name, instance_args).await?);
+    }
+
+    if args.cache.is_some() || args.dump.is_some() {
+        for instance in &instances {
+            if let Err(e) = instance.run_offline().await {
+                tracing::error!("{:?} {:?}: {}", args.cache, args.dump, e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    let socket_addr: std::net::SocketAddr = args.listen.parse().expect("invalid bind to listen");
+    let (tx, rx) = oneshot::channel();
+    // starting a "loading" only server
+    let loading_server = tokio::spawn(async move {
+        let routes = endpoints::routes_loading();
+        let (_addr, server) = warp::serve(routes.with(warp::trace::request()))
+            .bind_with_graceful_shutdown(socket_addr, async {
+                rx.await.ok();
+            });
+        server.await
+    });
+
+    futures::future::try_join_all(instances.iter_mut().map(|x| x.load())).await?;
+
+    tracing::info!("Killing temporary HTTP server");
+    let _ = tx.send(());
+    std::thread::sleep(std::time::Duration::from_secs(3)); // wait for server to shutdown
+    loading_server.abort();
+
+    if args.watch {
+        for instance in &mut instances {
+            instance.watch();
+        }
+    }
+    let mut routes = instances[0].routes(&args.static_dir, args.watch);
+    for instance in &instances[1..] {
+        routes = routes
+            .or(instance.routes(&args.static_dir, args.watch))
+            .unify()
+            .boxed();
+    }
+    if instances.len() > 1 {
+        // the liveness of the process and the client files outside of the instances
+        let liveness = warp::path!("_liveness").map(|| "# API3 DAO Tracker");
+        let root = liveness
+            .or(warp::fs::dir(args.static_dir.clone()))
+            .map(|r| Box::new(r) as Box<dyn warp::Reply>);
+        routes = routes.or(root).unify().boxed();
+    }
+    warp::serve(routes.with(warp::trace::request()))
+        .bind_with_graceful_shutdown(socket_addr, shutdown_signal())
+        .1
+        .await;
+    for instance in &instances {
+        instance.state.lock().unwrap().checkpoint();
+    }
+    Ok(())
+}
+
+// resolves when the process is asked to stop
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    };
+    tracing::info!("shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::events::Api3;
+    use web3::types::{H256, U256};
+
+    fn deposited(block_number: u64) -> (OnChainEvent, web3::types::Log) {
+        let e = OnChainEvent {
+            entry: Api3::Deposited {
+                user: H160::from_low_u64_be(1),
+                amount: U256::from(100),
+                user_unstaked: U256::zero(),
             },
+            tm: 1_600_000_000 + block_number,
+            block_number,
+            tx: H256::from_low_u64_be(block_number),
+            log_index: 0,
+            version: None,
+        };
+        let mut l = testrpc::log(H160::zero(), vec![], vec![]);
+        l.block_number = Some(block_number.into());
+        (e, l)
+    }
+
+    #[test]
+    fn it_saves_the_checkpoint_after_watching() {
+        let dir = testrpc::TempDir::new("watch");
+        let cache_dir = dir.to_str().unwrap();
+        let addresses = vec![H160::from_low_u64_be(1)];
+        let checkpoints = || checkpoint::Checkpoints::new(cache_dir, 1, &addresses, 0);
+
+        let mut s = State::new(1);
+        s.checkpoints = Some(checkpoints());
+        let (e, l) = deposited(10);
+        s.commit(e, l);
+        // nothing was watched, the loaded state is saved
+        s.watching = true;
+        s.checkpoint();
+        let loaded = checkpoints().newest(1).map(|c| c.block_number);
+
+        let depth = reader::REORG_DEPTH;
+        for block_number in (20..20 + 3 * depth).step_by(7) {
+            let (e, l) = deposited(block_number);
+            s.commit(e, l);
+        }
+        let last_block = s.app.last_block;
+        s.checkpoint();
+        let watched = checkpoints().newest(1);
+        assert_eq!(loaded, Some(10));
+        let c = watched.unwrap();
+        assert_eq!(c.block_number, last_block - depth);
+        assert!(c.app.last_block <= last_block - depth);
+        assert!(c.app.last_block > last_block - depth - 7);
+    }
+
+    #[tokio::test]
+    async fn it_reads_the_tokens_of_new_transfers_while_watching() {
+        use hex_literal::hex;
+        use reader::EventHandler;
+        use web3::ethabi::{encode, Token};
+
+        let dai = H160::from_low_u64_be(0xda);
+        let chain = Arc::new(Mutex::new(testrpc::TestChain::new(1)));
+        chain.lock().unwrap().calls.insert(
+            (dai, hex!("313ce567").to_vec()),
+            encode(&[Token::Uint(18.into())]),
         );
-        let routes = endpoints::routes(args.static_dir.clone(), state).or(chat);
-        warp::serve(routes.with(warp::trace::request()))
-            .run(socket_addr)
-            .await;
-    } else {
-        let routes = endpoints::routes(args.static_dir.clone(), state);
-        warp::serve(routes.with(warp::trace::request()))
-            .run(socket_addr)
-            .await;
+        let web3 = web3::Web3::new(
+            reader::get_transport(testrpc::serve(chain.clone()))
+                .await
+                .unwrap(),
+        );
+        let state = Arc::new(Mutex::new(State::new(1)));
+        let mut handler = WithTokens {
+            state: state.clone(),
+            registry: tokens::Registry::new("", 1),
+            web3,
+        };
+        let (mut e, l) = deposited(10);
+        e.entry = Api3::VaultTransfer {
+            agent: client::events::VotingAgent::Primary,
+            token: dai,
+            to: H160::from_low_u64_be(0xb1),
+            amount: U256::from(5),
+        };
+        handler.on(e, l).await;
+        let app = &state.lock().unwrap().app;
+        assert_eq!(app.tokens[&dai].decimals, 18);
+        assert!(app.tokens_to_read().is_empty());
     }
-    Ok(())
 }
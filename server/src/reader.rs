@@ -1,355 +1,1161 @@
+use crate::config::ContractVersion;
+use crate::replay::{is_replay, Replay};
+use crate::rpc;
+use crate::store::Store;
+use async_trait::async_trait;
 use client::events::{Api3, VotingAgent};
+use client::logreader::EventParseError;
 use client::state::OnChainEvent;
-use crc32fast::Hasher;
 use futures::StreamExt;
-use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::Read;
+use hex_literal::hex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::debug;
-use web3::api::Eth;
-use web3::transports::{Either, Http, Ipc};
-use web3::types::{BlockId, FilterBuilder, Log, H160, H256};
-use web3::{Transport, Web3};
+use web3::transports::{Batch, Either, Http, Ipc, WebSocket};
+use web3::types::{Block, BlockId, BlockNumber, FilterBuilder, Log, H160, H256};
+use web3::{BatchTransport, DuplexTransport, Web3};
 
-pub trait EventHandler {
-    fn on(&mut self, entry: OnChainEvent, l: Log) -> ();
+// how many recent blocks are tracked for chain reorganizations
+pub const REORG_DEPTH: u64 = 128;
+// how many times the logs query range could grow above the batch size
+pub const MAX_RANGE_GROWTH: u64 = 16;
+// the range is growing only when it had less logs
+pub const QUIET_LOGS: usize = 100;
+// node that doesn't respond in this time is considered failed
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(60);
+// max number of calls in one JSON-RPC batch request
+pub const MAX_BATCH_CALLS: usize = 100;
+// number of the logs that failed to decode which are kept
+pub const MAX_FAILURES: usize = 1000;
+// topic of the pool event that sets the voting and agent apps of the DAO
+pub const SET_DAO_APPS: [u8; 32] =
+    hex!("71b1ce304e98c2a645f0c32f4c9e3ae4d5dbe6717a8c17ccefb0083635afdc15");
+
+#[async_trait]
+pub trait EventHandler: Send {
+    async fn on(&mut self, entry: OnChainEvent, l: Log);
+    // undo the events of the blocks after `block_number`, as they left the chain
+    async fn rollback(&mut self, _block_number: u64) {}
+    // events that are waiting for confirmations, replacing the previous ones
+    async fn pending(&mut self, _events: Vec<OnChainEvent>) {}
 }
 
-pub async fn get_transport(source: String) -> Either<Http, Ipc> {
-    if source.contains(".ipc") {
-        let transport = Ipc::new(source.as_str())
-            .await
-            .expect("Failed to connect to IPC file");
+// receives every log that was read, with the timestamp of its block, before it is decoded
+pub trait LogRecorder: Send + std::fmt::Debug {
+    fn record(&mut self, l: &Log, tm: u64);
+}
+
+pub fn is_http(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+pub fn is_ws(source: &str) -> bool {
+    source.starts_with("ws://") || source.starts_with("wss://")
+}
+
+// transport that supports subscriptions: IPC file or WebSocket address
+pub async fn get_duplex_transport(source: &str) -> anyhow::Result<Either<Ipc, WebSocket>> {
+    if is_ws(source) {
+        let transport = WebSocket::new(source).await?;
         debug!("Connected to {:?}", source);
-        Either::Right(transport)
+        Ok(Either::Right(transport))
     } else {
-        let transport = Http::new(source.as_str()).expect("Invalid RPC HTTP endpoint");
+        if !Path::new(source).exists() {
+            return Err(anyhow::Error::msg("IPC file doesn't exists"));
+        }
+        let transport = Ipc::new(source).await?;
+        debug!("Connected to {:?}", source);
+        Ok(Either::Left(transport))
+    }
+}
+
+// any of the sources that the history could be read from
+pub type AnyTransport = Either<Either<Http, Replay>, Either<Ipc, WebSocket>>;
+
+pub async fn get_transport(source: String) -> anyhow::Result<AnyTransport> {
+    if is_replay(&source) {
+        Ok(Either::Left(Either::Right(Replay::new(&source)?)))
+    } else if is_http(&source) {
+        let transport = Http::new(source.as_str())?;
         debug!("Connecting to {:?}", source);
-        Either::Left(transport)
+        Ok(Either::Left(Either::Left(transport)))
+    } else {
+        Ok(Either::Right(get_duplex_transport(&source).await?))
+    }
+}
+
+// all contracts which logs are read, in the order of the cache checksum
+pub fn watched_addresses(
+    addr_primary: &[H160],
+    addr_secondary: &[H160],
+    addr: &[H160],
+) -> Vec<H160> {
+    let mut addr_watched: Vec<H160> = addr.to_vec();
+    addr_watched.extend_from_slice(addr_primary);
+    addr_watched.extend_from_slice(addr_secondary);
+    addr_watched
+}
+
+// voting and agent apps of the DAO, starting from the block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaoApps {
+    pub block_number: u64,
+    /// voting and agent apps
+    pub primary: Vec<H160>,
+    pub secondary: Vec<H160>,
+}
+
+impl DaoApps {
+    pub fn of(block_number: u64, entry: &Api3) -> Option<Self> {
+        match entry {
+            Api3::SetDaoApps {
+                agent_app_primary,
+                agent_app_secondary,
+                voting_app_primary,
+                voting_app_secondary,
+            } => Some(Self {
+                block_number,
+                primary: vec![*voting_app_primary, *agent_app_primary],
+                secondary: vec![*voting_app_secondary, *agent_app_secondary],
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub fn on_chain_event(l: &Log, entry: Api3, tm: u64) -> OnChainEvent {
+    OnChainEvent {
+        block_number: l.block_number.unwrap().as_u64(),
+        tx: l.transaction_hash.unwrap(),
+        log_index: l.log_index.unwrap().as_u64(),
+        entry,
+        tm,
+        version: None,
     }
 }
 
+// position of the log in the chain, used to skip the logs that were already applied.
+// `log_index` of u64::MAX means that the whole block was applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogPosition {
+    pub block_number: u64,
+    pub log_index: u64,
+}
+
+impl LogPosition {
+    pub fn block_end(block_number: u64) -> Self {
+        Self {
+            block_number,
+            log_index: u64::MAX,
+        }
+    }
+
+    pub fn of(l: &Log) -> Option<Self> {
+        Some(Self {
+            block_number: l.block_number?.as_u64(),
+            log_index: l.log_index?.as_u64(),
+        })
+    }
+}
+
+// reads blocks in a single JSON-RPC batch request
+pub async fn read_blocks<T: BatchTransport>(
+    transport: T,
+    hashes: &[H256],
+) -> web3::Result<Vec<Option<Block<H256>>>> {
+    let batch = Web3::new(Batch::new(transport));
+    let calls: Vec<_> = hashes
+        .iter()
+        .map(|hash| batch.eth().block(BlockId::Hash(*hash)))
+        .collect();
+    batch.transport().submit_batch().await?;
+    futures::future::try_join_all(calls).await
+}
+
 #[derive(Clone, Debug)]
 pub struct BlockBatch {
     pub from: u64,
     pub to: u64,
 }
 
-pub async fn get_batches<T: Transport>(
-    eth: Eth<T>,
-    genesis: u64,
-    max: Option<u64>,
-    batch_size: u64,
-) -> Vec<BlockBatch> {
-    let max_block: u64 = match max {
-        Some(x) => x,
-        None => eth
-            .block_number()
-            .await
-            .expect("max block height failure")
-            .as_u64(),
-    };
-    let mut from = genesis;
-    let mut res = vec![];
-    while from <= max_block {
-        let to = if from + batch_size > max_block {
-            max_block
-        } else {
-            from + batch_size - 1
-        };
-        res.push(BlockBatch { from, to });
-        from = from + batch_size
+// logs of the batch that are known events, with timestamps of their blocks
+#[derive(Debug)]
+pub struct FetchedBatch {
+    pub batch: BlockBatch,
+    pub entries: Vec<(Log, Api3)>,
+    // all logs of the batch with their timestamps, when the scanner records them
+    pub raw: Vec<(Log, u64)>,
+    pub logs_count: usize,
+    pub cached: bool,
+    pub elapsed: Duration,
+}
+
+// logs that failed to decode, with the errors, by their positions
+type Failures = BTreeMap<Option<LogPosition>, (Log, EventParseError)>;
+
+// settings of the scanner, besides the chain it reads
+#[derive(Debug, Clone)]
+pub struct ScannerOptions {
+    // folder of the cache, empty to keep nothing
+    pub cache_dir: String,
+    // voting apps that are given instead of being discovered
+    pub addr_primary: Vec<H160>,
+    pub addr_secondary: Vec<H160>,
+    // contracts besides the voting apps
+    pub addr: Vec<H160>,
+    pub genesis_block: u64,
+    pub max_block: Option<u64>,
+    pub batch_size: u64,
+    // events are committed only after this number of blocks on top of them
+    pub confirmations: u64,
+    // number of batches that are read at the same time
+    pub concurrency: usize,
+}
+
+impl Default for ScannerOptions {
+    fn default() -> Self {
+        Self {
+            cache_dir: String::new(),
+            addr_primary: vec![],
+            addr_secondary: vec![],
+            addr: vec![],
+            genesis_block: 0,
+            max_block: None,
+            batch_size: 500,
+            confirmations: 0,
+            concurrency: 1,
+        }
     }
-    res
 }
 
 #[derive(Debug, Clone)]
 pub struct Scanner {
     chain_id: u64,
     cache_dir: String,
+    // logs and timestamps that were read, shared with the copies of the scanner
+    store: Arc<Mutex<Store>>,
+    // whether the store is the one of the watched contracts. It is opened when the apps
+    // are known, so no file is left behind for the contracts without them
+    store_open: bool,
     addr_watched: Vec<H160>,
-    addr_primary: Vec<H160>,
-    addr_secondary: Vec<H160>,
+    // contracts besides the voting apps
+    addr: Vec<H160>,
+    // voting apps that were given or set by the pool, by the block they start from
+    apps: Vec<DaoApps>,
+    // first block that was not searched for SetDaoApps yet, nothing is searched for the given apps
+    apps_from: u64,
+    // block ranges of the contracts that are not read in all blocks
+    versions: Vec<ContractVersion>,
     genesis_block: u64,
     max_block: Option<u64>,
     batch_size: u64,
-    blocks_time: BTreeMap<H256, u64>,
-}
-
-pub fn blockstime_fn(cache_dir: &str, chain_id: u64) -> String {
-    format!("{}/blockstime{}.json", cache_dir, chain_id)
-}
-
-pub fn load_blockstime(cache_dir: &str, chain_id: u64) -> BTreeMap<H256, u64> {
-    if let Ok(mut f) = File::open(blockstime_fn(cache_dir, chain_id)) {
-        let mut data = String::new();
-        match f.read_to_string(&mut data) {
-            Ok(_) => match serde_json::from_str::<BTreeMap<H256, u64>>(&data) {
-                Ok(x) => {
-                    tracing::info!("blockstime cache {} records loaded", x.len());
-                    x
-                }
-                Err(e) => {
-                    tracing::info!("blockstime JSON parsing failure {}", e);
-                    BTreeMap::new()
-                }
-            },
-            Err(_) => BTreeMap::new(),
-        }
-    } else {
-        BTreeMap::new()
-    }
-}
-
-pub fn save_blockstime(
-    cache_dir: &str,
-    chain_id: u64,
-    blocks_time: &BTreeMap<H256, u64>,
-) -> anyhow::Result<()> {
-    if cache_dir.len() == 0 || blocks_time.len() == 0 {
-        return Ok(());
-    }
-    let f =
-        File::create(blockstime_fn(cache_dir, chain_id)).expect("Unable to create blockstime file");
-    serde_json::to_writer(&f, blocks_time)?;
-    Ok(())
+    // events are committed only after this number of blocks on top of them
+    confirmations: u64,
+    // number of batches that are read at the same time
+    concurrency: usize,
+    // size of the next logs query, shared with the copies of the scanner
+    range_size: Arc<rpc::RangeSize>,
+    backoff: rpc::Backoff,
+    timeout: Duration,
+    // hashes of the recent blocks that were applied while watching
+    recent_blocks: BTreeMap<u64, H256>,
+    // logs that failed to decode, shared with the copies of the scanner
+    failures: Arc<Mutex<Failures>>,
+    // receiver of the logs that were read, decoded or not
+    recorder: Option<Arc<Mutex<dyn LogRecorder>>>,
 }
 
 impl Scanner {
-    pub fn new(
-        chain_id: u64,
-        cache_dir: &str,
-        addr_primary: Vec<H160>,
-        addr_secondary: Vec<H160>,
-        addr: Vec<H160>,
-        genesis_block: u64,
-        max_block: Option<u64>,
-        batch_size: u64,
-    ) -> Self {
-        let mut addr_watched: Vec<H160> = addr.clone();
-        addr_primary
-            .iter()
-            .for_each(|x| addr_watched.push(x.clone()));
-        addr_secondary
-            .iter()
-            .for_each(|x| addr_watched.push(x.clone()));
+    pub fn new(chain_id: u64, options: ScannerOptions) -> anyhow::Result<Self> {
+        let ScannerOptions {
+            cache_dir,
+            addr_primary,
+            addr_secondary,
+            addr,
+            genesis_block,
+            max_block,
+            batch_size,
+            confirmations,
+            concurrency,
+        } = options;
+        let addr_watched = watched_addresses(&addr_primary, &addr_secondary, &addr);
 
-        Self {
+        let (apps, apps_from) = if addr_primary.is_empty() && addr_secondary.is_empty() {
+            (vec![], genesis_block)
+        } else {
+            let given = DaoApps {
+                block_number: 0,
+                primary: addr_primary,
+                secondary: addr_secondary,
+            };
+            (vec![given], u64::MAX)
+        };
+        // the apps are discovered when the scan starts, the store is opened after that
+        let store_open = !apps.is_empty();
+        let store = if store_open {
+            Store::open_dir(&cache_dir, chain_id, &addr_watched)?
+        } else {
+            Store::memory()
+        };
+        Ok(Self {
             chain_id,
-            cache_dir: cache_dir.to_owned(),
+            cache_dir,
+            store: Arc::new(Mutex::new(store)),
+            store_open,
             addr_watched,
-            addr_primary,
-            addr_secondary,
+            addr,
+            apps,
+            apps_from,
+            versions: vec![],
             genesis_block,
             max_block,
             batch_size,
-            blocks_time: load_blockstime(&cache_dir, chain_id),
-        }
+            confirmations,
+            concurrency: std::cmp::max(concurrency, 1),
+            range_size: Arc::new(rpc::RangeSize::new(
+                batch_size,
+                batch_size * MAX_RANGE_GROWTH,
+            )),
+            backoff: rpc::Backoff::default(),
+            timeout: RPC_TIMEOUT,
+            recent_blocks: BTreeMap::new(),
+            failures: Arc::new(Mutex::new(BTreeMap::new())),
+            recorder: None,
+        })
     }
-    pub fn agent(&self, address: H160) -> Option<VotingAgent> {
+    // voting app of the address at the block. The apps are known from the block
+    // they were set at and keep their places after they are replaced
+    pub fn agent(&self, address: H160, block_number: u64) -> Option<VotingAgent> {
         let mut v: Option<VotingAgent> = None;
-        if let Some(_) = self.addr_primary.iter().position(|&r| r == address) {
-            v = Some(VotingAgent::Primary);
-        }
-        if let Some(_) = self.addr_secondary.iter().position(|&r| r == address) {
-            v = Some(VotingAgent::Secondary);
+        for apps in self
+            .apps
+            .iter()
+            .take_while(|a| a.block_number <= block_number)
+        {
+            if apps.primary.contains(&address) {
+                v = Some(VotingAgent::Primary);
+            }
+            if apps.secondary.contains(&address) {
+                v = Some(VotingAgent::Secondary);
+            }
         }
         v
     }
 
-    pub fn cache_fn(&self, chain_id: u64, b: &BlockBatch) -> String {
-        let mut hasher = Hasher::new();
-        self.addr_watched.iter().for_each(|a| {
-            hasher.update(format!("{:?}", a).as_bytes());
-        });
-        let checksum = hasher.finalize();
-        format!(
-            "{}/chain{}-{}-{}-{}.json",
-            self.cache_dir, chain_id, b.from, b.to, checksum
-        )
+    // the latest voting apps
+    pub fn dao_apps(&self) -> Option<&DaoApps> {
+        self.apps.last()
+    }
+
+    // every log of the scan is given to the recorder, including those that are not decoded
+    pub fn set_recorder(&mut self, recorder: Arc<Mutex<dyn LogRecorder>>) {
+        self.recorder = Some(recorder);
     }
 
-    pub fn has_logs(&self, chain_id: u64, b: &BlockBatch) -> bool {
-        if self.cache_dir.len() == 0 {
-            return false;
+    // the scan stops at the block, which events are taken as final
+    pub fn stop_at(&mut self, block_number: u64) {
+        self.max_block = Some(block_number);
+        self.confirmations = 0;
+    }
+
+    pub fn watched(&self) -> &[H160] {
+        &self.addr_watched
+    }
+
+    // apps are searched from this block, when the scan starts after the genesis
+    pub fn set_apps_from(&mut self, block_number: u64) {
+        self.apps_from = block_number;
+    }
+
+    // apps that were learned up to the block, i.e. kept in the checkpoint.
+    // The search goes on from the next block
+    pub fn resume_apps(&mut self, apps: &[DaoApps], block_number: u64) {
+        for a in apps.iter().filter(|a| a.block_number <= block_number) {
+            self.add_apps(a.clone());
         }
-        Path::new(self.cache_fn(chain_id, b).as_str()).exists()
+        self.apps_from = std::cmp::max(self.apps_from, block_number + 1);
     }
 
-    pub async fn get_logs(&self, chain_id: u64, b: &BlockBatch) -> anyhow::Result<Vec<Log>> {
-        let mut f = File::open(self.cache_fn(chain_id, &b)).expect("Unable to open file");
-        let mut data = String::new();
-        f.read_to_string(&mut data).expect("Reading failure");
-        let logs: Vec<Log> = serde_json::from_str(&data).expect("JSON parsing failure");
-        Ok(logs)
+    // the cache is bound to the watched contracts, so it is switched to the new ones
+    fn reopen_store(&mut self) -> anyhow::Result<()> {
+        let store = Store::open_dir(&self.cache_dir, self.chain_id, &self.addr_watched)?;
+        self.store = Arc::new(Mutex::new(store));
+        self.store_open = true;
+        Ok(())
     }
 
-    pub async fn save_logs(
-        &self,
-        chain_id: u64,
-        b: &BlockBatch,
-        logs: &Vec<Log>,
+    // decodes the log, skipping the logs of the apps before they were set
+    // and the logs of the contracts out of their block ranges
+    pub fn decode(&self, l: &Log) -> Option<Api3> {
+        let block_number = l.block_number.map_or(u64::MAX, |x| x.as_u64());
+        if !self.is_active(l.address, block_number, block_number) {
+            return None;
+        }
+        let agent = self.agent(l.address, block_number);
+        let is_app = self
+            .apps
+            .iter()
+            .any(|a| a.primary.contains(&l.address) || a.secondary.contains(&l.address));
+        if agent.is_none() && is_app {
+            return None;
+        }
+        match crate::decoder::decode(agent, l) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.record_failure(l, e);
+                None
+            }
+        }
+    }
+
+    // keeps the log that failed to decode, it is skipped instead of stopping the scan
+    fn record_failure(&self, l: &Log, e: EventParseError) {
+        let mut failures = self.failures.lock().unwrap();
+        let pos = LogPosition::of(l);
+        if failures.contains_key(&pos) {
+            return;
+        }
+        tracing::warn!(
+            "log {} of tx {:?} is skipped: {}",
+            l.log_index.unwrap_or_default(),
+            l.transaction_hash.unwrap_or_default(),
+            e
+        );
+        if failures.len() < MAX_FAILURES {
+            failures.insert(pos, (l.clone(), e));
+        }
+    }
+
+    // logs that failed to decode, with the errors
+    pub fn failures(&self) -> Vec<(Log, EventParseError)> {
+        self.failures.lock().unwrap().values().cloned().collect()
+    }
+
+    // starts watching the apps of SetDaoApps event from its block.
+    // Returns whether the apps were not known
+    pub fn learn_apps(&mut self, block_number: u64, entry: &Api3) -> bool {
+        match DaoApps::of(block_number, entry) {
+            Some(apps) => self.add_apps(apps),
+            None => false,
+        }
+    }
+
+    fn add_apps(&mut self, apps: DaoApps) -> bool {
+        let block_number = apps.block_number;
+        let current = self
+            .apps
+            .iter()
+            .take_while(|a| a.block_number <= block_number)
+            .last();
+        if let Some(current) = current {
+            if current.primary == apps.primary && current.secondary == apps.secondary {
+                return false;
+            }
+        }
+        tracing::info!(
+            "DAO apps from block {}: primary {:?}, secondary {:?}",
+            block_number,
+            apps.primary,
+            apps.secondary
+        );
+        let at = self
+            .apps
+            .partition_point(|a| a.block_number <= block_number);
+        self.apps.insert(at, apps);
+        let primary: Vec<H160> = self.apps.iter().flat_map(|a| a.primary.clone()).collect();
+        let secondary: Vec<H160> = self.apps.iter().flat_map(|a| a.secondary.clone()).collect();
+        let mut watched = vec![];
+        for address in watched_addresses(&primary, &secondary, &self.addr) {
+            if !watched.contains(&address) {
+                watched.push(address);
+            }
+        }
+        self.addr_watched = watched;
+        self.store_open = false;
+        true
+    }
+
+    // contracts that are read only in their block ranges
+    pub fn set_versions(&mut self, versions: Vec<ContractVersion>) {
+        self.versions = versions;
+    }
+
+    // whether the contract is read in any of the blocks
+    fn is_active(&self, address: H160, from: u64, to: u64) -> bool {
+        let mut ranges = self
+            .versions
+            .iter()
+            .filter(|v| v.address == address)
+            .peekable();
+        ranges.peek().is_none() || ranges.any(|v| v.overlaps(from, to))
+    }
+
+    // version of the contract at the block
+    pub fn version(&self, address: H160, block_number: u64) -> Option<String> {
+        self.versions
+            .iter()
+            .find(|v| v.address == address && v.contains(block_number))
+            .map(|v| v.version.clone())
+    }
+
+    // event of the log, tagged with the version of its contract
+    pub fn event(&self, l: &Log, entry: Api3, tm: u64) -> OnChainEvent {
+        let mut e = on_chain_event(l, entry, tm);
+        e.version = self.version(l.address, e.block_number);
+        e
+    }
+
+    // learns the apps that the pool set up to the block, so their logs are read from the start.
+    // The store of the watched contracts is opened after that
+    pub async fn discover_apps<T: BatchTransport>(
+        &mut self,
+        web3: &Web3<T>,
+        to: u64,
     ) -> anyhow::Result<()> {
-        if self.cache_dir.len() == 0 {
-            return Ok(());
+        if self.apps_from <= to {
+            let topic = H256::from(SET_DAO_APPS);
+            let addresses = self.addr.clone();
+            let mut logs = self
+                .query_logs(web3, self.apps_from, to, addresses, Some(topic), None)
+                .await?;
+            logs.sort_by_key(LogPosition::of);
+            for l in logs.iter().filter(|l| l.topics.first() == Some(&topic)) {
+                if let (Some(entry), Some(block_number)) = (self.decode(l), l.block_number) {
+                    self.learn_apps(block_number.as_u64(), &entry);
+                }
+            }
+            self.apps_from = to + 1;
+        }
+        if !self.store_open {
+            self.reopen_store()?;
         }
-        let f = File::create(self.cache_fn(chain_id, &b)).expect("Unable to create file");
-        serde_json::to_writer(&f, logs)?;
         Ok(())
     }
 
+    // timestamp of the block, taken from the cache when possible
+    pub async fn block_time<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        hash: H256,
+    ) -> anyhow::Result<u64> {
+        if let Some(tm) = self.store.lock().unwrap().blocks_time.get(&hash) {
+            return Ok(*tm);
+        }
+        let tm = self.read_block_time(web3, hash).await?;
+        let found = vec![(hash, tm)].into_iter().collect();
+        self.store.lock().unwrap().append_blocks_time(&found)?;
+        Ok(tm)
+    }
+
+    // reads timestamp of the block from the node, retrying on failures
+    pub async fn read_block_time<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        hash: H256,
+    ) -> anyhow::Result<u64> {
+        let block = self
+            .backoff
+            .retry("block time", rpc::is_transient, || {
+                rpc::with_timeout(self.timeout, web3.eth().block(BlockId::Hash(hash)))
+            })
+            .await?;
+        match block {
+            Some(block) => Ok(block.timestamp.as_u64()),
+            None => Err(anyhow::Error::msg(format!("block {:?} not found", hash))),
+        }
+    }
+
+    // reads timestamps of the blocks from the node, in JSON-RPC batch requests
+    pub async fn read_blocks_time<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        hashes: &[H256],
+    ) -> anyhow::Result<BTreeMap<H256, u64>> {
+        let mut res = BTreeMap::new();
+        for chunk in hashes.chunks(MAX_BATCH_CALLS) {
+            let blocks = self
+                .backoff
+                .retry("blocks time", rpc::is_transient, || {
+                    rpc::with_timeout(self.timeout, read_blocks(web3.transport().clone(), chunk))
+                })
+                .await?;
+            for (hash, block) in chunk.iter().zip(blocks) {
+                match block {
+                    Some(block) => res.insert(*hash, block.timestamp.as_u64()),
+                    None => return Err(anyhow::Error::msg(format!("block {:?} not found", hash))),
+                };
+            }
+        }
+        Ok(res)
+    }
+
+    // reads timestamps of the blocks that are not in the cache yet and saves them
+    pub async fn fill_blocks_time<'a, T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        logs: impl Iterator<Item = &'a Log>,
+    ) -> anyhow::Result<()> {
+        let unknown: Vec<H256> = {
+            let store = self.store.lock().unwrap();
+            let unknown: BTreeSet<H256> = logs
+                .filter_map(|l| l.block_hash)
+                .filter(|hash| !store.blocks_time.contains_key(hash))
+                .collect();
+            unknown.into_iter().collect()
+        };
+        let found = self.read_blocks_time(web3, &unknown).await?;
+        self.store.lock().unwrap().append_blocks_time(&found)
+    }
+
+    // reads logs of the watched contracts in the range, only of those that are active in it
+    pub async fn fetch_logs<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        from: u64,
+        to: u64,
+    ) -> anyhow::Result<Vec<Log>> {
+        let addresses: Vec<H160> = self
+            .addr_watched
+            .iter()
+            .copied()
+            .filter(|&a| self.is_active(a, from, to))
+            .collect();
+        // the query without addresses would return the logs of all contracts
+        if addresses.is_empty() {
+            return Ok(vec![]);
+        }
+        self.query_logs(web3, from, to, addresses, None, Some(&self.range_size))
+            .await
+    }
+
+    // reads logs of the range, splitting it when the node can't return it at once.
+    // The size of the next ranges is adjusted when it is given
+    async fn query_logs<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        from: u64,
+        to: u64,
+        addresses: Vec<H160>,
+        topic: Option<H256>,
+        range_size: Option<&rpc::RangeSize>,
+    ) -> anyhow::Result<Vec<Log>> {
+        let mut logs = vec![];
+        let mut ranges = vec![(from, to)];
+        while let Some((from, to)) = ranges.pop() {
+            let filter = FilterBuilder::default()
+                .from_block(from.into())
+                .to_block(to.into())
+                .address(addresses.clone())
+                .topics(topic.map(|x| vec![x]), None, None, None)
+                .build();
+            // too large range is split instead of retrying, unless it is a single block
+            let retryable =
+                |e: &web3::Error| rpc::is_transient(e) && (from == to || !rpc::is_too_large(e));
+            let res = self
+                .backoff
+                .retry("logs", retryable, || {
+                    rpc::with_timeout(self.timeout, web3.eth().logs(filter.clone()))
+                })
+                .await;
+            match res {
+                Ok(found) => {
+                    if let Some(range_size) = range_size.filter(|_| found.len() < QUIET_LOGS) {
+                        range_size.grow(to - from + 1);
+                    }
+                    logs.extend(found);
+                }
+                Err(e) if from < to && rpc::is_too_large(&e) => {
+                    let middle = from + (to - from) / 2;
+                    tracing::warn!("splitting blocks {}..{}: {}", from, to, e);
+                    if let Some(range_size) = range_size {
+                        range_size.shrink(to - from + 1);
+                    }
+                    ranges.push((middle + 1, to));
+                    ranges.push((from, middle));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(logs)
+    }
+
     pub async fn scan<T>(
         &mut self,
         web3: &Web3<T>,
         handler: &mut impl EventHandler,
     ) -> anyhow::Result<u64>
     where
-        T: Transport,
+        T: BatchTransport,
     {
         let chain_id = self.chain_id;
-        let mut last_block = self.genesis_block;
-        for b in get_batches(
-            web3.eth(),
-            self.genesis_block,
-            self.max_block,
-            self.batch_size,
-        )
-        .await
-        {
-            let start = std::time::Instant::now();
-            let mut method = "".to_owned();
-            let _ = method; // dummy warning workaround
-            let logs: Vec<Log> = if self.has_logs(chain_id, &b) {
-                let logs = self.get_logs(chain_id, &b).await?;
-                method = format!(
-                    "cached {}..{}/{} in {:?}",
-                    b.from,
-                    b.to,
-                    chain_id,
-                    start.elapsed()
-                );
-                logs
-            } else {
-                let filter = FilterBuilder::default()
-                    .from_block(b.from.into())
-                    .to_block(b.to.into())
-                    .address(self.addr_watched.clone())
-                    .build();
-                let logs: Vec<Log> = web3.eth().logs(filter).await?;
-                self.save_logs(chain_id, &b, &logs).await?;
-                method = format!(
-                    "scanned {}..{}/{} in {:?}",
-                    b.from,
-                    b.to,
-                    chain_id,
-                    start.elapsed()
-                );
-                logs
-            };
+        // nothing is scanned when there are no blocks after the genesis yet
+        let mut last_block = self.genesis_block.saturating_sub(1);
+        let head = match self.max_block {
+            Some(x) if self.confirmations == 0 => x,
+            max => {
+                let head = self
+                    .backoff
+                    .retry("block number", rpc::is_transient, || {
+                        rpc::with_timeout(self.timeout, web3.eth().block_number())
+                    })
+                    .await?
+                    .as_u64();
+                max.map_or(head, |x| std::cmp::min(x, head))
+            }
+        };
+        // the events of the last blocks are not final yet
+        let confirmed = head.saturating_sub(self.confirmations);
+        self.discover_apps(web3, confirmed).await?;
 
-            // let start = std::time::Instant::now();
-            let mut blocktime_dur = std::time::Duration::from_nanos(0);
-            let mut handler_dur = std::time::Duration::from_nanos(0);
-            for l in &logs {
-                if let Ok(entry) = Api3::from_log(self.agent(l.address), &l) {
-                    let blockstart = std::time::Instant::now();
-                    let tmkey: H256 = l.block_hash.unwrap();
-                    let ts: u64 = if self.blocks_time.contains_key(&tmkey) {
-                        match self.blocks_time.get(&tmkey) {
-                            Some(x) => x.clone(),
-                            None => web3
-                                .eth()
-                                .block(BlockId::Hash(l.block_hash.unwrap()))
-                                .await
-                                .expect("block failure")
-                                .expect("block timestamp failure")
-                                .timestamp
-                                .as_u64(),
-                        }
-                    } else {
-                        web3.eth()
-                            .block(BlockId::Hash(l.block_hash.unwrap()))
-                            .await
-                            .expect("block failure")
-                            .expect("block timestamp failure")
-                            .timestamp
-                            .as_u64()
+        // batches are planned when they are about to be read, so they take
+        // the current range size. Cached blocks are read up to the end of their frame
+        let range_size = self.range_size.clone();
+        let store = self.store.clone();
+        let mut next = self.genesis_block;
+        let batches = std::iter::from_fn(move || {
+            if next > confirmed {
+                return None;
+            }
+            let store = store.lock().unwrap();
+            let to = match store.frame_end(next) {
+                Some(to) => std::cmp::min(to, confirmed),
+                None => {
+                    // not crossing into the cached range
+                    let end = next + range_size.get() - 1;
+                    let end = match store.ranges.range(next..=end).next() {
+                        Some((&from, _)) => from - 1,
+                        None => end,
                     };
-                    self.blocks_time.insert(tmkey, ts);
-
-                    blocktime_dur += blockstart.elapsed();
-
-                    let handlerstart = std::time::Instant::now();
-                    handler.on(
-                        OnChainEvent {
-                            block_number: l.block_number.unwrap().as_u64(),
-                            tx: l.transaction_hash.unwrap(),
-                            log_index: l.log_index.unwrap().as_u64(),
-                            entry,
-                            tm: ts,
-                        },
-                        l.clone(),
-                    );
-                    handler_dur += handlerstart.elapsed();
+                    std::cmp::min(end, confirmed)
+                }
+            };
+            let b = BlockBatch { from: next, to };
+            next = to + 1;
+            Some(b)
+        });
+        let total = (confirmed + 1).saturating_sub(self.genesis_block);
+
+        // batches are read concurrently by the copy of the scanner,
+        // but handled one after another in the order of blocks
+        let start = Instant::now();
+        let mut blocks_count: u64 = 0;
+        let mut events_count: usize = 0;
+        let reader = self.clone();
+        let mut fetched = futures::stream::iter(batches)
+            .map(|b| reader.fetch_batch(web3, b))
+            .buffered(self.concurrency);
+        while let Some(f) = fetched.next().await {
+            let f = f?;
+            let handler_start = Instant::now();
+            if let Some(recorder) = &self.recorder {
+                let mut recorder = recorder.lock().unwrap();
+                for (l, tm) in &f.raw {
+                    recorder.record(l, *tm);
                 }
             }
-            save_blockstime(&self.cache_dir, chain_id, &self.blocks_time)?;
+            for (l, entry) in f.entries {
+                let ts: u64 = self.block_time(web3, l.block_hash.unwrap()).await?;
+                handler.on(self.event(&l, entry, ts), l).await;
+                events_count += 1;
+            }
+            blocks_count += f.batch.to - f.batch.from + 1;
+            let seconds = start.elapsed().as_secs_f64();
+            tracing::info!(
+                "{} events, took {:?} ({} {}..{}/{} in {:?}), {}/{} blocks, {:.0} blocks/s",
+                f.logs_count,
+                handler_start.elapsed(),
+                if f.cached { "cached" } else { "scanned" },
+                f.batch.from,
+                f.batch.to,
+                chain_id,
+                f.elapsed,
+                blocks_count,
+                total,
+                if seconds > 0.0 {
+                    blocks_count as f64 / seconds
+                } else {
+                    0.0
+                },
+            );
+            last_block = f.batch.to;
+        }
+        if blocks_count > 0 {
             tracing::info!(
-                "{} events, took {:?}, blocks {:?} ({})",
-                logs.len(),
-                handler_dur,
-                blocktime_dur,
-                method,
+                "{} blocks with {} events scanned in {:?}",
+                blocks_count,
+                events_count,
+                start.elapsed()
             );
-            last_block = b.to;
+        }
+        if confirmed < head {
+            let from = std::cmp::max(confirmed + 1, self.genesis_block);
+            let events = self.events(web3, from, head).await?;
+            handler
+                .pending(events.into_iter().map(|(e, _)| e).collect())
+                .await;
         }
         Ok(last_block)
     }
 
-    // continuously watch incoming blocks
-    pub async fn watch_ipc(
+    // reads logs of the batch from the cache or from the node and the timestamps
+    // of their blocks. The scanner is not changed, so batches could be read concurrently
+    pub async fn fetch_batch<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        b: BlockBatch,
+    ) -> anyhow::Result<FetchedBatch> {
+        let start = Instant::now();
+        let found = self.store.lock().unwrap().get_logs(b.from, b.to)?;
+        let cached = found.is_some();
+        let mut logs: Vec<Log> = match found {
+            Some(logs) => logs,
+            None => {
+                let logs = self.fetch_logs(web3, b.from, b.to).await?;
+                self.store
+                    .lock()
+                    .unwrap()
+                    .append_logs(b.from, b.to, &logs)?;
+                logs
+            }
+        };
+        logs.sort_by_key(LogPosition::of);
+        let logs_count = logs.len();
+        let raw = match self.recorder {
+            Some(_) => {
+                self.fill_blocks_time(web3, logs.iter()).await?;
+                let store = self.store.lock().unwrap();
+                logs.iter()
+                    .map(|l| {
+                        let tm = l.block_hash.and_then(|h| store.blocks_time.get(&h));
+                        (l.clone(), tm.copied().unwrap_or_default())
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+        let entries: Vec<(Log, Api3)> = logs
+            .into_iter()
+            .filter_map(|l| self.decode(&l).map(|entry| (l, entry)))
+            .collect();
+        self.fill_blocks_time(web3, entries.iter().map(|(l, _)| l))
+            .await?;
+        Ok(FetchedBatch {
+            batch: b,
+            entries,
+            raw,
+            logs_count,
+            cached,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    // reads and decodes events of the given blocks range
+    pub async fn events<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+        from: u64,
+        to: u64,
+    ) -> anyhow::Result<Vec<(OnChainEvent, Log)>> {
+        let logs: Vec<Log> = self.fetch_logs(web3, from, to).await?;
+        let entries: Vec<(Log, Api3)> = logs
+            .into_iter()
+            .filter_map(|l| self.decode(&l).map(|entry| (l, entry)))
+            .collect();
+        // read all timestamps first, so the failure leaves nothing half-applied
+        self.fill_blocks_time(web3, entries.iter().map(|(l, _)| l))
+            .await?;
+        let mut events = vec![];
+        for (l, entry) in entries {
+            let tm: u64 = self.block_time(web3, l.block_hash.unwrap()).await?;
+            events.push((self.event(&l, entry, tm), l));
+        }
+        Ok(events)
+    }
+
+    // decodes the events of the blocks range again from the cache, a frame at a time,
+    // without the node. Fails when some of the blocks are not cached
+    pub fn replay_cached(
+        &self,
+        from: u64,
+        to: u64,
+        mut on: impl FnMut(OnChainEvent, Log),
+    ) -> anyhow::Result<()> {
+        let mut next = from;
+        while next <= to {
+            let not_cached = || anyhow::Error::msg(format!("block {} is not cached", next));
+            let store = self.store.lock().unwrap();
+            let end = std::cmp::min(store.frame_end(next).ok_or_else(not_cached)?, to);
+            let mut logs = store.get_logs(next, end)?.ok_or_else(not_cached)?;
+            logs.sort_by_key(LogPosition::of);
+            let mut events = vec![];
+            for l in logs {
+                if let Some(entry) = self.decode(&l) {
+                    let tm = l.block_hash.and_then(|h| store.blocks_time.get(&h));
+                    let tm = *tm.ok_or_else(|| {
+                        anyhow::Error::msg(format!("time of block {} is not cached", next))
+                    })?;
+                    events.push((self.event(&l, entry, tm), l));
+                }
+            }
+            drop(store);
+            for (e, l) in events {
+                on(e, l);
+            }
+            next = end + 1;
+        }
+        Ok(())
+    }
+
+    fn remember_block(&mut self, number: u64, hash: H256) {
+        self.recent_blocks.insert(number, hash);
+        if number > REORG_DEPTH {
+            self.recent_blocks = self.recent_blocks.split_off(&(number - REORG_DEPTH));
+        }
+    }
+
+    // remembers the hash of the canonical block with the given number
+    async fn remember_canonical<T: BatchTransport>(
+        &mut self,
+        web3: &Web3<T>,
+        number: u64,
+    ) -> anyhow::Result<()> {
+        let id = BlockId::Number(BlockNumber::Number(number.into()));
+        if let Some(hash) = web3.eth().block(id).await?.and_then(|b| b.hash) {
+            self.remember_block(number, hash);
+        }
+        Ok(())
+    }
+
+    // checks that the remembered blocks are still in the chain: the next block
+    // must have the remembered one as its parent. When it doesn't,
+    // returns the last remembered block that is still in the chain
+    pub async fn fork_point<T: BatchTransport>(
+        &self,
+        web3: &Web3<T>,
+    ) -> anyhow::Result<Option<u64>> {
+        let mut reorg = false;
+        for (&number, &hash) in self.recent_blocks.iter().rev() {
+            let id = BlockId::Number(BlockNumber::Number((number + 1).into()));
+            let in_chain = match web3.eth().block(id).await? {
+                Some(next) => next.parent_hash == hash,
+                None => {
+                    // it is the head of the chain
+                    let id = BlockId::Number(BlockNumber::Number(number.into()));
+                    match web3.eth().block(id).await? {
+                        Some(b) => b.hash == Some(hash),
+                        None => false,
+                    }
+                }
+            };
+            if in_chain {
+                return Ok(if reorg { Some(number) } else { None });
+            }
+            tracing::warn!("block {} {:?} is not in the chain", number, hash);
+            reorg = true;
+        }
+        // none of the remembered blocks stayed in the chain
+        Ok(match self.recent_blocks.keys().next() {
+            Some(first) if reorg => Some(first.saturating_sub(1)),
+            _ => None,
+        })
+    }
+
+    // undoes the blocks after `fork`, they will be applied again from the chain
+    async fn rollback(
+        &mut self,
+        fork: u64,
+        pos: &mut LogPosition,
+        handler: &mut impl EventHandler,
+    ) {
+        tracing::warn!("chain reorganization, rolling back to block {}", fork);
+        self.recent_blocks.split_off(&(fork + 1));
+        if *pos > LogPosition::block_end(fork) {
+            *pos = LogPosition::block_end(fork);
+        }
+        handler.rollback(fork).await;
+    }
+
+    // rolls back the blocks that are not in the chain anymore
+    async fn check_reorg<T: BatchTransport>(
+        &mut self,
+        web3: &Web3<T>,
+        pos: &mut LogPosition,
+        handler: &mut impl EventHandler,
+    ) -> anyhow::Result<()> {
+        if self.recent_blocks.is_empty() {
+            // nothing to compare with, starting from the current chain
+            return self.remember_canonical(web3, pos.block_number).await;
+        }
+        if let Some(fork) = self.fork_point(web3).await? {
+            self.rollback(fork, pos, handler).await;
+        }
+        Ok(())
+    }
+
+    // applies events of the blocks after `pos` up to the block `to`
+    async fn catch_up<T: BatchTransport>(
+        &mut self,
+        web3: &Web3<T>,
+        pos: &mut LogPosition,
+        to: u64,
+        handler: &mut impl EventHandler,
+    ) -> anyhow::Result<()> {
+        loop {
+            let from = if pos.log_index == u64::MAX {
+                pos.block_number + 1
+            } else {
+                pos.block_number
+            };
+            if from > to {
+                return Ok(());
+            }
+            let batch_to = std::cmp::min(from + self.batch_size - 1, to);
+            // the block is remembered before reading logs, so the logs of another fork
+            // make the next check fail instead of passing it
+            self.remember_canonical(web3, batch_to).await?;
+            let mut events = self.events(web3, from, batch_to).await?;
+            // logs of the new apps were not read, so the blocks from their setting are read again
+            let set_at = events
+                .iter()
+                .position(|(e, _)| self.learn_apps(e.block_number, &e.entry));
+            if let Some(i) = set_at {
+                events.truncate(i + 1);
+            }
+            if !events.is_empty() {
+                tracing::info!(
+                    "{} new events in blocks {}..{}",
+                    events.len(),
+                    from,
+                    batch_to
+                );
+            }
+            self.apply(events, pos, handler).await?;
+            if set_at.is_none() {
+                *pos = LogPosition::block_end(batch_to);
+            }
+        }
+    }
+
+    // reads logs of the blocks that were added after `pos`
+    // and moves `pos` forward as the blocks are processed.
+    // Blocks that left the chain are rolled back first
+    pub async fn poll<T: BatchTransport>(
+        &mut self,
+        web3: &Web3<T>,
+        pos: &mut LogPosition,
+        handler: &mut impl EventHandler,
+    ) -> anyhow::Result<()> {
+        self.check_reorg(web3, pos, handler).await?;
+        let head = web3.eth().block_number().await?.as_u64();
+        let confirmed = head.saturating_sub(self.confirmations);
+        self.catch_up(web3, pos, confirmed, handler).await?;
+        if self.confirmations > 0 {
+            let events = if confirmed < head {
+                self.events(web3, confirmed + 1, head).await?
+            } else {
+                vec![]
+            };
+            let events = events.into_iter().map(|(e, _)| e).collect();
+            handler.pending(events).await;
+        }
+        Ok(())
+    }
+
+    // continuously watch incoming logs using IPC or WebSocket subscription.
+    // Connection is restored after any failure, missed blocks are read again
+    pub async fn watch_subscription(
         &mut self,
-        web3: &Web3<Ipc>,
+        source: &str,
         from_block: u64,
-        handler_mux: Arc<Mutex<impl EventHandler>>,
+        mut handler: impl EventHandler,
+        reconnect_delay: Duration,
     ) -> anyhow::Result<()> {
         tracing::info!("listening to blocks from {} in real-time", from_block);
+        let mut pos = LogPosition::block_end(from_block);
+        loop {
+            match self.subscribe(source, &mut pos, &mut handler).await {
+                Ok(_) => tracing::warn!("subscription closed after {:?}", pos),
+                Err(e) => tracing::warn!("subscription failure after {:?}: {}", pos, e),
+            };
+            tokio::time::sleep(reconnect_delay).await;
+        }
+    }
+
+    // single connection of `watch_subscription`, returns when the connection is lost
+    async fn subscribe(
+        &mut self,
+        source: &str,
+        pos: &mut LogPosition,
+        handler: &mut impl EventHandler,
+    ) -> anyhow::Result<()> {
+        let web3 = Web3::new(get_duplex_transport(source).await?);
+        if self.confirmations > 0 {
+            // logs are read behind every new block, once they are confirmed
+            let mut heads = web3.eth_subscribe().subscribe_new_heads().await?;
+            self.poll(&web3, pos, handler).await?;
+            while let Some(head) = heads.next().await {
+                head?;
+                self.poll(&web3, pos, handler).await?;
+            }
+            return Ok(());
+        }
         let filter = FilterBuilder::default()
-            .from_block(from_block.into())
             .address(self.addr_watched.clone())
             .build();
-        let filter = web3.eth_filter().create_logs_filter(filter).await.unwrap();
-        let logs_stream = filter.stream(Duration::from_secs(10));
-        futures::pin_mut!(logs_stream);
-        loop {
-            tracing::info!("waiting for entries");
-            let l: Log = logs_stream.next().await.unwrap().unwrap();
-            if let Ok(entry) = Api3::from_log(self.agent(l.address), &l) {
-                let tmkey: H256 = l.block_hash.unwrap();
-                let tm: u64 = web3
-                    .eth()
-                    .block(BlockId::Hash(l.block_hash.unwrap()))
-                    .await
-                    .expect("block failure")
-                    .expect("block timestamp failure")
-                    .timestamp
-                    .as_u64();
+        // subscribing before reading the missed blocks, so nothing falls in between
+        let logs_stream = web3.eth_subscribe().subscribe_logs(filter).await?;
+        self.poll(&web3, pos, handler).await?;
+        self.stream(&web3, logs_stream, pos, handler).await
+    }
 
-                handler_mux.lock().unwrap().on(
-                    OnChainEvent {
-                        block_number: l.block_number.unwrap().as_u64(),
-                        tx: l.transaction_hash.unwrap(),
-                        log_index: l.log_index.unwrap().as_u64(),
-                        entry,
-                        tm,
-                    },
-                    l,
-                );
-                self.blocks_time.insert(tmkey, tm);
-                save_blockstime(&self.cache_dir, self.chain_id, &self.blocks_time)?;
+    async fn stream<T: DuplexTransport + BatchTransport>(
+        &mut self,
+        web3: &Web3<T>,
+        logs_stream: impl futures::Stream<Item = web3::error::Result<Log>>,
+        pos: &mut LogPosition,
+        handler: &mut impl EventHandler,
+    ) -> anyhow::Result<()> {
+        futures::pin_mut!(logs_stream);
+        while let Some(l) = logs_stream.next().await {
+            let l: Log = l?;
+            let log_pos = match LogPosition::of(&l) {
+                Some(x) => x,
+                None => continue,
+            };
+            if l.removed == Some(true) {
+                // the block of the log left the chain
+                if log_pos.block_number <= pos.block_number {
+                    self.rollback(log_pos.block_number - 1, pos, handler).await;
+                }
+                continue;
+            }
+            let remembered = self.recent_blocks.get(&log_pos.block_number).copied();
+            if remembered.is_some() && remembered != l.block_hash {
+                // another block of the same height, the logs of the remembered one are undone
+                self.rollback(log_pos.block_number - 1, pos, handler).await;
+            }
+            if log_pos <= *pos {
+                continue;
+            }
+            if !self.recent_blocks.contains_key(&log_pos.block_number) {
+                // the first log of the new block, which must continue the remembered blocks
+                self.check_reorg(web3, pos, handler).await?;
+                self.catch_up(web3, pos, log_pos.block_number - 1, handler)
+                    .await?;
+                self.remember_block(log_pos.block_number, l.block_hash.unwrap());
+            }
+            if let Some(entry) = self.decode(&l) {
+                let tm: u64 = self.block_time(web3, l.block_hash.unwrap()).await?;
+                let learned = self.learn_apps(log_pos.block_number, &entry);
+                let event = self.event(&l, entry, tm);
+                self.apply(vec![(event, l)], pos, handler).await?;
+                if learned {
+                    // subscribing again to the logs of the new apps
+                    return Ok(());
+                }
 
                 // match &entry {
                 //     Api3::StartVote{ agent, vote_id, creator: _, metadata: _ } => {
@@ -367,5 +1173,858 @@ impl Scanner {
                 // };
             }
         }
+        Ok(())
+    }
+
+    // passes events to the handler, skipping the ones that were applied before `pos`
+    async fn apply(
+        &mut self,
+        events: Vec<(OnChainEvent, Log)>,
+        pos: &mut LogPosition,
+        handler: &mut impl EventHandler,
+    ) -> anyhow::Result<()> {
+        for (e, l) in events {
+            let log_pos = match LogPosition::of(&l) {
+                Some(x) => x,
+                None => continue, // pending log, will come again once mined
+            };
+            if log_pos <= *pos {
+                continue;
+            }
+            if let Some(hash) = l.block_hash {
+                self.remember_block(log_pos.block_number, hash);
+            }
+            handler.on(e, l).await;
+            *pos = log_pos;
+        }
+        Ok(())
+    }
+
+    // continuously poll the node for the logs of the new blocks
+    pub async fn watch_http<T: BatchTransport>(
+        &mut self,
+        web3: &Web3<T>,
+        from_block: u64,
+        mut handler: impl EventHandler,
+        interval: Duration,
+    ) -> anyhow::Result<()> {
+        tracing::info!("polling blocks after {} every {:?}", from_block, interval);
+        let mut pos = LogPosition::block_end(from_block);
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.poll(web3, &mut pos, &mut handler).await {
+                tracing::warn!("polling failure after {:?}: {}", pos, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrpc::{self, TestChain};
+    use hex_literal::hex;
+
+    #[derive(Default)]
+    struct Collector {
+        events: Vec<OnChainEvent>,
+        rollbacks: Vec<u64>,
+        pending: Vec<(u64, u64)>,
+    }
+
+    impl Collector {
+        fn rollback_to(&mut self, block_number: u64) {
+            self.events.retain(|e| e.block_number <= block_number);
+            self.rollbacks.push(block_number);
+        }
+
+        fn set_pending(&mut self, events: Vec<OnChainEvent>) {
+            self.pending = events
+                .iter()
+                .map(|e| (e.block_number, e.log_index))
+                .collect();
+        }
+    }
+
+    #[async_trait]
+    impl EventHandler for Collector {
+        async fn on(&mut self, e: OnChainEvent, _: Log) {
+            self.events.push(e);
+        }
+
+        async fn rollback(&mut self, block_number: u64) {
+            self.rollback_to(block_number);
+        }
+
+        async fn pending(&mut self, events: Vec<OnChainEvent>) {
+            self.set_pending(events);
+        }
+    }
+
+    // the collector that is checked while the scanner is watching
+    #[async_trait]
+    impl EventHandler for Arc<Mutex<Collector>> {
+        async fn on(&mut self, e: OnChainEvent, _: Log) {
+            self.lock().unwrap().events.push(e);
+        }
+
+        async fn rollback(&mut self, block_number: u64) {
+            self.lock().unwrap().rollback_to(block_number);
+        }
+
+        async fn pending(&mut self, events: Vec<OnChainEvent>) {
+            self.lock().unwrap().set_pending(events);
+        }
+    }
+
+    fn positions(handler: &Arc<Mutex<Collector>>) -> Vec<(u64, u64)> {
+        let c = handler.lock().unwrap();
+        c.events
+            .iter()
+            .map(|e| (e.block_number, e.log_index))
+            .collect()
+    }
+
+    fn pool() -> H160 {
+        H160::from_low_u64_be(0xa3)
+    }
+
+    fn scheduled_unstake(address: H160) -> Log {
+        testrpc::log(
+            address,
+            vec![
+                hex!("06fbd2297e6f6f7701a9cf99685a6af911cab275ec5c75ac7aaaf13b5cf3d61f").into(),
+                hex!("000000000000000000000000061b8335e1d2042975c4ed849943334bd07fb504").into(),
+            ],
+            hex!("0000000000000000000000000000000000000000000000056bc75e2d631000000000000000000000000000000000000000000000000000056bb73f60696ee4160000000000000000000000000000000000000000000000000000000060da02bd").to_vec(),
+        )
+    }
+
+    #[tokio::test]
+    async fn it_scans_and_polls_http() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine_empty(2);
+            c.mine(vec![scheduled_unstake(pool())]);
+            c.mine(vec![scheduled_unstake(H160::from_low_u64_be(1))]); // not watched
+            c.mine_empty(4);
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 4,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut collector = Collector::default();
+        let last_block = scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(last_block, 8);
+        assert_eq!(collector.events.len(), 1);
+        assert_eq!(collector.events[0].block_number, 3);
+        assert_eq!(collector.events[0].tm, TestChain::timestamp(3));
+
+        let mut handler = Arc::new(Mutex::new(collector));
+        let mut pos = LogPosition::block_end(last_block);
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(pos, LogPosition::block_end(8));
+        assert_eq!(handler.lock().unwrap().events.len(), 1);
+
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine_empty(6);
+            c.mine(vec![scheduled_unstake(pool()), scheduled_unstake(pool())]);
+        }
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(pos, LogPosition::block_end(15));
+        let events = &handler.lock().unwrap().events;
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2].block_number, 15);
+        assert_eq!(events[2].log_index, 1);
+    }
+
+    fn set_dao_apps(primary: [H160; 2], secondary: [H160; 2]) -> Log {
+        let mut data = vec![];
+        for address in [primary[1], secondary[1], primary[0], secondary[0]] {
+            data.extend_from_slice(H256::from(address).as_bytes());
+        }
+        testrpc::log(pool(), vec![SET_DAO_APPS.into()], data)
+    }
+
+    fn execute_vote(voting: H160, vote_id: u64) -> Log {
+        let topic = hex!("bf8e2b108bb7c980e08903a8a46527699d5e84905a082d56dacb4150725c8cab");
+        testrpc::log(
+            voting,
+            vec![topic.into(), H256::from_low_u64_be(vote_id)],
+            vec![],
+        )
+    }
+
+    #[tokio::test]
+    async fn it_skips_logs_that_fail_to_decode() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        let mut broken = scheduled_unstake(pool());
+        broken.data.0.truncate(40);
+        chain
+            .lock()
+            .unwrap()
+            .mine(vec![broken, scheduled_unstake(pool())]);
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut collector = Collector::default();
+        scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(collector.events.len(), 1);
+        assert_eq!(collector.events[0].log_index, 1);
+        let failures = scanner.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].1, EventParseError::OutOfData(32, 32, 40));
+    }
+
+    #[tokio::test]
+    async fn it_discovers_dao_apps() {
+        let app = H160::from_low_u64_be;
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        let set_at = {
+            let mut c = chain.lock().unwrap();
+            // the app is not known before the pool sets it
+            c.mine(vec![execute_vote(app(1), 1)]);
+            let set_at = c.mine(vec![set_dao_apps([app(1), app(2)], [app(3), app(4)])]);
+            c.mine(vec![execute_vote(app(1), 2), execute_vote(app(3), 3)]);
+            set_at
+        };
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let dir = testrpc::TempDir::new("apps");
+        let cache_dir = dir.to_str().unwrap().to_owned();
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                cache_dir: cache_dir.clone(),
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 2,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut collector = Collector::default();
+        let last_block = scanner.scan(&web3, &mut collector).await.unwrap();
+        // the only cache file is the one of the discovered apps
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        let foreign = crate::store::foreign_files(&cache_dir, 1, scanner.watched());
+        assert_eq!((files, foreign.len()), (1, 0));
+        let votes: Vec<(VotingAgent, u64)> = collector
+            .events
+            .iter()
+            .filter_map(|e| match &e.entry {
+                Api3::ExecuteVote { agent, vote_id } => Some((agent.clone(), vote_id.as_u64())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(collector.events.len(), 3);
+        assert_eq!(
+            votes,
+            vec![(VotingAgent::Primary, 2), (VotingAgent::Secondary, 3)]
+        );
+        assert_eq!(scanner.dao_apps().unwrap().block_number, set_at);
+        assert_eq!(scanner.watched().len(), 5);
+
+        // the new primary app is read from the block it is set at
+        let mut handler = Arc::new(Mutex::new(collector));
+        let mut pos = LogPosition::block_end(last_block);
+        let replaced_at = chain.lock().unwrap().mine(vec![
+            set_dao_apps([app(5), app(6)], [app(3), app(4)]),
+            execute_vote(app(5), 4),
+            execute_vote(app(1), 5),
+        ]);
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(pos, LogPosition::block_end(replaced_at));
+        assert_eq!(
+            positions(&handler)[3..],
+            [(replaced_at, 0), (replaced_at, 1), (replaced_at, 2)]
+        );
+        assert_eq!(scanner.agent(app(5), replaced_at - 1), None);
+        assert_eq!(
+            scanner.agent(app(1), replaced_at),
+            Some(VotingAgent::Primary)
+        );
+        assert_eq!(scanner.watched().len(), 7);
+
+        // the apps that were kept in the checkpoint are not searched again
+        let apps = vec![
+            DaoApps {
+                block_number: set_at,
+                primary: vec![app(1), app(2)],
+                secondary: vec![app(3), app(4)],
+            },
+            DaoApps {
+                block_number: replaced_at,
+                primary: vec![app(5), app(6)],
+                secondary: vec![app(3), app(4)],
+            },
+        ];
+        let requests = chain.lock().unwrap().requests.len();
+        let mut resumed = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 2,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        resumed.resume_apps(&apps, replaced_at);
+        resumed.discover_apps(&web3, replaced_at).await.unwrap();
+        assert_eq!(chain.lock().unwrap().requests.len(), requests);
+        assert_eq!(resumed.watched(), scanner.watched());
+        // neither are the given ones
+        let mut given = Scanner::new(
+            1,
+            ScannerOptions {
+                addr_primary: vec![app(1)],
+                addr_secondary: vec![app(3)],
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 2,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        given.discover_apps(&web3, replaced_at).await.unwrap();
+        assert_eq!(chain.lock().unwrap().requests.len(), requests);
+    }
+
+    #[tokio::test]
+    async fn it_reads_contracts_in_their_block_ranges() {
+        let upgraded = H160::from_low_u64_be(0xa4);
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine(vec![scheduled_unstake(pool()), scheduled_unstake(upgraded)]);
+            c.mine_empty(3);
+            c.mine(vec![scheduled_unstake(pool()), scheduled_unstake(upgraded)]);
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let addresses = vec![pool(), upgraded];
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: addresses,
+                genesis_block: 1,
+                batch_size: 2,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let version = |name: &str, address, from_block, to_block| ContractVersion {
+            version: name.to_owned(),
+            address,
+            from_block,
+            to_block,
+        };
+        scanner.set_versions(vec![
+            version("v0", pool(), None, Some(3)),
+            version("v1", upgraded, Some(4), None),
+        ]);
+        assert_eq!(scanner.fetch_logs(&web3, 1, 3).await.unwrap().len(), 1);
+        assert_eq!(scanner.fetch_logs(&web3, 4, 5).await.unwrap().len(), 1);
+
+        let mut collector = Collector::default();
+        scanner.scan(&web3, &mut collector).await.unwrap();
+        let tagged: Vec<(u64, Option<String>)> = collector
+            .events
+            .iter()
+            .map(|e| (e.block_number, e.version.clone()))
+            .collect();
+        assert_eq!(
+            tagged,
+            vec![(1, Some("v0".to_owned())), (5, Some("v1".to_owned()))]
+        );
+    }
+
+    // waits until the handler has events at the given positions
+    async fn wait_for(handler: &Arc<Mutex<Collector>>, expected: Vec<(u64, u64)>) {
+        for _ in 0..100 {
+            if positions(handler) == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(positions(handler), expected);
+    }
+
+    #[tokio::test]
+    async fn it_resubscribes_ws_and_reads_missed_blocks() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine_empty(2);
+            c.mine(vec![scheduled_unstake(pool())]);
+        }
+        let source = testrpc::serve(chain.clone()).replace("http://", "ws://");
+        let web3 = Web3::new(get_transport(source.clone()).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 4,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut collector = Collector::default();
+        let last_block = scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(last_block, 3);
+
+        let handler = Arc::new(Mutex::new(collector));
+        let rc = handler.clone();
+        tokio::spawn(async move {
+            let delay = Duration::from_millis(200);
+            scanner
+                .watch_subscription(&source, last_block, rc, delay)
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // delivered by the subscription
+        chain.lock().unwrap().mine(vec![scheduled_unstake(pool())]);
+        wait_for(&handler, vec![(3, 0), (4, 0)]).await;
+
+        // mined while the connection is down, read again after reconnecting
+        {
+            let mut c = chain.lock().unwrap();
+            c.disconnect();
+            c.mine(vec![scheduled_unstake(pool()), scheduled_unstake(pool())]);
+        }
+        wait_for(&handler, vec![(3, 0), (4, 0), (5, 0), (5, 1)]).await;
+        // and the subscription is active again
+        chain.lock().unwrap().mine(vec![scheduled_unstake(pool())]);
+        wait_for(&handler, vec![(3, 0), (4, 0), (5, 0), (5, 1), (6, 0)]).await;
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(positions(&handler).len(), 5);
+        assert!(handler.lock().unwrap().rollbacks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_rolls_back_reorganized_blocks_when_polling() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine_empty(2);
+            c.mine(vec![scheduled_unstake(pool())]);
+            c.mine_empty(1);
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 4,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut handler = Arc::new(Mutex::new(Collector::default()));
+        let mut pos = LogPosition::block_end(0);
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine(vec![scheduled_unstake(pool())]);
+            c.mine_empty(1);
+        }
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(positions(&handler), vec![(3, 0), (5, 0)]);
+
+        // block 5 is replaced with another one with different logs
+        {
+            let mut c = chain.lock().unwrap();
+            c.rewind(4);
+            c.mine(vec![scheduled_unstake(pool()), scheduled_unstake(pool())]);
+            c.mine_empty(2);
+        }
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(pos, LogPosition::block_end(7));
+        assert_eq!(positions(&handler), vec![(3, 0), (5, 0), (5, 1)]);
+        assert_eq!(handler.lock().unwrap().rollbacks, vec![4]);
+
+        // nothing changes when the chain is the same
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(handler.lock().unwrap().rollbacks, vec![4]);
+    }
+
+    #[tokio::test]
+    async fn it_rolls_back_reorganized_blocks_when_subscribed() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine_empty(2);
+            c.mine(vec![scheduled_unstake(pool())]);
+        }
+        let source = testrpc::serve(chain.clone()).replace("http://", "ws://");
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 4,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let handler = Arc::new(Mutex::new(Collector::default()));
+        let rc = handler.clone();
+        tokio::spawn(async move {
+            let delay = Duration::from_millis(200);
+            scanner.watch_subscription(&source, 0, rc, delay).await
+        });
+        wait_for(&handler, vec![(3, 0)]).await;
+
+        // removed logs are received from the subscription
+        chain.lock().unwrap().mine(vec![scheduled_unstake(pool())]);
+        wait_for(&handler, vec![(3, 0), (4, 0)]).await;
+        {
+            let mut c = chain.lock().unwrap();
+            c.rewind(3);
+            c.mine(vec![scheduled_unstake(pool()), scheduled_unstake(pool())]);
+        }
+        wait_for(&handler, vec![(3, 0), (4, 0), (4, 1)]).await;
+        assert_eq!(handler.lock().unwrap().rollbacks, vec![3]);
+
+        // reorganization while disconnected is found by the parent hash
+        {
+            let mut c = chain.lock().unwrap();
+            c.disconnect();
+            c.rewind(3);
+            c.mine_empty(1);
+            c.mine(vec![scheduled_unstake(pool())]);
+        }
+        wait_for(&handler, vec![(3, 0), (5, 0)]).await;
+        assert_eq!(handler.lock().unwrap().rollbacks, vec![3, 3]);
+    }
+
+    #[tokio::test]
+    async fn it_rolls_back_replaced_block_of_the_stream() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        let replaced = {
+            let mut c = chain.lock().unwrap();
+            c.mine_empty(2);
+            let n = c.mine(vec![scheduled_unstake(pool())]);
+            c.blocks[n as usize].logs.clone()
+        };
+        let source = testrpc::serve(chain.clone()).replace("http://", "ws://");
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 4,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // logs are given to the stream directly, without the removed ones
+        let (tx, rx) = futures::channel::mpsc::unbounded::<Log>();
+        let handler = Arc::new(Mutex::new(Collector::default()));
+        let mut rc = handler.clone();
+        tokio::spawn(async move {
+            let web3 = Web3::new(get_duplex_transport(&source).await.unwrap());
+            let mut pos = LogPosition::block_end(2);
+            let logs = rx.map(Ok::<Log, web3::Error>);
+            scanner.stream(&web3, logs, &mut pos, &mut rc).await
+        });
+        tx.unbounded_send(replaced[0].clone()).unwrap();
+        wait_for(&handler, vec![(3, 0)]).await;
+
+        let logs = {
+            let mut c = chain.lock().unwrap();
+            c.rewind(2);
+            let n = c.mine(vec![scheduled_unstake(pool()), scheduled_unstake(pool())]);
+            c.blocks[n as usize].logs.clone()
+        };
+        for l in &logs {
+            tx.unbounded_send(l.clone()).unwrap();
+        }
+        wait_for(&handler, vec![(3, 0), (3, 1)]).await;
+        assert_eq!(handler.lock().unwrap().rollbacks, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn it_commits_confirmed_events_only() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            c.mine_empty(2);
+            c.mine(vec![scheduled_unstake(pool())]);
+            c.mine_empty(1);
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 4,
+                confirmations: 2,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut collector = Collector::default();
+        let last_block = scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(last_block, 2);
+        assert!(collector.events.is_empty());
+        assert_eq!(collector.pending, vec![(3, 0)]);
+
+        let mut handler = Arc::new(Mutex::new(collector));
+        let mut pos = LogPosition::block_end(last_block);
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert!(positions(&handler).is_empty());
+        assert_eq!(handler.lock().unwrap().pending, vec![(3, 0)]);
+
+        chain.lock().unwrap().mine_empty(1);
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(pos, LogPosition::block_end(3));
+        assert_eq!(positions(&handler), vec![(3, 0)]);
+        assert!(handler.lock().unwrap().pending.is_empty());
+
+        chain.lock().unwrap().mine(vec![scheduled_unstake(pool())]);
+        scanner.poll(&web3, &mut pos, &mut handler).await.unwrap();
+        assert_eq!(positions(&handler), vec![(3, 0)]);
+        assert_eq!(handler.lock().unwrap().pending, vec![(6, 0)]);
+    }
+
+    #[tokio::test]
+    async fn it_commits_confirmed_events_when_subscribed() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        chain.lock().unwrap().mine_empty(2);
+        let source = testrpc::serve(chain.clone()).replace("http://", "ws://");
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 4,
+                confirmations: 1,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let handler = Arc::new(Mutex::new(Collector::default()));
+        let rc = handler.clone();
+        tokio::spawn(async move {
+            let delay = Duration::from_millis(200);
+            scanner.watch_subscription(&source, 1, rc, delay).await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        chain.lock().unwrap().mine(vec![scheduled_unstake(pool())]);
+        for _ in 0..100 {
+            if !handler.lock().unwrap().pending.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(handler.lock().unwrap().pending, vec![(3, 0)]);
+        assert!(positions(&handler).is_empty());
+
+        chain.lock().unwrap().mine_empty(1);
+        wait_for(&handler, vec![(3, 0)]).await;
+        assert!(handler.lock().unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_scans_concurrently_in_order() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        let mut expected = vec![];
+        {
+            let mut c = chain.lock().unwrap();
+            for i in 1..=30u64 {
+                let logs: Vec<Log> = (0..i % 3).map(|_| scheduled_unstake(pool())).collect();
+                let n = c.mine(logs);
+                (0..i % 3).for_each(|log_index| expected.push((n, log_index)));
+            }
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 2,
+                concurrency: 8,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut collector = Collector::default();
+        let last_block = scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(last_block, 30);
+        let found: Vec<(u64, u64)> = collector
+            .events
+            .iter()
+            .map(|e| (e.block_number, e.log_index))
+            .collect();
+        assert_eq!(found, expected);
+        assert!(collector
+            .events
+            .iter()
+            .all(|e| e.tm == TestChain::timestamp(e.block_number)));
+    }
+
+    #[tokio::test]
+    async fn it_reads_blocks_time_in_batches() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            for _ in 0..(MAX_BATCH_CALLS + 20) {
+                c.mine(vec![scheduled_unstake(pool())]);
+            }
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 1000,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut collector = Collector::default();
+        scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(collector.events.len(), MAX_BATCH_CALLS + 20);
+        assert!(collector
+            .events
+            .iter()
+            .all(|e| e.tm == TestChain::timestamp(e.block_number)));
+
+        let c = chain.lock().unwrap();
+        let lookups: Vec<usize> = c
+            .requests
+            .iter()
+            .filter(|r| r.iter().any(|m| m == "eth_getBlockByHash"))
+            .map(|r| r.len())
+            .collect();
+        assert_eq!(lookups, vec![MAX_BATCH_CALLS, 20]);
+    }
+
+    #[tokio::test]
+    async fn it_splits_and_retries_log_queries() {
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            for _ in 0..12 {
+                c.mine(vec![scheduled_unstake(pool())]);
+            }
+            c.max_logs = Some(3);
+            c.failures = 2;
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 8,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        scanner.backoff = rpc::Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(10),
+            attempts: 5,
+        };
+        let mut collector = Collector::default();
+        let last_block = scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(last_block, 12);
+        let blocks: Vec<u64> = collector.events.iter().map(|e| e.block_number).collect();
+        assert_eq!(blocks, (1..=12).collect::<Vec<u64>>());
+        assert!(scanner.range_size.get() <= 4);
+        assert_eq!(chain.lock().unwrap().failures, 0);
+
+        // a single block that can't be read fails after the attempts
+        chain.lock().unwrap().max_logs = Some(0);
+        assert!(scanner.fetch_logs(&web3, 3, 3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rescans_from_cached_ranges() {
+        let dir = testrpc::TempDir::new("cache");
+        let cache_dir = dir.to_str().unwrap();
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            for _ in 0..10 {
+                c.mine(vec![scheduled_unstake(pool())]);
+            }
+            c.max_logs = Some(3);
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                cache_dir: cache_dir.to_owned(),
+                addr: vec![pool()],
+                genesis_block: 1,
+                batch_size: 8,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut collector = Collector::default();
+        scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(collector.events.len(), 10);
+
+        // the node is not asked for logs or blocks anymore
+        chain.lock().unwrap().max_logs = Some(0);
+        let mut scanner = Scanner::new(
+            1,
+            ScannerOptions {
+                cache_dir: cache_dir.to_owned(),
+                addr: vec![pool()],
+                genesis_block: 1,
+                max_block: Some(10),
+                batch_size: 3, // batch size doesn't matter for the cache
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        scanner.backoff.attempts = 1;
+        let mut collector = Collector::default();
+        let last_block = scanner.scan(&web3, &mut collector).await.unwrap();
+        assert_eq!(last_block, 10);
+        assert_eq!(collector.events.len(), 10);
     }
 }
@@ -0,0 +1,326 @@
+// Transport that answers from a recording of `--dump raw` instead of a node,
+// so the history could be processed again without any node, exactly as it was recorded.
+// The recording is JSONL file of `Record` lines.
+use jsonrpc_core as rpc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use web3::types::{Block, Log, H160, H256, U256, U64};
+use web3::{BatchTransport, RequestId, Transport};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Record {
+    Chain { chain_id: u64 },
+    Log { log: Box<Log>, tm: u64 },
+    Head { block_number: u64 },
+}
+
+pub fn is_replay(source: &str) -> bool {
+    source.ends_with(".jsonl")
+}
+
+// what the node would return, taken from the recording
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    pub chain_id: u64,
+    pub head: u64,
+    pub logs: Vec<Log>,
+    pub blocks_time: BTreeMap<H256, u64>,
+    /// hashes of the blocks with logs
+    pub blocks: BTreeMap<u64, H256>,
+}
+
+impl Recording {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let mut res = Self::default();
+        let f = File::open(path)?;
+        for (n, line) in BufReader::new(f).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Record>(&line) {
+                Ok(r) => res.push(r),
+                Err(e) => {
+                    return Err(anyhow::Error::msg(format!(
+                        "{}:{}: invalid record: {}",
+                        path,
+                        n + 1,
+                        e
+                    )))
+                }
+            }
+        }
+        tracing::info!(
+            "replaying {} logs of chain {} up to block {} from {}",
+            res.logs.len(),
+            res.chain_id,
+            res.head,
+            path
+        );
+        Ok(res)
+    }
+
+    pub fn push(&mut self, r: Record) {
+        match r {
+            Record::Chain { chain_id } => self.chain_id = chain_id,
+            Record::Head { block_number } => self.head = std::cmp::max(self.head, block_number),
+            Record::Log { log, tm } => {
+                if let (Some(hash), Some(number)) = (log.block_hash, log.block_number) {
+                    self.blocks_time.insert(hash, tm);
+                    self.blocks.insert(number.as_u64(), hash);
+                    self.head = std::cmp::max(self.head, number.as_u64());
+                }
+                self.logs.push(*log);
+            }
+        }
+    }
+
+    // blocks without logs were not recorded, they get the hashes made of their numbers
+    fn hash_of(&self, number: u64) -> H256 {
+        match self.blocks.get(&number) {
+            Some(hash) => *hash,
+            None => H256::from_low_u64_be(number),
+        }
+    }
+
+    fn block_json(&self, number: u64) -> Value {
+        if number > self.head {
+            return Value::Null;
+        }
+        let hash = self.hash_of(number);
+        let block: Block<H256> = Block {
+            hash: Some(hash),
+            parent_hash: if number > 0 {
+                self.hash_of(number - 1)
+            } else {
+                H256::zero()
+            },
+            number: Some(U64::from(number)),
+            timestamp: U256::from(self.blocks_time.get(&hash).copied().unwrap_or_default()),
+            ..Block::default()
+        };
+        serde_json::to_value(&block).unwrap()
+    }
+
+    fn block_number(&self, v: Option<&Value>) -> Result<u64, String> {
+        match v.and_then(|x| x.as_str()) {
+            None | Some("latest") | Some("pending") => Ok(self.head),
+            Some("earliest") => Ok(0),
+            Some(x) => {
+                u64::from_str_radix(x.trim_start_matches("0x"), 16).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn get_logs(&self, filter: &Value) -> Result<Value, String> {
+        let from = self.block_number(filter.get("fromBlock"))?;
+        let to = self.block_number(filter.get("toBlock"))?;
+        let addresses: Vec<H160> = match filter.get("address") {
+            Some(Value::Array(list)) => list
+                .iter()
+                .filter_map(|a| a.as_str().and_then(|s| H160::from_str(s).ok()))
+                .collect(),
+            Some(Value::String(s)) => H160::from_str(s).into_iter().collect(),
+            _ => vec![],
+        };
+        // only the first topic is filtered
+        let topics: Vec<H256> = match filter.get("topics").and_then(|x| x.get(0)) {
+            Some(Value::Array(list)) => list
+                .iter()
+                .filter_map(|t| t.as_str().and_then(|s| H256::from_str(s).ok()))
+                .collect(),
+            Some(Value::String(s)) => H256::from_str(s).into_iter().collect(),
+            _ => vec![],
+        };
+        let logs: Vec<&Log> = self
+            .logs
+            .iter()
+            .filter(|l| match l.block_number {
+                Some(n) => n.as_u64() >= from && n.as_u64() <= to,
+                None => false,
+            })
+            .filter(|l| addresses.is_empty() || addresses.contains(&l.address))
+            .filter(|l| topics.is_empty() || l.topics.first().map_or(false, |t| topics.contains(t)))
+            .collect();
+        Ok(serde_json::to_value(logs).unwrap())
+    }
+
+    fn result(&self, method: &str, params: &[Value]) -> web3::Result<Value> {
+        let res = match method {
+            "eth_chainId" => Ok(json!(format!("0x{:x}", self.chain_id))),
+            "eth_blockNumber" => Ok(json!(format!("0x{:x}", self.head))),
+            "eth_getLogs" => self.get_logs(params.first().unwrap_or(&Value::Null)),
+            "eth_getBlockByHash" => {
+                let hash = params.first().and_then(|x| x.as_str()).unwrap_or_default();
+                let hash = H256::from_str(hash).map_err(|e| e.to_string())?;
+                let number = self
+                    .blocks
+                    .iter()
+                    .find(|(_, h)| **h == hash)
+                    .map(|(n, _)| *n);
+                Ok(match number {
+                    Some(n) => self.block_json(n),
+                    None => Value::Null,
+                })
+            }
+            "eth_getBlockByNumber" => Ok(self.block_json(self.block_number(params.first())?)),
+            _ => {
+                return Err(web3::Error::Rpc(rpc::Error {
+                    code: rpc::ErrorCode::MethodNotFound,
+                    message: format!("{} is not available in the replay", method),
+                    data: None,
+                }))
+            }
+        };
+        res.map_err(|e| web3::Error::Rpc(rpc::Error::invalid_params(e)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Replay {
+    recording: Arc<Recording>,
+    id: Arc<AtomicUsize>,
+}
+
+impl Replay {
+    pub fn new(path: &str) -> anyhow::Result<Self> {
+        Ok(Self::from_recording(Recording::load(path)?))
+    }
+
+    pub fn from_recording(recording: Recording) -> Self {
+        Self {
+            recording: Arc::new(recording),
+            id: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+
+    fn call(&self, request: rpc::Call) -> web3::Result<Value> {
+        match request {
+            rpc::Call::MethodCall(c) => {
+                let params = match c.params {
+                    rpc::Params::Array(x) => x,
+                    _ => vec![],
+                };
+                self.recording.result(&c.method, &params)
+            }
+            _ => Err(web3::Error::Transport(
+                "only method calls are replayed".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Transport for Replay {
+    type Out = futures::future::Ready<web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, rpc::Call) {
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+        (id, web3::helpers::build_request(id, method, params))
+    }
+
+    fn send(&self, _id: RequestId, request: rpc::Call) -> Self::Out {
+        futures::future::ready(self.call(request))
+    }
+}
+
+impl BatchTransport for Replay {
+    type Batch = futures::future::Ready<web3::Result<Vec<web3::Result<Value>>>>;
+
+    fn send_batch<T>(&self, requests: T) -> Self::Batch
+    where
+        T: IntoIterator<Item = (RequestId, rpc::Call)>,
+    {
+        let res = requests.into_iter().map(|(_, c)| self.call(c)).collect();
+        futures::future::ready(Ok(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dumper;
+    use crate::reader::{get_transport, Scanner, ScannerOptions};
+    use crate::testrpc::{self, TestChain};
+    use std::sync::Mutex;
+    use web3::Web3;
+
+    #[tokio::test]
+    async fn it_replays_the_recording() {
+        let chain = Arc::new(Mutex::new(TestChain::new(3)));
+        let address = H160::from_low_u64_be(0xa3);
+        {
+            let mut c = chain.lock().unwrap();
+            for i in 0..12u64 {
+                let logs = (0..i % 3)
+                    .map(|_| testrpc::log(address, vec![H256::from_low_u64_be(i)], vec![]))
+                    .collect();
+                c.mine(logs);
+                c.mine_empty(i % 2);
+            }
+            // ScheduledUnstake without its fields, which fails to decode
+            let topic = hex_literal::hex!(
+                "06fbd2297e6f6f7701a9cf99685a6af911cab275ec5c75ac7aaaf13b5cf3d61f"
+            );
+            c.mine(vec![testrpc::log(address, vec![topic.into()], vec![1; 40])]);
+        }
+        let web3 = Web3::new(get_transport(testrpc::serve(chain.clone())).await.unwrap());
+        let mut scanner = Scanner::new(
+            3,
+            ScannerOptions {
+                addr: vec![address],
+                genesis_block: 1,
+                batch_size: 5,
+                concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let recorder = Arc::new(Mutex::new(dumper::Raw::new(vec![], 3)));
+        scanner.set_recorder(recorder.clone());
+        let head = scanner.scan(&web3, &mut dumper::Ignore).await.unwrap();
+        assert_eq!(scanner.failures().len(), 1);
+        recorder.lock().unwrap().done(head);
+        // the log that is not decoded is recorded as well
+        let recording = String::from_utf8(recorder.lock().unwrap().out.clone()).unwrap();
+        assert_eq!(
+            recording.lines().filter(|x| x.contains("\"log\"")).count(),
+            13
+        );
+
+        let dir = crate::testrpc::TempDir::new("replay");
+        let path = dir.join("history.jsonl");
+        std::fs::write(&path, &recording).unwrap();
+        let source = path.to_str().unwrap().to_owned();
+        assert!(is_replay(&source));
+        let web3 = Web3::new(get_transport(source).await.unwrap());
+        assert_eq!(web3.eth().chain_id().await.unwrap().as_u64(), 3);
+        // scanning the recording gives the same recording, whatever the batches are
+        let mut scanner = Scanner::new(
+            3,
+            ScannerOptions {
+                addr: vec![address],
+                genesis_block: 1,
+                batch_size: 7,
+                concurrency: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let replayed = Arc::new(Mutex::new(dumper::Raw::new(vec![], 3)));
+        scanner.set_recorder(replayed.clone());
+        let last_block = scanner.scan(&web3, &mut dumper::Ignore).await.unwrap();
+        assert_eq!(last_block, head);
+        assert_eq!(scanner.failures().len(), 1);
+        replayed.lock().unwrap().done(head);
+        let replayed = String::from_utf8(replayed.lock().unwrap().out.clone()).unwrap();
+        assert_eq!(replayed, recording);
+        assert!(web3.eth().gas_price().await.is_err());
+    }
+}
@@ -0,0 +1,229 @@
+// Helpers for the nodes that are not always reliable:
+// retrying with exponential backoff and adapting the size of the logs queries
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// exponential backoff between the attempts of the failing request
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub attempts: usize,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            attempts: 8,
+        }
+    }
+}
+
+impl Backoff {
+    // calls `f` until it succeeds, the error is not `retryable` or attempts are over
+    pub async fn retry<T, F, Fut>(
+        &self,
+        what: &str,
+        retryable: impl Fn(&web3::Error) -> bool,
+        mut f: F,
+    ) -> web3::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = web3::Result<T>>,
+    {
+        let mut delay = self.initial;
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(x) => return Ok(x),
+                Err(e) if attempt < self.attempts && retryable(&e) => {
+                    tracing::warn!(
+                        "{} failed (attempt {}): {}, retrying in {:?}",
+                        what,
+                        attempt,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = std::cmp::min(delay * 2, self.max);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// limits request duration, timeout becomes a transport error
+pub async fn with_timeout<T>(
+    timeout: Duration,
+    f: impl Future<Output = web3::Result<T>>,
+) -> web3::Result<T> {
+    match tokio::time::timeout(timeout, f).await {
+        Ok(res) => res,
+        Err(_) => Err(web3::Error::Transport(format!(
+            "request timed out after {:?}",
+            timeout
+        ))),
+    }
+}
+
+// whether the node refused to return logs because of the size of the range
+pub fn is_too_large(e: &web3::Error) -> bool {
+    let message = match e {
+        web3::Error::Rpc(e) => e.message.to_lowercase(),
+        web3::Error::Transport(msg) => msg.to_lowercase(),
+        _ => return false,
+    };
+    [
+        "more than",
+        "too many",
+        "too large",
+        "too wide",
+        "response size",
+        "limit exceeded",
+        "timed out",
+        "timeout",
+    ]
+    .iter()
+    .any(|x| message.contains(x))
+}
+
+// whether the same request could succeed later
+pub fn is_transient(e: &web3::Error) -> bool {
+    match e {
+        web3::Error::Unreachable
+        | web3::Error::Transport(_)
+        | web3::Error::Io(_)
+        | web3::Error::InvalidResponse(_) => true,
+        // server errors, internal errors and rate limits
+        web3::Error::Rpc(e) => matches!(e.code.code(), -32099..=-32000 | -32603 | 429),
+        _ => false,
+    }
+}
+
+// size of the blocks range for the logs query, shared by concurrent readers.
+// It is halved when the node can't return the range and doubled over quiet stretches
+#[derive(Debug)]
+pub struct RangeSize {
+    size: AtomicU64,
+    max: u64,
+}
+
+impl RangeSize {
+    pub fn new(initial: u64, max: u64) -> Self {
+        let max = std::cmp::max(max, 1);
+        Self {
+            size: AtomicU64::new(initial.clamp(1, max)),
+            max,
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    // range of `failed` blocks was too large
+    pub fn shrink(&self, failed: u64) {
+        let size = std::cmp::max(failed / 2, 1);
+        self.size.fetch_min(size, Ordering::Relaxed);
+    }
+
+    // range of `succeeded` blocks was quiet enough to try larger one
+    pub fn grow(&self, succeeded: u64) {
+        let size = std::cmp::min(succeeded.saturating_mul(2), self.max);
+        let _ = self
+            .size
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                if succeeded >= current && size > current {
+                    Some(size)
+                } else {
+                    None
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn rpc_error(code: i64, message: &str) -> web3::Error {
+        let e = serde_json::json!({"code": code, "message": message});
+        web3::Error::Rpc(serde_json::from_value(e).unwrap())
+    }
+
+    #[test]
+    fn it_classifies_errors() {
+        let e = rpc_error(-32005, "query returned more than 10000 results");
+        assert!(is_too_large(&e));
+        assert!(is_transient(&e));
+        let e = web3::Error::Transport("request timed out after 1s".to_owned());
+        assert!(is_too_large(&e));
+        let e = rpc_error(-32603, "internal error");
+        assert!(!is_too_large(&e));
+        assert!(is_transient(&e));
+        assert!(!is_transient(&rpc_error(-32602, "invalid params")));
+        assert!(!is_transient(&web3::Error::Decoder("bad".to_owned())));
+    }
+
+    #[test]
+    fn it_adapts_range_size() {
+        let size = RangeSize::new(100, 1000);
+        size.grow(50); // smaller range says nothing
+        assert_eq!(size.get(), 100);
+        size.grow(100);
+        assert_eq!(size.get(), 200);
+        size.shrink(200);
+        assert_eq!(size.get(), 100);
+        size.shrink(1);
+        assert_eq!(size.get(), 1);
+        for _ in 0..20 {
+            size.grow(size.get());
+        }
+        assert_eq!(size.get(), 1000);
+    }
+
+    #[tokio::test]
+    async fn it_retries_with_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+            attempts: 4,
+        };
+        let calls = AtomicUsize::new(0);
+        let res = backoff
+            .retry("test", is_transient, || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(web3::Error::Unreachable),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(res.unwrap(), 2);
+
+        calls.store(0, Ordering::SeqCst);
+        let res: web3::Result<()> = backoff
+            .retry("test", is_transient, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(web3::Error::Unreachable)
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        calls.store(0, Ordering::SeqCst);
+        let res: web3::Result<()> = backoff
+            .retry("test", is_transient, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(web3::Error::Decoder("bad".to_owned()))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
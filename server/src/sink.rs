@@ -0,0 +1,339 @@
+// Sinks of the events: handlers that run in their own tasks behind bounded queues,
+// so the state, websocket broadcast and other consumers are fed independently
+// and a slow one doesn't stall reading the chain
+use crate::reader::EventHandler;
+use async_trait::async_trait;
+use client::state::OnChainEvent;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use web3::types::Log;
+
+// number of messages that are queued for the sink
+pub const QUEUE_SIZE: usize = 1024;
+
+// what happens when the queue of the sink is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// reading waits for the sink, nothing is lost
+    Wait,
+    /// events are dropped until the sink catches up. It gets the latest pending events
+    /// and the earliest rollback after its queue instead
+    Drop,
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    On(Box<(OnChainEvent, Log)>),
+    Rollback(u64),
+    Pending(Vec<OnChainEvent>),
+}
+
+// what the dropping sink gets after its queue, when the queue was full.
+// Only the latest pending events and the earliest rollback are kept
+#[derive(Debug, Default)]
+struct Behind {
+    rollback: Option<u64>,
+    pending: Option<Vec<OnChainEvent>>,
+}
+
+impl Behind {
+    fn is_empty(&self) -> bool {
+        self.rollback.is_none() && self.pending.is_none()
+    }
+}
+
+pub struct Sink {
+    pub name: String,
+    overflow: Overflow,
+    tx: mpsc::Sender<Message>,
+    behind: Arc<Mutex<Behind>>,
+    // wakes the task when the sink is behind
+    resync: Arc<Notify>,
+    /// messages that were dropped on overflow
+    pub dropped: usize,
+}
+
+impl Sink {
+    // starts the task that passes queued messages to the handler
+    pub fn spawn(
+        name: &str,
+        capacity: usize,
+        overflow: Overflow,
+        mut handler: impl EventHandler + 'static,
+    ) -> Self {
+        let (tx, mut rx) = mpsc::channel(capacity);
+        let behind = Arc::new(Mutex::new(Behind::default()));
+        let resync = Arc::new(Notify::new());
+        let (task_behind, task_resync) = (behind.clone(), resync.clone());
+        tokio::spawn(async move {
+            loop {
+                // the queued messages are older than the ones the sink is behind with
+                tokio::select! {
+                    biased;
+                    m = rx.recv() => match m {
+                        Some(Message::On(x)) => handler.on(x.0, x.1).await,
+                        Some(Message::Rollback(block_number)) => {
+                            handler.rollback(block_number).await
+                        }
+                        Some(Message::Pending(events)) => handler.pending(events).await,
+                        None => break,
+                    },
+                    _ = task_resync.notified() => {
+                        let behind = std::mem::take(&mut *task_behind.lock().unwrap());
+                        if let Some(block_number) = behind.rollback {
+                            handler.rollback(block_number).await;
+                        }
+                        if let Some(events) = behind.pending {
+                            handler.pending(events).await;
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            name: name.to_owned(),
+            overflow,
+            tx,
+            behind,
+            resync,
+            dropped: 0,
+        }
+    }
+
+    // the message that doesn't fit into the queue puts the sink behind. Rollbacks and pending
+    // events are kept for it, or it would keep the events of the reorganized blocks.
+    // Events are dropped until the sink takes them, so it never gets them out of order
+    fn try_send(&self, m: Message) -> Result<(), &'static str> {
+        let mut behind = self.behind.lock().unwrap();
+        let m = if behind.is_empty() {
+            match self.tx.try_send(m) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::TrySendError::Full(m)) => m,
+                Err(mpsc::error::TrySendError::Closed(_)) => return Err("closed"),
+            }
+        } else {
+            m
+        };
+        match m {
+            Message::On(_) => return Err("full"),
+            Message::Rollback(block_number) => {
+                let earliest = behind
+                    .rollback
+                    .map_or(block_number, |x| x.min(block_number));
+                behind.rollback = Some(earliest);
+            }
+            Message::Pending(events) => behind.pending = Some(events),
+        }
+        self.resync.notify_one();
+        Ok(())
+    }
+
+    async fn send(&mut self, m: Message) {
+        let res = match self.overflow {
+            Overflow::Wait => self.tx.send(m).await.map_err(|_| "closed"),
+            Overflow::Drop => self.try_send(m),
+        };
+        if let Err(reason) = res {
+            if self.dropped == 0 {
+                tracing::warn!("sink {} is {}, dropping messages", self.name, reason);
+            }
+            self.dropped += 1;
+        } else if self.dropped > 0 && self.behind.lock().unwrap().is_empty() {
+            tracing::warn!("sink {} is back, {} dropped", self.name, self.dropped);
+            self.dropped = 0;
+        }
+    }
+}
+
+// passes every event to all sinks
+#[derive(Default)]
+pub struct FanOut {
+    pub sinks: Vec<Sink>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, sink: Sink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    async fn send(&mut self, m: Message) {
+        for sink in &mut self.sinks {
+            sink.send(m.clone()).await;
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for FanOut {
+    async fn on(&mut self, e: OnChainEvent, l: Log) {
+        self.send(Message::On(Box::new((e, l)))).await;
+    }
+
+    async fn rollback(&mut self, block_number: u64) {
+        self.send(Message::Rollback(block_number)).await;
+    }
+
+    async fn pending(&mut self, events: Vec<OnChainEvent>) {
+        self.send(Message::Pending(events)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::events::Api3;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use web3::types::{H160, H256};
+
+    // keeps the blocks of the events, waiting before each of them
+    struct Recorder {
+        blocks: Arc<Mutex<Vec<u64>>>,
+        rollbacks: Arc<Mutex<Vec<u64>>>,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl EventHandler for Recorder {
+        async fn on(&mut self, e: OnChainEvent, _: Log) {
+            tokio::time::sleep(self.delay).await;
+            self.blocks.lock().unwrap().push(e.block_number);
+        }
+
+        async fn rollback(&mut self, block_number: u64) {
+            self.blocks.lock().unwrap().retain(|&b| b <= block_number);
+            self.rollbacks.lock().unwrap().push(block_number);
+        }
+    }
+
+    fn event(block_number: u64) -> OnChainEvent {
+        OnChainEvent {
+            entry: Api3::Unknown,
+            tm: 0,
+            block_number,
+            tx: H256::from_low_u64_be(block_number),
+            log_index: 0,
+            version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn it_feeds_sinks_independently() {
+        let fast = Arc::new(Mutex::new(vec![]));
+        let slow = Arc::new(Mutex::new(vec![]));
+        let slow_rollbacks = Arc::new(Mutex::new(vec![]));
+        let recorder =
+            |blocks: &Arc<Mutex<Vec<u64>>>, rollbacks: &Arc<Mutex<Vec<u64>>>, delay| Recorder {
+                blocks: blocks.clone(),
+                rollbacks: rollbacks.clone(),
+                delay: Duration::from_millis(delay),
+            };
+        let mut fan_out = FanOut::new()
+            .with(Sink::spawn(
+                "fast",
+                2,
+                Overflow::Wait,
+                recorder(&fast, &Arc::new(Mutex::new(vec![])), 0),
+            ))
+            .with(Sink::spawn(
+                "slow",
+                2,
+                Overflow::Drop,
+                recorder(&slow, &slow_rollbacks, 100),
+            ));
+        let log = crate::testrpc::log(H160::zero(), vec![], vec![]);
+        let start = std::time::Instant::now();
+        for block in 1..=20 {
+            fan_out.on(event(block), log.clone()).await;
+        }
+        // the slow sink doesn't hold the events back
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(fan_out.sinks[1].dropped > 0);
+        // and it still gets the rollback
+        fan_out.rollback(15).await;
+        for _ in 0..100 {
+            if fast.lock().unwrap().len() == 15 && !slow_rollbacks.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*fast.lock().unwrap(), (1..=15).collect::<Vec<u64>>());
+        assert_eq!(*slow_rollbacks.lock().unwrap(), vec![15]);
+        assert!(slow.lock().unwrap().len() < 20);
+    }
+
+    // doesn't handle anything until the gate is opened
+    struct Stuck {
+        gate: Arc<tokio::sync::Semaphore>,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Stuck {
+        async fn see(&mut self, what: String) {
+            self.gate.acquire().await.unwrap().forget();
+            self.seen.lock().unwrap().push(what);
+        }
+    }
+
+    #[async_trait]
+    impl EventHandler for Stuck {
+        async fn on(&mut self, e: OnChainEvent, _: Log) {
+            self.see(format!("on {}", e.block_number)).await;
+        }
+
+        async fn rollback(&mut self, block_number: u64) {
+            self.see(format!("rollback {}", block_number)).await;
+        }
+
+        async fn pending(&mut self, events: Vec<OnChainEvent>) {
+            self.see(format!("pending {}", events.len())).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn it_keeps_a_stuck_sink_behind() {
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let seen = Arc::new(Mutex::new(vec![]));
+        let stuck = Stuck {
+            gate: gate.clone(),
+            seen: seen.clone(),
+        };
+        let mut fan_out = FanOut::new().with(Sink::spawn("stuck", 2, Overflow::Drop, stuck));
+        let log = crate::testrpc::log(H160::zero(), vec![], vec![]);
+        let producer = async {
+            for block in 1..=10 {
+                fan_out.on(event(block), log.clone()).await;
+                fan_out.pending(vec![event(block + 1)]).await;
+            }
+            fan_out.rollback(8).await;
+            fan_out.rollback(5).await;
+            fan_out.on(event(6), log.clone()).await;
+            fan_out.pending(vec![event(7), event(8)]).await;
+        };
+        // the producer is not held by the sink
+        tokio::time::timeout(Duration::from_secs(1), producer)
+            .await
+            .unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+
+        gate.add_permits(100);
+        for _ in 0..100 {
+            if seen.lock().unwrap().len() >= 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the queued messages, then the earliest rollback and the latest pending events.
+        // The events after the full queue are dropped
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["on 1", "pending 1", "rollback 5", "pending 2"]
+        );
+    }
+}
@@ -0,0 +1,1109 @@
+// Append-only cache of the logs and block timestamps that were read from the node.
+//
+// The file starts with MAGIC and continues with frames of
+// `[payload length: u32][crc32 of payload: u32][payload]`, little-endian.
+// A frame holds either the logs of a scanned range of blocks or a set of block timestamps.
+// Addresses and hashes are written in full only the first time they appear in the frame,
+// later they are referred by their index, so every frame is decoded on its own.
+// Only the scanned ranges with the offsets of their frames and the timestamps are kept
+// in memory, the logs are read from the file when they are asked for.
+// A frame that was written partially (i.e. the process was killed) is cut off on opening.
+use crate::args::CacheMode;
+use crc32fast::Hasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use web3::types::{Bytes, Log, H160, H256, U256, U64};
+
+pub const MAGIC: &[u8; 8] = b"A3TCACH2";
+// compaction merges the frames of a range while they have less logs
+pub const COMPACT_LOGS: usize = 10_000;
+
+const FRAME_LOGS: u8 = 1;
+const FRAME_BLOCKS_TIME: u8 = 2;
+
+// what the frame that was read contained
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    Logs { from: u64, to: u64, logs: Vec<Log> },
+    BlocksTime(Vec<(H256, u64)>),
+}
+
+// adds the range, merging it with the ranges that overlap or touch it
+fn merge_range(ranges: &mut BTreeMap<u64, u64>, from: u64, to: u64) {
+    let (mut from, mut to) = (from, to);
+    let touching: Vec<(u64, u64)> = ranges
+        .range(..=to.saturating_add(1))
+        .rev()
+        .take_while(|(_, &end)| end.saturating_add(1) >= from)
+        .map(|(&start, &end)| (start, end))
+        .collect();
+    for (start, end) in touching {
+        ranges.remove(&start);
+        from = std::cmp::min(from, start);
+        to = std::cmp::max(to, end);
+    }
+    ranges.insert(from, to);
+}
+
+fn overlaps(ranges: &BTreeMap<u64, u64>, from: u64, to: u64) -> bool {
+    match ranges.range(..=to).next_back() {
+        Some((_, &end)) => end >= from,
+        None => false,
+    }
+}
+
+// result of checking the store file, which is not changed by the check
+#[derive(Debug, Default)]
+pub struct Report {
+    pub size: u64,
+    pub frames: usize,
+    /// ranges of blocks that were written again over the scanned ones
+    pub overlaps: Vec<(u64, u64)>,
+    /// offset where the file is broken and the reason
+    pub broken: Option<(u64, String)>,
+    pub ranges: BTreeMap<u64, u64>,
+    pub blocks_time: usize,
+}
+
+impl Report {
+    // ranges of the blocks between `from` and `to` that were not scanned
+    pub fn gaps(&self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let store = Store {
+            ranges: self.ranges.clone(),
+            ..Store::default()
+        };
+        store.gaps(from, to)
+    }
+}
+
+pub fn contracts_checksum(addresses: &[H160]) -> u32 {
+    let mut hasher = Hasher::new();
+    addresses.iter().for_each(|a| {
+        hasher.update(format!("{:?}", a).as_bytes());
+    });
+    hasher.finalize()
+}
+
+// cache files of the chain that were saved for another set of contracts
+pub fn foreign_files(cache_dir: &str, chain_id: u64, addresses: &[H160]) -> Vec<PathBuf> {
+    let checksum = contracts_checksum(addresses).to_string();
+    let prefix = format!("chain{}-", chain_id);
+    let entries = match std::fs::read_dir(cache_dir) {
+        Ok(x) => x,
+        Err(_) => return vec![],
+    };
+    let mut res: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // the last part is the checksum both in the store and in the JSON batches
+            let stem = match name.strip_suffix(".cache") {
+                Some(x) => x,
+                None => match name.strip_suffix(".json") {
+                    Some(x) => x,
+                    None => return false,
+                },
+            };
+            stem.starts_with(&prefix) && stem.rsplit('-').next() != Some(checksum.as_str())
+        })
+        .map(|entry| entry.path())
+        .collect();
+    res.sort();
+    res
+}
+
+// values that are written in full once, then referred by index
+#[derive(Debug, Clone, Default)]
+struct Dict<K> {
+    index: HashMap<K, u64>,
+    values: Vec<K>,
+}
+
+impl<K: Copy + Eq + Hash> Dict<K> {
+    fn push(&mut self, value: K) {
+        self.index.insert(value, self.values.len() as u64);
+        self.values.push(value);
+    }
+}
+
+// values that are referred in one frame
+#[derive(Debug, Default)]
+struct Refs {
+    addresses: Dict<H160>,
+    hashes: Dict<H256>,
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        buf.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    buf.push(x as u8);
+}
+
+// reference of the value: 0 is followed by the new value, n is the value at n-1
+fn put_ref<K: Copy + Eq + Hash + AsRef<[u8]>>(buf: &mut Vec<u8>, dict: &mut Dict<K>, value: K) {
+    match dict.index.get(&value) {
+        Some(i) => put_varint(buf, i + 1),
+        None => {
+            put_varint(buf, 0);
+            buf.extend_from_slice(value.as_ref());
+            dict.push(value);
+        }
+    }
+}
+
+fn put_u256(buf: &mut Vec<u8>, x: U256) {
+    let mut bytes = [0u8; 32];
+    x.to_big_endian(&mut bytes);
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    buf.push((32 - skip) as u8);
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+// ABI data is made of 32-byte words, which are mostly zeros on the left
+fn put_data(buf: &mut Vec<u8>, data: &[u8]) {
+    put_varint(buf, data.len() as u64);
+    if data.len() % 32 != 0 {
+        buf.extend_from_slice(data);
+        return;
+    }
+    for word in data.chunks(32) {
+        let skip = word.iter().take_while(|b| **b == 0).count();
+        buf.push(skip as u8);
+        buf.extend_from_slice(&word[skip..]);
+    }
+}
+
+fn put_log(buf: &mut Vec<u8>, refs: &mut Refs, l: &Log) {
+    let flags: u8 = [
+        l.block_hash.is_some(),
+        l.block_number.is_some(),
+        l.transaction_hash.is_some(),
+        l.transaction_index.is_some(),
+        l.log_index.is_some(),
+        l.transaction_log_index.is_some(),
+        l.log_type.is_some(),
+        l.removed.is_some(),
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |acc, (i, &set)| if set { acc | (1 << i) } else { acc });
+    buf.push(flags);
+    put_ref(buf, &mut refs.addresses, l.address);
+    put_varint(buf, l.topics.len() as u64);
+    for topic in &l.topics {
+        put_ref(buf, &mut refs.hashes, *topic);
+    }
+    put_data(buf, &l.data.0);
+    if let Some(x) = l.block_hash {
+        put_ref(buf, &mut refs.hashes, x);
+    }
+    if let Some(x) = l.block_number {
+        put_varint(buf, x.as_u64());
+    }
+    if let Some(x) = l.transaction_hash {
+        put_ref(buf, &mut refs.hashes, x);
+    }
+    if let Some(x) = l.transaction_index {
+        put_varint(buf, x.as_u64());
+    }
+    if let Some(x) = l.log_index {
+        put_u256(buf, x);
+    }
+    if let Some(x) = l.transaction_log_index {
+        put_u256(buf, x);
+    }
+    if let Some(x) = &l.log_type {
+        put_varint(buf, x.len() as u64);
+        buf.extend_from_slice(x.as_bytes());
+    }
+    if let Some(x) = l.removed {
+        buf.push(x as u8);
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(anyhow::Error::msg("unexpected end of frame"));
+        }
+        let res = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(res)
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn varint(&mut self) -> anyhow::Result<u64> {
+        let mut x: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            x |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Ok(x);
+            }
+        }
+        Err(anyhow::Error::msg("varint overflow"))
+    }
+
+    fn reference<K: Copy + Eq + Hash>(
+        &mut self,
+        dict: &mut Dict<K>,
+        size: usize,
+        from_slice: fn(&[u8]) -> K,
+    ) -> anyhow::Result<K> {
+        match self.varint()? {
+            0 => {
+                let value = from_slice(self.bytes(size)?);
+                dict.push(value);
+                Ok(value)
+            }
+            i => match dict.values.get(i as usize - 1) {
+                Some(value) => Ok(*value),
+                None => Err(anyhow::Error::msg(format!("unknown reference {}", i))),
+            },
+        }
+    }
+
+    fn u256(&mut self) -> anyhow::Result<U256> {
+        let len = self.byte()? as usize;
+        if len > 32 {
+            return Err(anyhow::Error::msg("invalid number length"));
+        }
+        Ok(U256::from_big_endian(self.bytes(len)?))
+    }
+
+    fn data(&mut self) -> anyhow::Result<Vec<u8>> {
+        let len = self.varint()? as usize;
+        if len % 32 != 0 {
+            return Ok(self.bytes(len)?.to_vec());
+        }
+        let mut data = Vec::with_capacity(len);
+        for _ in 0..len / 32 {
+            let skip = self.byte()? as usize;
+            if skip > 32 {
+                return Err(anyhow::Error::msg("invalid word"));
+            }
+            data.extend_from_slice(&[0u8; 32][..skip]);
+            data.extend_from_slice(self.bytes(32 - skip)?);
+        }
+        Ok(data)
+    }
+
+    fn log(&mut self, refs: &mut Refs) -> anyhow::Result<Log> {
+        let flags = self.byte()?;
+        let has = |i: u8| flags & (1 << i) != 0;
+        let address = self.reference(&mut refs.addresses, 20, H160::from_slice)?;
+        let mut topics = vec![];
+        for _ in 0..self.varint()? {
+            topics.push(self.reference(&mut refs.hashes, 32, H256::from_slice)?);
+        }
+        let data = Bytes(self.data()?);
+        let mut l = Log {
+            address,
+            topics,
+            data,
+            block_hash: None,
+            block_number: None,
+            transaction_hash: None,
+            transaction_index: None,
+            log_index: None,
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        };
+        if has(0) {
+            l.block_hash = Some(self.reference(&mut refs.hashes, 32, H256::from_slice)?);
+        }
+        if has(1) {
+            l.block_number = Some(U64::from(self.varint()?));
+        }
+        if has(2) {
+            l.transaction_hash = Some(self.reference(&mut refs.hashes, 32, H256::from_slice)?);
+        }
+        if has(3) {
+            l.transaction_index = Some(U64::from(self.varint()?));
+        }
+        if has(4) {
+            l.log_index = Some(self.u256()?);
+        }
+        if has(5) {
+            l.transaction_log_index = Some(self.u256()?);
+        }
+        if has(6) {
+            let len = self.varint()? as usize;
+            l.log_type = Some(String::from_utf8(self.bytes(len)?.to_vec())?);
+        }
+        if has(7) {
+            l.removed = Some(self.byte()? != 0);
+        }
+        Ok(l)
+    }
+}
+
+fn decode_frame(payload: &[u8]) -> anyhow::Result<Frame> {
+    let mut d = Decoder {
+        buf: payload,
+        pos: 0,
+    };
+    let mut refs = Refs::default();
+    match d.byte()? {
+        FRAME_LOGS => {
+            let from = d.varint()?;
+            let to = d.varint()?;
+            let mut logs = vec![];
+            for _ in 0..d.varint()? {
+                logs.push(d.log(&mut refs)?);
+            }
+            Ok(Frame::Logs { from, to, logs })
+        }
+        FRAME_BLOCKS_TIME => {
+            let mut blocks_time = vec![];
+            for _ in 0..d.varint()? {
+                let hash = d.reference(&mut refs.hashes, 32, H256::from_slice)?;
+                blocks_time.push((hash, d.varint()?));
+            }
+            Ok(Frame::BlocksTime(blocks_time))
+        }
+        kind => Err(anyhow::Error::msg(format!("unknown frame {}", kind))),
+    }
+}
+
+// reads as much of the buffer as there is
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(x) => n += x,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+fn has_magic(mut r: impl Read) -> std::io::Result<bool> {
+    let mut magic = [0u8; 8];
+    Ok(read_full(&mut r, &mut magic)? == magic.len() && &magic == MAGIC)
+}
+
+// payload of the next frame, none at the end. The length is not trusted
+// before the checksum is checked, so the payload is read as far as it goes
+fn read_payload(r: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 8];
+    match read_full(r, &mut header)? {
+        0 => return Ok(None),
+        8 => {}
+        _ => return Err(anyhow::Error::msg("incomplete frame header")),
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut payload = vec![];
+    r.by_ref().take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Err(anyhow::Error::msg("incomplete frame"));
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(anyhow::Error::msg("checksum mismatch"));
+    }
+    Ok(Some(payload))
+}
+
+// reads the frames of the file one by one, giving them with their offsets.
+// Returns the length of the valid part of the file and the reason why the rest is not valid.
+// Failures of reading the file are returned as errors
+fn walk(file: &File, mut visit: impl FnMut(u64, Frame)) -> anyhow::Result<(u64, Option<String>)> {
+    let mut reader = BufReader::new(file);
+    let mut pos = MAGIC.len() as u64;
+    reader.seek(SeekFrom::Start(pos))?;
+    loop {
+        let payload = match read_payload(&mut reader) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Ok((pos, None)),
+            Err(e) if e.is::<std::io::Error>() => return Err(e),
+            Err(e) => return Ok((pos, Some(e.to_string()))),
+        };
+        match decode_frame(&payload) {
+            Ok(frame) => visit(pos, frame),
+            Err(e) => return Ok((pos, Some(e.to_string()))),
+        }
+        pos += 8 + payload.len() as u64;
+    }
+}
+
+// what was read from the node: logs of the ranges of blocks and the timestamps of blocks
+#[derive(Debug, Default)]
+pub struct Store {
+    // file to append to, none for the cache that is kept in memory only
+    file: Option<File>,
+    path: Option<PathBuf>,
+    // frames of the cache that is kept in memory only
+    memory: Vec<u8>,
+    // length of the valid part of the file
+    len: u64,
+    /// scanned ranges of blocks, by their first block. Adjacent ranges are merged
+    pub ranges: BTreeMap<u64, u64>,
+    // parts of the frames that were not written over by the later frames,
+    // by their first block: the last block and the offset of the frame
+    frames: BTreeMap<u64, (u64, u64)>,
+    pub blocks_time: BTreeMap<H256, u64>,
+}
+
+impl Store {
+    // cache that is not saved anywhere
+    pub fn memory() -> Self {
+        Self::default()
+    }
+
+    // name of the file is bound to the watched contracts,
+    // as the logs of the other contracts were not read
+    pub fn file_name(cache_dir: &str, chain_id: u64, addresses: &[H160]) -> PathBuf {
+        let checksum = contracts_checksum(addresses);
+        Path::new(cache_dir).join(format!("chain{}-{}.cache", chain_id, checksum))
+    }
+
+    // opens the store file in the cache folder, or the memory store if there is no folder
+    pub fn open_dir(cache_dir: &str, chain_id: u64, addresses: &[H160]) -> anyhow::Result<Self> {
+        if cache_dir.is_empty() {
+            return Ok(Self::memory());
+        }
+        let path = Self::file_name(cache_dir, chain_id, addresses);
+        let existed = path.exists();
+        let mut store = Self::open(&path)?;
+        if !existed {
+            store.import_legacy(cache_dir, chain_id, addresses)?;
+        }
+        Ok(store)
+    }
+
+    // reads the file, cutting off the broken tail of it
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut store = Self {
+            path: Some(path.to_owned()),
+            ..Self::default()
+        };
+        let size = file.metadata()?.len();
+        if size == 0 {
+            file.write_all(MAGIC)?;
+            store.len = MAGIC.len() as u64;
+            store.file = Some(file);
+            return Ok(store);
+        }
+        if !has_magic(&file)? {
+            return Err(anyhow::Error::msg(format!(
+                "{} is not a cache file",
+                path.display()
+            )));
+        }
+        let (len, broken) = walk(&file, |offset, frame| store.add_frame(offset, frame))?;
+        if let Some(e) = broken {
+            tracing::warn!(
+                "cache {} is broken at {}: {}, {} bytes are dropped",
+                path.display(),
+                len,
+                e,
+                size - len
+            );
+            file.set_len(len)?;
+        }
+        store.len = len;
+        store.file = Some(file);
+        tracing::info!(
+            "cache {} loaded: {} ranges, {} frames, {} timestamps",
+            path.display(),
+            store.ranges.len(),
+            store.frames.len(),
+            store.blocks_time.len()
+        );
+        Ok(store)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // last block of the scanned range that contains the block
+    pub fn covered(&self, block_number: u64) -> Option<u64> {
+        match self.ranges.range(..=block_number).next_back() {
+            Some((_, &to)) if to >= block_number => Some(to),
+            _ => None,
+        }
+    }
+
+    // last block of the frame that contains the block, the logs are read a frame at a time
+    pub fn frame_end(&self, block_number: u64) -> Option<u64> {
+        match self.frames.range(..=block_number).next_back() {
+            Some((_, &(to, _))) if to >= block_number => Some(to),
+            _ => None,
+        }
+    }
+
+    // parts of the frames with the blocks, cut to them: first and last block and the offset
+    fn frames_of(&self, from: u64, to: u64) -> Vec<(u64, u64, u64)> {
+        let first = match self.frames.range(..=from).next_back() {
+            Some((&start, _)) => start,
+            None => from,
+        };
+        self.frames
+            .range(first..=to)
+            .filter(|(_, (end, _))| *end >= from)
+            .map(|(&start, &(end, offset))| {
+                let start = std::cmp::max(start, from);
+                (start, std::cmp::min(end, to), offset)
+            })
+            .collect()
+    }
+
+    // logs of the blocks, if all of them were scanned
+    pub fn get_logs(&self, from: u64, to: u64) -> anyhow::Result<Option<Vec<Log>>> {
+        match self.covered(from) {
+            Some(end) if end >= to => {}
+            _ => return Ok(None),
+        }
+        let mut res = vec![];
+        for (start, end, offset) in self.frames_of(from, to) {
+            if let Frame::Logs { from, logs, .. } = self.read_frame_at(offset)? {
+                res.extend(logs.into_iter().filter(|l| {
+                    let block_number = l.block_number.map_or(from, |x| x.as_u64());
+                    block_number >= start && block_number <= end
+                }));
+            }
+        }
+        res.sort_by_key(|l| l.block_number);
+        Ok(Some(res))
+    }
+
+    // ranges of the blocks between `from` and `to` that were not scanned
+    pub fn gaps(&self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let mut res = vec![];
+        let mut next = from;
+        while next <= to {
+            match self.covered(next) {
+                Some(end) => next = end.saturating_add(1),
+                None => {
+                    let end = match self.ranges.range(next..).next() {
+                        Some((&start, _)) if start <= to => start - 1,
+                        _ => to,
+                    };
+                    res.push((next, end));
+                    next = end.saturating_add(1);
+                }
+            }
+            if next == 0 {
+                break; // u64::MAX was covered
+            }
+        }
+        res
+    }
+
+    // checks the file without changing it
+    pub fn verify(path: &Path) -> anyhow::Result<Report> {
+        let file = File::open(path)?;
+        let mut report = Report {
+            size: file.metadata()?.len(),
+            ..Report::default()
+        };
+        if !has_magic(&file)? {
+            report.broken = Some((0, "not a cache file".to_owned()));
+            return Ok(report);
+        }
+        let mut blocks_time = HashSet::new();
+        let (len, broken) = walk(&file, |_, frame| {
+            match frame {
+                Frame::Logs { from, to, .. } => {
+                    if overlaps(&report.ranges, from, to) {
+                        report.overlaps.push((from, to));
+                    }
+                    merge_range(&mut report.ranges, from, to);
+                }
+                Frame::BlocksTime(x) => blocks_time.extend(x.into_iter().map(|(hash, _)| hash)),
+            }
+            report.frames += 1;
+        })?;
+        report.broken = broken.map(|reason| (len, reason));
+        report.blocks_time = blocks_time.len();
+        Ok(report)
+    }
+
+    // rewrites the file without the logs that were written over. Frames of the range
+    // are merged while they have less than COMPACT_LOGS logs, timestamps go to one frame
+    pub fn compact(&mut self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(x) => x.clone(),
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("tmp");
+        if tmp.exists() {
+            std::fs::remove_file(&tmp)?;
+        }
+        let mut compacted = Self::open(&tmp)?;
+        for (&from, &to) in &self.ranges {
+            let mut start = from;
+            let mut logs = vec![];
+            for (first, last, _) in self.frames_of(from, to) {
+                let part = self.get_logs(first, last)?.unwrap_or_default();
+                if !logs.is_empty() && logs.len() + part.len() > COMPACT_LOGS {
+                    compacted.append_logs(start, first - 1, &logs)?;
+                    start = first;
+                    logs.clear();
+                }
+                logs.extend(part);
+            }
+            compacted.append_logs(start, to, &logs)?;
+        }
+        compacted.append_blocks_time(&self.blocks_time)?;
+        if let Some(f) = &compacted.file {
+            f.sync_all()?;
+        }
+        std::fs::rename(&tmp, &path)?;
+        compacted.path = Some(path);
+        *self = compacted;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    pub fn append_logs(&mut self, from: u64, to: u64, logs: &[Log]) -> anyhow::Result<()> {
+        let mut refs = Refs::default();
+        let mut buf = vec![FRAME_LOGS];
+        put_varint(&mut buf, from);
+        put_varint(&mut buf, to);
+        put_varint(&mut buf, logs.len() as u64);
+        for l in logs {
+            put_log(&mut buf, &mut refs, l);
+        }
+        let offset = self.write_frame(&buf)?;
+        self.add_logs(from, to, offset);
+        Ok(())
+    }
+
+    // saves the timestamps of the blocks that were not known yet
+    pub fn append_blocks_time(&mut self, blocks_time: &BTreeMap<H256, u64>) -> anyhow::Result<()> {
+        let new: Vec<(H256, u64)> = blocks_time
+            .iter()
+            .filter(|(hash, _)| !self.blocks_time.contains_key(hash))
+            .map(|(hash, tm)| (*hash, *tm))
+            .collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+        let mut refs = Refs::default();
+        let mut buf = vec![FRAME_BLOCKS_TIME];
+        put_varint(&mut buf, new.len() as u64);
+        for (hash, tm) in &new {
+            put_ref(&mut buf, &mut refs.hashes, *hash);
+            put_varint(&mut buf, *tm);
+        }
+        self.write_frame(&buf)?;
+        self.blocks_time.extend(new);
+        Ok(())
+    }
+
+    // takes the frame that is at the offset
+    fn add_frame(&mut self, offset: u64, frame: Frame) {
+        match frame {
+            Frame::Logs { from, to, .. } => self.add_logs(from, to, offset),
+            Frame::BlocksTime(blocks_time) => self.blocks_time.extend(blocks_time),
+        }
+    }
+
+    // the logs of the blocks are read from the frame at the offset from now on
+    fn add_logs(&mut self, from: u64, to: u64, offset: u64) {
+        let replaced: Vec<(u64, (u64, u64))> = self
+            .frames
+            .range(..=to)
+            .rev()
+            .take_while(|(_, (end, _))| *end >= from)
+            .map(|(&start, &part)| (start, part))
+            .collect();
+        for (start, (end, old)) in replaced {
+            self.frames.remove(&start);
+            if start < from {
+                self.frames.insert(start, (from - 1, old));
+            }
+            if end > to {
+                self.frames.insert(to + 1, (end, old));
+            }
+        }
+        self.frames.insert(from, (to, offset));
+        merge_range(&mut self.ranges, from, to);
+    }
+
+    fn read_frame_at(&self, offset: u64) -> anyhow::Result<Frame> {
+        let payload = match &self.file {
+            Some(f) => {
+                let mut f: &File = f;
+                f.seek(SeekFrom::Start(offset))?;
+                read_payload(&mut BufReader::new(f))?
+            }
+            None => match self.memory.get(offset as usize..) {
+                Some(mut x) => read_payload(&mut x)?,
+                None => None,
+            },
+        };
+        match payload {
+            Some(x) => decode_frame(&x),
+            None => Err(anyhow::Error::msg(format!("no frame at {}", offset))),
+        }
+    }
+
+    // appends the frame, returns its offset
+    fn write_frame(&mut self, payload: &[u8]) -> anyhow::Result<u64> {
+        let mut frame = Vec::with_capacity(payload.len() + 8);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        frame.extend_from_slice(payload);
+        match &mut self.file {
+            Some(file) => {
+                if let Err(e) = file.write_all(&frame) {
+                    // the next frame must not follow the partial one
+                    let _ = file.set_len(self.len);
+                    return Err(e.into());
+                }
+            }
+            None => self.memory.extend_from_slice(&frame),
+        }
+        let offset = self.len;
+        self.len += frame.len() as u64;
+        Ok(offset)
+    }
+
+    // takes the JSON files of the previous versions from the cache folder
+    fn import_legacy(
+        &mut self,
+        cache_dir: &str,
+        chain_id: u64,
+        addresses: &[H160],
+    ) -> anyhow::Result<()> {
+        let checksum = contracts_checksum(addresses).to_string();
+        let mut batches: BTreeMap<u64, (u64, PathBuf)> = BTreeMap::new();
+        for entry in std::fs::read_dir(cache_dir)?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let parts: Vec<&str> = match name.strip_suffix(".json") {
+                Some(x) => x.split('-').collect(),
+                None => continue,
+            };
+            if parts.len() != 4 || parts[0] != format!("chain{}", chain_id) || parts[3] != checksum
+            {
+                continue;
+            }
+            if let (Ok(from), Ok(to)) = (parts[1].parse::<u64>(), parts[2].parse::<u64>()) {
+                batches.insert(from, (to, entry.path()));
+            }
+        }
+        for (from, (to, path)) in &batches {
+            let mut data = String::new();
+            File::open(path)?.read_to_string(&mut data)?;
+            let logs: Vec<Log> = serde_json::from_str(&data)?;
+            self.append_logs(*from, *to, &logs)?;
+        }
+        let blockstime = Path::new(cache_dir).join(format!("blockstime{}.json", chain_id));
+        let mut blocks_time = BTreeMap::new();
+        if let Ok(mut f) = File::open(&blockstime) {
+            let mut data = String::new();
+            f.read_to_string(&mut data)?;
+            blocks_time = serde_json::from_str(&data)?;
+            self.append_blocks_time(&blocks_time)?;
+        }
+        if !batches.is_empty() || !blocks_time.is_empty() {
+            tracing::info!(
+                "imported {} cached batches and {} timestamps of the previous version",
+                batches.len(),
+                blocks_time.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+// runs the cache command, the error means the cache needs attention
+pub fn run(
+    mode: &CacheMode,
+    cache_dir: &str,
+    chain_id: u64,
+    addresses: &[H160],
+    genesis_block: u64,
+    max_block: Option<u64>,
+    bundle: Option<&str>,
+) -> anyhow::Result<()> {
+    if cache_dir.is_empty() {
+        return Err(anyhow::Error::msg("CACHE_DIR is not set"));
+    }
+    if let CacheMode::Export | CacheMode::Import = mode {
+        let bundle = match bundle {
+            Some(x) => Path::new(x),
+            None => return Err(anyhow::Error::msg("CACHE_BUNDLE is not set")),
+        };
+        let manifest = match mode {
+            CacheMode::Export => crate::bundle::export(cache_dir, chain_id, addresses, bundle)?,
+            _ => crate::bundle::import(cache_dir, chain_id, addresses, genesis_block, bundle)?,
+        };
+        println!(
+            "{}: chain {}, blocks {}..{} in {} ranges, {} files",
+            bundle.display(),
+            manifest.chain_id,
+            manifest.from_block,
+            manifest.to_block,
+            manifest.ranges.len(),
+            manifest.files.len()
+        );
+        return Ok(());
+    }
+    let path = Store::file_name(cache_dir, chain_id, addresses);
+    if !path.exists() {
+        return Err(anyhow::Error::msg(format!("{} not found", path.display())));
+    }
+    match mode {
+        CacheMode::Verify => {
+            let report = Store::verify(&path)?;
+            println!(
+                "{}: {} bytes, {} frames, {} ranges, {} timestamps",
+                path.display(),
+                report.size,
+                report.frames,
+                report.ranges.len(),
+                report.blocks_time
+            );
+            let mut problems = 0;
+            if let Some((pos, reason)) = &report.broken {
+                println!("broken at {}: {}", pos, reason);
+                problems += 1;
+            }
+            for (from, to) in &report.overlaps {
+                println!("overlapping range {}..{}", from, to);
+                problems += 1;
+            }
+            for f in foreign_files(cache_dir, chain_id, addresses) {
+                println!("{}: the contracts checksum doesn't match", f.display());
+                problems += 1;
+            }
+            if problems > 0 {
+                return Err(anyhow::Error::msg(format!("{} problems found", problems)));
+            }
+        }
+        CacheMode::Gaps => {
+            // the file is read as it is, the broken tail is left for the scanner
+            let report = Store::verify(&path)?;
+            if let Some((pos, reason)) = &report.broken {
+                println!("broken at {}: {}, the rest is not read", pos, reason);
+            }
+            let to = match (max_block, report.ranges.values().next_back()) {
+                (Some(x), _) => x,
+                (None, Some(&x)) => x,
+                (None, None) => genesis_block,
+            };
+            let gaps = report.gaps(genesis_block, to);
+            for (from, to) in &gaps {
+                println!("{}..{} ({} blocks)", from, to, to - from + 1);
+            }
+            println!("{} gaps in blocks {}..{}", gaps.len(), genesis_block, to);
+        }
+        CacheMode::Compact => {
+            let mut store = Store::open(&path)?;
+            let before = store.size();
+            store.compact()?;
+            println!(
+                "{}: {} bytes compacted to {}",
+                path.display(),
+                before,
+                store.size()
+            );
+        }
+        CacheMode::Export | CacheMode::Import => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrpc::{TempDir, TestChain};
+
+    fn chain() -> TestChain {
+        let mut chain = TestChain::new(1);
+        let address = H160::from_low_u64_be(7);
+        for i in 1..=6u64 {
+            let mut data = vec![0u8; 64];
+            data[31] = i as u8;
+            data.extend_from_slice(b"not a word");
+            let topics = vec![H256::from_low_u64_be(100), H256::from_low_u64_be(i % 2)];
+            let logs = (0..i % 3)
+                .map(|_| crate::testrpc::log(address, topics.clone(), data.clone()))
+                .collect();
+            chain.mine(logs);
+        }
+        chain
+    }
+
+    #[test]
+    fn it_appends_and_reopens() {
+        let dir = TempDir::new("store");
+        let chain = chain();
+        let path = Store::file_name(dir.to_str().unwrap(), 1, &[]);
+        let mut store = Store::open(&path).unwrap();
+        let logs = chain.logs(&serde_json::json!({"fromBlock": "0x1", "toBlock": "0x3"}));
+        store.append_logs(1, 3, &logs).unwrap();
+        let logs = chain.logs(&serde_json::json!({"fromBlock": "0x4", "toBlock": "0x6"}));
+        store.append_logs(4, 6, &logs).unwrap();
+        let times: BTreeMap<H256, u64> =
+            chain.blocks.iter().map(|b| (b.hash, b.timestamp)).collect();
+        store.append_blocks_time(&times).unwrap();
+        // known timestamps are not written again
+        let len = std::fs::metadata(&path).unwrap().len();
+        store.append_blocks_time(&times).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.ranges, vec![(1, 6)].into_iter().collect());
+        assert_eq!(store.blocks_time, times);
+        let expected = chain.logs(&serde_json::json!({"fromBlock": "0x2", "toBlock": "0x5"}));
+        assert_eq!(store.get_logs(2, 5).unwrap().unwrap(), expected);
+        assert!(store.get_logs(0, 5).unwrap().is_none());
+        assert!(store.get_logs(5, 7).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_drops_partially_written_frame() {
+        let dir = TempDir::new("store-broken");
+        let chain = chain();
+        let path = Store::file_name(dir.to_str().unwrap(), 1, &[]);
+        let mut store = Store::open(&path).unwrap();
+        let logs = chain.logs(&serde_json::json!({"fromBlock": "0x1", "toBlock": "0x3"}));
+        store.append_logs(1, 3, &logs).unwrap();
+        let good = std::fs::metadata(&path).unwrap().len();
+        let logs = chain.logs(&serde_json::json!({"fromBlock": "0x4", "toBlock": "0x6"}));
+        store.append_logs(4, 6, &logs).unwrap();
+        let full = std::fs::metadata(&path).unwrap().len();
+        drop(store);
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(full - 3).unwrap();
+
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), good);
+        assert_eq!(store.ranges, vec![(1, 3)].into_iter().collect());
+        // the values of the dropped frame are written again
+        store.append_logs(4, 6, &logs).unwrap();
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.get_logs(4, 6).unwrap().unwrap(), logs);
+    }
+
+    #[test]
+    fn it_reads_the_logs_of_the_last_frame() {
+        let dir = TempDir::new("store-frames");
+        let chain = chain();
+        let path = Store::file_name(dir.to_str().unwrap(), 1, &[]);
+        let all = chain.logs(&serde_json::json!({"fromBlock": "0x1", "toBlock": "0x6"}));
+        let mut store = Store::open(&path).unwrap();
+        store.append_logs(1, 6, &all).unwrap();
+        // the blocks 3..4 are written again without logs
+        store.append_logs(3, 4, &[]).unwrap();
+        let expected: Vec<Log> = all
+            .iter()
+            .filter(|l| !(3..=4).contains(&l.block_number.unwrap().as_u64()))
+            .cloned()
+            .collect();
+        assert_eq!(store.get_logs(1, 6).unwrap().unwrap(), expected);
+        assert_eq!(store.frame_end(1), Some(2));
+        assert_eq!(store.frame_end(3), Some(4));
+        assert_eq!(store.frame_end(5), Some(6));
+
+        let mut memory = Store::memory();
+        memory.append_logs(1, 6, &all).unwrap();
+        memory.append_logs(3, 4, &[]).unwrap();
+        assert_eq!(memory.get_logs(1, 6).unwrap().unwrap(), expected);
+
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(store.frames.len(), 3);
+        assert_eq!(store.get_logs(2, 5).unwrap().unwrap(), expected[1..]);
+        store.compact().unwrap();
+        let report = Store::verify(&path).unwrap();
+        assert_eq!(report.frames, 1);
+        assert_eq!(store.get_logs(1, 6).unwrap().unwrap(), expected);
+    }
+
+    #[test]
+    fn it_merges_ranges() {
+        let mut store = Store::memory();
+        store.append_logs(10, 19, &[]).unwrap();
+        store.append_logs(30, 39, &[]).unwrap();
+        store.append_logs(20, 25, &[]).unwrap();
+        assert_eq!(store.covered(15), Some(25));
+        assert_eq!(store.covered(26), None);
+        store.append_logs(26, 29, &[]).unwrap();
+        assert_eq!(store.ranges, vec![(10, 39)].into_iter().collect());
+        assert_eq!(store.covered(9), None);
+    }
+
+    #[test]
+    fn it_verifies_and_compacts() {
+        let dir = TempDir::new("store-compact");
+        let chain = chain();
+        let path = Store::file_name(dir.to_str().unwrap(), 1, &[]);
+        let mut store = Store::open(&path).unwrap();
+        for (from, to) in [(1, 2), (3, 3), (2, 4), (6, 6)] {
+            let filter = serde_json::json!({
+                "fromBlock": format!("0x{:x}", from),
+                "toBlock": format!("0x{:x}", to),
+            });
+            store.append_logs(from, to, &chain.logs(&filter)).unwrap();
+        }
+        assert_eq!(store.gaps(0, 7), vec![(0, 0), (5, 5), (7, 7)]);
+        let report = Store::verify(&path).unwrap();
+        assert_eq!(report.frames, 4);
+        assert_eq!(report.overlaps, vec![(2, 4)]);
+        assert!(report.broken.is_none());
+
+        let logs = store.get_logs(1, 4).unwrap().unwrap();
+        let size = store.size();
+        store.compact().unwrap();
+        assert!(store.size() < size);
+        let report = Store::verify(&path).unwrap();
+        assert_eq!(report.frames, 2);
+        assert!(report.overlaps.is_empty());
+        let compacted = Store::open(&path).unwrap();
+        assert_eq!(compacted.get_logs(1, 4).unwrap().unwrap(), logs);
+
+        // garbage at the end is reported, but not removed by the check
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[1, 0, 0, 0, 0, 0, 0, 0, 1]).unwrap();
+        let report = Store::verify(&path).unwrap();
+        assert_eq!(report.broken.as_ref().unwrap().1, "checksum mismatch");
+        assert_eq!(report.size, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(report.gaps(0, 7), vec![(0, 0), (5, 5), (7, 7)]);
+        run(
+            &CacheMode::Gaps,
+            dir.to_str().unwrap(),
+            1,
+            &[],
+            0,
+            Some(7),
+            None,
+        )
+        .unwrap();
+        assert_eq!(report.size, std::fs::metadata(&path).unwrap().len());
+
+        let other = Store::file_name(dir.to_str().unwrap(), 1, &[H160::from_low_u64_be(1)]);
+        std::fs::write(&other, MAGIC).unwrap();
+        let foreign = foreign_files(dir.to_str().unwrap(), 1, &[]);
+        assert_eq!(foreign, vec![other]);
+    }
+}
@@ -0,0 +1,409 @@
+// In-process stand-in of an Ethereum JSON-RPC node.
+// It serves a scripted chain to the tests, so the scanner and the watchers
+// can be run without any real node. Temporary folders of the tests are here too.
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use warp::ws::{Message, WebSocket};
+use warp::Filter;
+use web3::types::{Block, Bytes, Log, H160, H256, U256, U64};
+
+#[derive(Debug, Clone)]
+pub struct TestBlock {
+    pub number: u64,
+    pub hash: H256,
+    pub parent_hash: H256,
+    pub timestamp: u64,
+    pub logs: Vec<Log>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub id: String,
+    // "logs" or "newHeads"
+    pub kind: String,
+    pub filter: Value,
+    tx: mpsc::UnboundedSender<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestChain {
+    pub chain_id: u64,
+    pub blocks: Vec<TestBlock>,
+    // increased on every rewind, so the blocks of the new fork get new hashes
+    fork: u64,
+    subscriptions: Vec<Subscription>,
+    next_subscription: u64,
+    // closes all WebSocket connections
+    disconnect: broadcast::Sender<()>,
+    /// eth_getLogs fails when there are more logs in the response
+    pub max_logs: Option<usize>,
+    /// number of the next calls that fail with internal error
+    pub failures: usize,
+    /// methods of every received request, a batch request lists all its calls
+    pub requests: Vec<Vec<String>>,
+    /// results of eth_call by the contract and the calldata, other calls revert
+    pub calls: BTreeMap<(H160, Vec<u8>), Vec<u8>>,
+}
+
+pub type SharedChain = Arc<Mutex<TestChain>>;
+
+// builds a log that is not yet included in any block
+pub fn log(address: H160, topics: Vec<H256>, data: Vec<u8>) -> Log {
+    Log {
+        address,
+        topics,
+        data: Bytes(data),
+        block_hash: None,
+        block_number: None,
+        transaction_hash: None,
+        transaction_index: None,
+        log_index: None,
+        transaction_log_index: None,
+        log_type: None,
+        removed: Some(false),
+    }
+}
+
+impl TestChain {
+    pub fn new(chain_id: u64) -> Self {
+        let mut chain = Self {
+            chain_id,
+            blocks: vec![],
+            fork: 1,
+            subscriptions: vec![],
+            next_subscription: 1,
+            disconnect: broadcast::channel(1).0,
+            max_logs: None,
+            failures: 0,
+            requests: vec![],
+            calls: BTreeMap::new(),
+        };
+        chain.mine(vec![]); // genesis
+        chain
+    }
+
+    pub fn head(&self) -> u64 {
+        self.blocks.len() as u64 - 1
+    }
+
+    pub fn timestamp(number: u64) -> u64 {
+        1_600_000_000 + number * 13
+    }
+
+    // appends a block containing given logs, returns its number
+    pub fn mine(&mut self, logs: Vec<Log>) -> u64 {
+        let number = self.blocks.len() as u64;
+        let hash = H256::from_low_u64_be((self.fork << 32) | number);
+        let parent_hash = match self.blocks.last() {
+            Some(b) => b.hash,
+            None => H256::zero(),
+        };
+        let logs = logs
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut l)| {
+                l.block_hash = Some(hash);
+                l.block_number = Some(U64::from(number));
+                l.transaction_hash = Some(H256::from_low_u64_be((number << 16) | i as u64));
+                l.transaction_index = Some(i.into());
+                l.log_index = Some(U256::from(i));
+                l.transaction_log_index = Some(U256::zero());
+                l
+            })
+            .collect::<Vec<Log>>();
+        self.notify(&logs);
+        let block = TestBlock {
+            number,
+            hash,
+            parent_hash,
+            timestamp: Self::timestamp(number),
+            logs,
+        };
+        let header = self.block_json(&block);
+        for s in self.subscriptions.iter().filter(|s| s.kind == "newHeads") {
+            let _ = s.tx.send(json!({
+                "jsonrpc": "2.0",
+                "method": "eth_subscription",
+                "params": {"subscription": s.id, "result": header},
+            }));
+        }
+        self.blocks.push(block);
+        number
+    }
+
+    // sends logs to the subscribers, which filters are matching
+    fn notify(&mut self, logs: &[Log]) {
+        for s in self.subscriptions.iter().filter(|s| s.kind == "logs") {
+            let addresses = Self::addresses(&s.filter);
+            for l in logs {
+                if addresses.is_empty() || addresses.contains(&l.address) {
+                    let _ = s.tx.send(json!({
+                        "jsonrpc": "2.0",
+                        "method": "eth_subscription",
+                        "params": {"subscription": s.id, "result": l},
+                    }));
+                }
+            }
+        }
+    }
+
+    // drops all WebSocket connections and their subscriptions
+    pub fn disconnect(&mut self) {
+        self.subscriptions.clear();
+        let _ = self.disconnect.send(());
+    }
+
+    pub fn mine_empty(&mut self, n: u64) {
+        for _ in 0..n {
+            self.mine(vec![]);
+        }
+    }
+
+    // drops all blocks after `number`, the next mined blocks belong to another fork.
+    // Subscribers receive the logs of the dropped blocks as removed
+    pub fn rewind(&mut self, number: u64) -> Vec<Log> {
+        self.fork += 1;
+        let dropped = self.blocks.split_off(number as usize + 1);
+        let removed: Vec<Log> = dropped
+            .into_iter()
+            .flat_map(|b| b.logs)
+            .map(|mut l| {
+                l.removed = Some(true);
+                l
+            })
+            .collect();
+        self.notify(&removed);
+        removed
+    }
+
+    fn block_json(&self, b: &TestBlock) -> Value {
+        let block: Block<H256> = Block {
+            hash: Some(b.hash),
+            parent_hash: b.parent_hash,
+            number: Some(U64::from(b.number)),
+            timestamp: U256::from(b.timestamp),
+            ..Block::default()
+        };
+        serde_json::to_value(&block).unwrap()
+    }
+
+    fn block_number(&self, v: Option<&Value>, default: u64) -> u64 {
+        match v.and_then(|x| x.as_str()) {
+            Some("latest") | Some("pending") => self.head(),
+            Some("earliest") => 0,
+            Some(x) => u64::from_str_radix(x.trim_start_matches("0x"), 16).unwrap_or(default),
+            None => default,
+        }
+    }
+
+    fn addresses(filter: &Value) -> Vec<H160> {
+        match filter.get("address") {
+            Some(Value::Array(list)) => list
+                .iter()
+                .filter_map(|a| a.as_str().and_then(|s| H160::from_str(s).ok()))
+                .collect(),
+            Some(Value::String(s)) => H160::from_str(s).into_iter().collect(),
+            _ => vec![],
+        }
+    }
+
+    // only the first topic is filtered
+    fn topics(filter: &Value) -> Vec<H256> {
+        match filter.get("topics").and_then(|x| x.get(0)) {
+            Some(Value::Array(list)) => list
+                .iter()
+                .filter_map(|t| t.as_str().and_then(|s| H256::from_str(s).ok()))
+                .collect(),
+            Some(Value::String(s)) => H256::from_str(s).into_iter().collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn logs(&self, filter: &Value) -> Vec<Log> {
+        let from = self.block_number(filter.get("fromBlock"), self.head());
+        let to = self.block_number(filter.get("toBlock"), self.head());
+        let addresses = Self::addresses(filter);
+        let topics = Self::topics(filter);
+        self.blocks
+            .iter()
+            .filter(|b| b.number >= from && b.number <= to)
+            .flat_map(|b| b.logs.clone())
+            .filter(|l| addresses.is_empty() || addresses.contains(&l.address))
+            .filter(|l| topics.is_empty() || l.topics.first().map_or(false, |t| topics.contains(t)))
+            .collect()
+    }
+
+    // returns the result or the error code with message
+    fn result(&self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "eth_chainId" => Ok(json!(format!("0x{:x}", self.chain_id))),
+            "eth_blockNumber" => Ok(json!(format!("0x{:x}", self.head()))),
+            "eth_getLogs" => {
+                let logs = self.logs(&params[0]);
+                match self.max_logs {
+                    Some(max) if logs.len() > max => {
+                        Err((-32005, format!("query returned more than {} results", max)))
+                    }
+                    _ => Ok(serde_json::to_value(logs).unwrap()),
+                }
+            }
+            "eth_getBlockByHash" => {
+                let hash = H256::from_str(params[0].as_str().unwrap_or_default())
+                    .map_err(|e| (-32602, e.to_string()))?;
+                Ok(match self.blocks.iter().find(|b| b.hash == hash) {
+                    Some(b) => self.block_json(b),
+                    None => Value::Null,
+                })
+            }
+            "eth_getBlockByNumber" => {
+                let number = self.block_number(params.get(0), self.head());
+                Ok(match self.blocks.get(number as usize) {
+                    Some(b) => self.block_json(b),
+                    None => Value::Null,
+                })
+            }
+            "eth_call" => {
+                let to = H160::from_str(params[0]["to"].as_str().unwrap_or_default())
+                    .map_err(|e| (-32602, e.to_string()))?;
+                let data = params[0]["data"].as_str().unwrap_or_default();
+                let data = hex::decode(data.trim_start_matches("0x"))
+                    .map_err(|e| (-32602, e.to_string()))?;
+                match self.calls.get(&(to, data)) {
+                    Some(x) => Ok(json!(Bytes(x.clone()))),
+                    None => Err((3, "execution reverted".to_owned())),
+                }
+            }
+            _ => Err((-32601, format!("method {} is not supported", method))),
+        }
+    }
+
+    // responds to a single JSON-RPC call
+    pub fn call(&mut self, req: &Value) -> Value {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return json!({
+                "jsonrpc": "2.0",
+                "id": req["id"],
+                "error": {"code": -32603, "message": "internal error"},
+            });
+        }
+        let method = req["method"].as_str().unwrap_or_default();
+        match self.result(method, &req["params"]) {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": req["id"], "result": result}),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": req["id"],
+                "error": {"code": code, "message": message},
+            }),
+        }
+    }
+
+    // responds to a single call or to a batch of calls
+    pub fn handle(&mut self, req: &Value) -> Value {
+        let method = |c: &Value| c["method"].as_str().unwrap_or_default().to_owned();
+        match req {
+            Value::Array(calls) => {
+                self.requests.push(calls.iter().map(method).collect());
+                Value::Array(calls.iter().map(|c| self.call(c)).collect())
+            }
+            _ => {
+                self.requests.push(vec![method(req)]);
+                self.call(req)
+            }
+        }
+    }
+}
+
+// serves one WebSocket connection, until the client or `disconnect` closes it
+async fn connection(chain: SharedChain, ws: WebSocket) {
+    let (tx, mut notifications) = mpsc::unbounded_channel::<Value>();
+    let mut disconnect = chain.lock().unwrap().disconnect.subscribe();
+    let (mut sink, mut stream) = ws.split();
+    loop {
+        let out = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(msg)) if msg.is_text() => {
+                    let req: Value = match serde_json::from_str(msg.to_str().unwrap()) {
+                        Ok(x) => x,
+                        Err(_) => break,
+                    };
+                    let mut c = chain.lock().unwrap();
+                    if req["method"] == "eth_subscribe" {
+                        let id = format!("0x{:x}", c.next_subscription);
+                        c.next_subscription += 1;
+                        c.subscriptions.push(Subscription {
+                            id: id.clone(),
+                            kind: req["params"][0].as_str().unwrap_or_default().to_owned(),
+                            filter: req["params"][1].clone(),
+                            tx: tx.clone(),
+                        });
+                        json!({"jsonrpc": "2.0", "id": req["id"], "result": id})
+                    } else {
+                        c.handle(&req)
+                    }
+                }
+                Some(Ok(_)) => continue,
+                _ => break,
+            },
+            Some(n) = notifications.recv() => n,
+            _ = disconnect.recv() => break,
+        };
+        if sink.send(Message::text(out.to_string())).await.is_err() {
+            break;
+        }
+    }
+    let _ = sink.close().await;
+}
+
+// starts HTTP and WebSocket JSON-RPC server on a random local port,
+// returns its HTTP URL. WebSocket is served on the same address
+pub fn serve(chain: SharedChain) -> String {
+    let ws_chain = chain.clone();
+    let ws = warp::ws().map(move |ws: warp::ws::Ws| {
+        let chain = ws_chain.clone();
+        ws.on_upgrade(move |socket| connection(chain, socket))
+    });
+    let http = warp::post()
+        .and(warp::body::json())
+        .map(move |req: Value| warp::reply::json(&chain.lock().unwrap().handle(&req)));
+    let (addr, server) = warp::serve(ws.or(http)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    format!("http://{}", addr)
+}
+
+// folder of the test in the temp folder, it is removed with its files when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("api3tracker-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,306 @@
+// Registry of the ERC20 tokens: symbol, name and decimals of the tokens that are met
+// in the treasuries, vote scripts and agent transfers are read from their contracts.
+// The tokens that were read are kept in the cache folder and not read again
+use crate::config::Token;
+use crate::contracts::Erc20Contract;
+use crate::rpc;
+use client::state::{AppState, TokenInfo};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use web3::types::{H160, U256};
+
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    path: Option<PathBuf>,
+    /// tokens that were configured or read, by the address
+    pub tokens: BTreeMap<H160, TokenInfo>,
+    // configured tokens, they keep their symbols
+    configured: Vec<H160>,
+    // contracts that are not tokens, they are not read again
+    failed: BTreeSet<H160>,
+    backoff: rpc::Backoff,
+}
+
+impl Registry {
+    pub fn new(cache_dir: &str, chain_id: u64) -> Self {
+        let path = if cache_dir.is_empty() {
+            None
+        } else {
+            Some(Path::new(cache_dir).join(format!("tokens{}.json", chain_id)))
+        };
+        let mut tokens = BTreeMap::new();
+        if let Some(p) = &path {
+            if let Ok(s) = std::fs::read_to_string(p) {
+                match serde_json::from_str::<Vec<TokenInfo>>(&s) {
+                    Ok(list) => tokens.extend(list.into_iter().map(|t| (t.address, t))),
+                    Err(e) => tracing::warn!("tokens cache {:?} is ignored: {}", p, e),
+                }
+            }
+        }
+        Self {
+            path,
+            tokens,
+            configured: vec![],
+            failed: BTreeSet::new(),
+            backoff: rpc::Backoff::default(),
+        }
+    }
+
+    // configured tokens are not read, they have the given symbols and decimals
+    pub fn configure(&mut self, tokens: &[Token]) {
+        for t in tokens {
+            let name = match self.tokens.get(&t.address) {
+                Some(x) => x.name.clone(),
+                None => String::new(),
+            };
+            self.tokens.insert(
+                t.address,
+                TokenInfo {
+                    address: t.address,
+                    symbol: t.symbol.clone(),
+                    name,
+                    decimals: t.decimals,
+                },
+            );
+            self.configured.push(t.address);
+        }
+    }
+
+    // token of the address, it is read once
+    pub async fn read<T: web3::Transport>(
+        &mut self,
+        web3: &web3::Web3<T>,
+        address: H160,
+    ) -> Option<TokenInfo> {
+        if let Some(t) = self.tokens.get(&address) {
+            return Some(t.clone());
+        }
+        if self.failed.contains(&address) {
+            return None;
+        }
+        let contract = Erc20Contract::new(web3, address);
+        // the errors of the node are retried, the contract call errors are kept
+        let found = {
+            let contract = &contract;
+            self.backoff
+                .retry("decimals", rpc::is_transient, || async move {
+                    match contract.decimals().await {
+                        Err(web3::contract::Error::Api(e)) => Err(e),
+                        res => Ok(res),
+                    }
+                })
+                .await
+        };
+        let decimals = match found {
+            Ok(Ok(x)) if x <= U256::from(36) => x.as_usize(),
+            Ok(Ok(x)) => {
+                tracing::warn!(
+                    "{:?} is not a token, decimals {} are out of range",
+                    address,
+                    x
+                );
+                self.failed.insert(address);
+                return None;
+            }
+            // the result is not a number
+            Ok(Err(e)) => {
+                tracing::warn!("{:?} is not a token: {}", address, e);
+                self.failed.insert(address);
+                return None;
+            }
+            Err(e) if rpc::is_transient(&e) => {
+                tracing::warn!("token {:?} is not read: {}", address, e);
+                return None;
+            }
+            // the call reverted
+            Err(e) => {
+                tracing::warn!("{:?} is not a token: {}", address, e);
+                self.failed.insert(address);
+                return None;
+            }
+        };
+        let t = contract.read_info(decimals).await;
+        tracing::info!("token {:?} is {} ({})", address, t.symbol, t.decimals);
+        self.tokens.insert(address, t.clone());
+        if let Err(e) = self.save() {
+            tracing::warn!("tokens cache is not saved: {}", e);
+        }
+        Some(t)
+    }
+
+    // reads the tokens that the state has met, and adds all tokens to the state
+    pub async fn update<T: web3::Transport>(&mut self, web3: &web3::Web3<T>, app: &mut AppState) {
+        for address in app.tokens_to_read() {
+            self.read(web3, address).await;
+        }
+        self.add_to(app);
+    }
+
+    // adds the tokens that the state doesn't have yet
+    pub fn add_to(&self, app: &mut AppState) {
+        let others = self.tokens.keys().filter(|x| !self.configured.contains(x));
+        for address in self.configured.iter().chain(others) {
+            if !app.tokens.contains_key(address) {
+                app.add_token(self.tokens[address].clone());
+            }
+        }
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(p) = &self.path {
+            let list: Vec<&TokenInfo> = self.tokens.values().collect();
+            std::fs::write(p, serde_json::to_string_pretty(&list)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrpc::{self, TestChain};
+    use client::script::AGENT_EXECUTE;
+    use client::state::{Voting, VotingStaticData};
+    use hex_literal::hex;
+    use std::sync::{Arc, Mutex};
+    use web3::ethabi::{encode, Token as AbiToken};
+    use web3::types::U256;
+
+    #[tokio::test]
+    async fn it_reads_unlisted_tokens() {
+        let api3 = H160::from_low_u64_be(0xa3);
+        let dai = H160::from_low_u64_be(0xda);
+        let other = H160::from_low_u64_be(0xdd);
+        let mkr = H160::from_low_u64_be(0x4d);
+        let agent = H160::from_low_u64_be(0xa1);
+        let to = H160::from_low_u64_be(0xb1);
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        {
+            let mut c = chain.lock().unwrap();
+            let mut answer = |address: H160, selector: [u8; 4], value: AbiToken| {
+                c.calls
+                    .insert((address, selector.to_vec()), encode(&[value]));
+            };
+            answer(dai, hex!("313ce567"), AbiToken::Uint(18.into()));
+            answer(dai, hex!("95d89b41"), AbiToken::String("DAI".to_owned()));
+            answer(dai, hex!("06fdde03"), AbiToken::String("Dai".to_owned()));
+            // the same symbol as the configured token, without a name
+            answer(other, hex!("313ce567"), AbiToken::Uint(6.into()));
+            answer(other, hex!("95d89b41"), AbiToken::String("API3".to_owned()));
+            // symbol and name as bytes32
+            let mut symbol = b"MKR".to_vec();
+            symbol.resize(32, 0);
+            answer(mkr, hex!("313ce567"), AbiToken::Uint(18.into()));
+            answer(mkr, hex!("95d89b41"), AbiToken::FixedBytes(symbol));
+        }
+        let web3 = web3::Web3::new(
+            crate::reader::get_transport(testrpc::serve(chain.clone()))
+                .await
+                .unwrap(),
+        );
+
+        let mut app = AppState::new(1);
+        // a voting that transfers DAI through the agent
+        let mut transfer = hex!("a9059cbb").to_vec();
+        transfer.extend(encode(&[AbiToken::Address(to), AbiToken::Uint(5.into())]));
+        let mut execute = AGENT_EXECUTE.to_vec();
+        execute.extend(encode(&[
+            AbiToken::Address(dai),
+            AbiToken::Uint(U256::zero()),
+            AbiToken::Bytes(transfer),
+        ]));
+        let mut script = hex!("00000001").to_vec();
+        script.extend(agent.as_bytes());
+        script.extend(&(execute.len() as u32).to_be_bytes());
+        script.extend(execute);
+        let data = VotingStaticData {
+            start_date: 0,
+            support_required: 0.5,
+            min_quorum: 0.15,
+            voting_power: U256::zero(),
+            script,
+            user_voting_power_at: U256::zero(),
+            discussion_url: String::new(),
+        };
+        let v = Voting {
+            details: Some(data.into_details(&app.tokens)),
+            ..Voting::default()
+        };
+        assert!(v.details.as_ref().unwrap().action.is_none());
+        app.votings.insert(1, v);
+        app.unknown_tokens.insert(other);
+        app.unknown_tokens.insert(mkr);
+        app.unknown_tokens.insert(to);
+
+        let dir = testrpc::TempDir::new("tokens");
+        let mut registry = Registry::new(dir.to_str().unwrap(), 1);
+        registry.configure(&[Token {
+            symbol: "API3".to_owned(),
+            address: api3,
+            decimals: 18,
+        }]);
+        registry.update(&web3, &mut app).await;
+        assert_eq!(app.tokens[&dai].symbol, "DAI");
+        assert_eq!(app.tokens[&dai].name, "Dai");
+        assert_eq!(app.decimals["DAI"], 18);
+        assert_eq!(app.tokens[&api3].symbol, "API3");
+        assert_eq!(app.tokens[&other].symbol, format!("API3 ({})", other));
+        assert_eq!(app.decimals[&format!("API3 ({})", other)], 6);
+        assert_eq!(app.tokens[&mkr].symbol, "MKR");
+        assert_eq!(app.tokens[&mkr].name, "");
+        // the wallet is not a token, it is left unread
+        assert!(!app.tokens.contains_key(&to));
+        assert_eq!(
+            app.tokens_to_read().into_iter().collect::<Vec<H160>>(),
+            vec![to]
+        );
+        let treasury_tokens = app.token_addresses();
+        assert_eq!(treasury_tokens["DAI"], dai);
+
+        let details = data.into_details(&app.tokens);
+        let action = details.action.unwrap();
+        assert_eq!((action.token.as_str(), action.decimals), ("DAI", 18));
+        assert_eq!(action.wallet, Some(to));
+
+        // the cached tokens are not read again
+        let calls = chain.lock().unwrap().requests.len();
+        let mut cached = Registry::new(dir.to_str().unwrap(), 1);
+        let mut app = AppState::new(1);
+        app.unknown_tokens.insert(dai);
+        cached.update(&web3, &mut app).await;
+        assert_eq!(chain.lock().unwrap().requests.len(), calls);
+        assert_eq!(app.tokens[&dai].symbol, "DAI");
+    }
+
+    #[tokio::test]
+    async fn it_reads_the_token_again_after_node_errors() {
+        let dai = H160::from_low_u64_be(0xda);
+        let wallet = H160::from_low_u64_be(0xb1);
+        let chain = Arc::new(Mutex::new(TestChain::new(1)));
+        chain.lock().unwrap().calls.insert(
+            (dai, hex!("313ce567").to_vec()),
+            encode(&[AbiToken::Uint(18.into())]),
+        );
+        let web3 = web3::Web3::new(
+            crate::reader::get_transport(testrpc::serve(chain.clone()))
+                .await
+                .unwrap(),
+        );
+        let mut registry = Registry::new("", 1);
+        registry.backoff = rpc::Backoff {
+            initial: std::time::Duration::from_millis(1),
+            max: std::time::Duration::from_millis(1),
+            attempts: 2,
+        };
+        chain.lock().unwrap().failures = 2;
+        assert!(registry.read(&web3, dai).await.is_none());
+        assert!(!registry.failed.contains(&dai));
+        assert_eq!(registry.read(&web3, dai).await.unwrap().decimals, 18);
+
+        // the call of the wallet reverts, it is not read again
+        assert!(registry.read(&web3, wallet).await.is_none());
+        let calls = chain.lock().unwrap().requests.len();
+        assert!(registry.read(&web3, wallet).await.is_none());
+        assert_eq!(chain.lock().unwrap().requests.len(), calls);
+    }
+}